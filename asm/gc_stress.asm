@@ -0,0 +1,222 @@
+;; Garbage collector stress test
+; Builds a long linked list and a full binary tree while producing lots of garbage and forcing collections,
+; then walks both structures and checks that every node kept its data and links.
+; Also checks that references keep their marker when popped from the stack.
+; Prints "OK" if everything survived, or which structure got corrupted otherwise.
+
+.define LIST_LENGTH 500
+; Sum of 1..LIST_LENGTH
+.define LIST_SUM 125250
+.define TREE_DEPTH 9
+; Sum of (depth + 1) over every node of a full tree with depth TREE_DEPTH
+.define TREE_SUM 2036
+
+jmp main
+
+; Params: depth
+; Returns: [R]root of a full binary tree where every node contains its depth
+; Tree node memory layout:
+; Size: 3w
+; +0w depth
+; +1w [R]left
+; +2w [R]right
+Tree::build:
+    new 3w, r0
+    mov [sp+2w], [r0]
+    mov 0, [r0+1w]
+    mov 0, [r0+2w]
+
+    ; Garbage
+    new 5w, r1
+
+    cmp [r0], 0
+    jeq Tree::build::end
+
+    push r0 ; [sp+1w] = node
+
+    mov [r0], r1
+    sub 1, r1
+    push r1
+    call Tree::build
+    pop r1
+    mov [sp+1w], r2
+    mov r0, [r2+1w]
+
+    ; Garbage shaped like a tree, which points to a live node
+    new 3w, r1
+    new 3w, [r1+1w]
+    mov r2, [r1+2w]
+
+    mov [r2], r1
+    sub 1, r1
+    push r1
+    call Tree::build
+    pop r1
+    mov [sp+1w], r2
+    mov r0, [r2+2w]
+
+    pop r0
+
+    Tree::build::end:
+    ret
+
+; Params: [R]node
+; Returns: Sum of (depth + 1) over every node of the tree, or 0 if the tree is malformed
+Tree::sum:
+    mov [sp+2w], r1
+    cmp r1, 0
+    jne Tree::sum::node
+    mov 0, r0
+    ret
+
+    Tree::sum::node:
+    mov [r1], r0
+    add 1, r0
+    push r0 ; [sp+1w] = partial sum
+
+    push [r1+1w]
+    call Tree::sum
+    pop r2
+    add r0, [sp+1w]
+
+    mov [sp+3w], r1
+    push [r1+2w]
+    call Tree::sum
+    pop r2
+    add r0, [sp+1w]
+
+    pop r0
+    ret
+
+; Params: length
+; Returns: [R]head of a linked list with the values 1..length, in ascending order
+; List node memory layout:
+; Size: 2w
+; +0w value
+; +1w [R]next
+List::build:
+    mov [sp+2w], r2
+    mov 0, r3
+
+    List::build::loop:
+    new 2w, r0
+    mov r2, [r0]
+    mov r3, [r0+1w]
+    mov r0, r3
+
+    ; Garbage: a small cycle that nothing else points to
+    new 2w, r1
+    new 2w, [r1+1w]
+    mov [r1+1w], r0
+    mov r1, [r0+1w]
+
+    ; Collect every once in a while, even though memory isn't full
+    mov r2, r1
+    and 0b11111, r1
+    cmp r1, 0
+    jne List::build::next
+    gc
+
+    List::build::next:
+    sub 1, r2
+    cmp r2, 0
+    jne List::build::loop
+
+    mov r3, r0
+    ret
+
+; Params: [R]head
+; Returns: Sum of every value in the list
+List::sum:
+    mov [sp+2w], r1
+    mov 0, r0
+
+    List::sum::loop:
+    cmp r1, 0
+    jeq List::sum::end
+    add [r1], r0
+    mov [r1+1w], r1
+    jmp List::sum::loop
+
+    List::sum::end:
+    ret
+
+main:
+    push LIST_LENGTH
+    call List::build
+    pop r1
+    push r0 ; [sp+1w] = list
+
+    push TREE_DEPTH
+    call Tree::build
+    pop r1
+    push r0 ; [sp+1w] = tree, [sp+2w] = list
+
+    ; Drop everything from the registers so only the stack keeps the structures alive
+    mov 0, r0
+    mov 0, r1
+    mov 0, r2
+    mov 0, r3
+    gc
+    gc
+
+    ; Popping the structures back must keep them marked as references,
+    ; otherwise the next collection would free them even though they're back on the stack
+    pop r0
+    pop r1
+    isref r0
+    jne main::stack_failed
+    isref r1
+    jne main::stack_failed
+    push r1
+    push r0
+    mov 0, r0
+    mov 0, r1
+    gc
+
+    push [sp+2w]
+    call List::sum
+    pop r1
+    cmp r0, LIST_SUM
+    jne main::list_failed
+
+    push [sp+1w]
+    call Tree::sum
+    pop r1
+    cmp r0, TREE_SUM
+    jne main::tree_failed
+
+    mov main::ok, r0
+    push r0
+    ref [sp+1w]
+    push main::ok_len
+    jmp main::end
+
+    main::list_failed:
+    mov main::list_failed_str, r0
+    push r0
+    ref [sp+1w]
+    push main::list_failed_len
+    jmp main::end
+
+    main::tree_failed:
+    mov main::tree_failed_str, r0
+    push r0
+    ref [sp+1w]
+    push main::tree_failed_len
+    jmp main::end
+
+    main::stack_failed:
+    mov main::stack_failed_str, r0
+    push r0
+    ref [sp+1w]
+    push main::stack_failed_len
+
+    main::end:
+    native 0
+    halt
+
+main::ok: .string main::ok_len "OK\n"
+main::list_failed_str: .string main::list_failed_len "Linked list was corrupted\n"
+main::tree_failed_str: .string main::tree_failed_len "Tree was corrupted\n"
+main::stack_failed_str: .string main::stack_failed_len "Popping from the stack lost a reference marker\n"
@@ -1,30 +1,63 @@
 ;; Kitchen Sink
 ; This file uses all available instructions and assembler features
+# Comments start with either ; or # and run until the end of the line
+/* Block comments can span multiple lines.
+   They end at the first star followed by a slash, so they can't be nested */
 
-; .define: Simple compile-time constant definition. Can only be used to define numbers.
+; .define: Simple compile-time constant definition. The value can be a number or a symbol defined before it.
 ; The defined label can be used anywhere a regular label can be used
 .define COMPILE_TIME_CONSTANT 1337
+.define CONSTANT_ALIAS COMPILE_TIME_CONSTANT
+
+; _start: If a label with this name exists, the assembler adds a jump to it at the very beginning of the program,
+; so execution starts there no matter where it is in the file
+_start:
+
+; .if/.else/.endif: Conditional assembly. The symbol must be .define'd before the .if, and any non-zero value is true.
+; Code in the branch that isn't taken is skipped entirely. Blocks can be nested.
+.define DEBUG 0
+.if DEBUG
+debugcpu 0
+brk
+.else
+nop
+.endif
+
+; .rept/.endr: Repeats everything between them the specified number of times. Blocks can be nested.
+; Every copy is identical, so labels can't be defined inside a block, not even local ones, as each copy would define them again
+.rept 2
+nop
+.endr
 
 ; Simple stuff
 nop
-mov COMPILE_TIME_CONSTANT, r0
+mov CONSTANT_ALIAS, r0
 push r0
 pop r0
 add r0, r0
 sub r0, r0
 mul r0, r0
 div 1, r0
+idiv 1, r0
 and r0, r0
 or r0, r0
 xor r0, r0
+tst r0, r0
 not r0
 shl r0, r0
 shr r0, r0
 cmp r0, r0
+icmp r0, r0
+cmovz r0, r1
+cmovnz r0, r1
+cmovc r0, r1
+cmovnc r0, r1
+clf
 
 ; Number bases and multipliers
 ; 0x?? = Hexadecimal
 ; 0b?? = Binary
+; 0o?? = Octal
 ; ??w = Word-size, value is multiplied by 8
 mov 1, r0
 mov -1, r0
@@ -32,17 +65,41 @@ mov 0xA, r0
 mov -0xA, r0
 mov 0b10, r0
 mov -0b10, r0
+mov 0o17, r0
+mov -0o17, r0
 mov 1w, r0
 mov -1w, r0
 mov 0xAw, r0
 mov -0xAw, r0
 mov 0b10w, r0
 mov -0b10w, r0
+mov 0o17w, r0
+mov -0o17w, r0
+
+; Character literals, which evaluate to the character's code point
+; Supported escapes: \n \t \r \0 \' \" \\ \xNN
+mov 'A', r0
+mov '\n', r0
+mov '\'', r0
+mov '\0', r0
+mov '\x7F', r0
 
 ; Memory
+newaligned 5w, 4096, r1
 new 128w, r0
 unref r0
 ref r0
+isref r0
+; Indexed references add the value of a register to a reference
+mov 1w, r1
+mov 42, [r0+r1]
+mov [r0+r1], r2
+fetchadd [r0], 1, r1
+movip r1
+objid r0, r1
+; Labels can be followed by a constant offset, with no spaces in between
+mov string+1, r0
+mov string-1w, r0
 gc
 
 ; Jumps
@@ -54,6 +111,10 @@ l4: jge l5
 l5: jlt l6
 l6: jle l7
 l7: call subroutine
+; Labels starting with a dot are local to the most recent label without one, so names can be reused across routines.
+; Here, .local is actually l7.local, which is also how other routines can refer to it
+jmp .local
+.local: nop
 
 ; Native
 mov string, r0
@@ -66,11 +127,48 @@ pop r0
 pop r0
 
 halt
+; Never reached, as the program already halted. Would stop the program with "Hello..." as the error message
+abort string, string_len
 
 subroutine: ret
 
+; .section: Marks everything after it as code or data, until the next .section.
+; Only used by tools such as the disassembler, which will hex-dump data sections instead of decoding them.
+; Anything before the first .section is considered code.
+.section data
+; .org: fills the assembled binary with zeroes until the current address is the specified value.
+; The address must not be behind the current address.
+; The interpreter always loads the program at address 0, so this is also the runtime address of whatever comes next,
+; and labels defined after it have that address as their value.
+.org 0x200
 ; .align: fills the assembled binary with zeroes until the current address is a multiple of the specified value
+; An optional second value is used as the padding byte instead of zero
 .align 1w
+; .byte: Writes each of the specified values as a single byte directly to the assembled binary at the current address
+; Values must be between 0 and 255. Character literals can also be used.
+.byte 0x41, 'B', 10
+; .word: Writes each of the specified values as a little-endian word directly to the assembled binary at the current address
+; The current address must be a multiple of the word size.
+; If a label is specified before the values, the number of values is stored in that label.
+.align 1w, 0xFF
+.word words_len 1, -2, 0xFF
+; .zero: Writes the specified number of zero bytes directly to the assembled binary at the current address.
+; Useful to reserve space for a buffer, with a label right before it.
+.zero 3
 ; .string: Writes the specified string as UTF-8 directly to the assembled binary at the current address
 ; If a label is specified before the string, the string's size in bytes is stored in that label.
+; Supports the same escapes as character literals, so \0 can be used to null-terminate a string.
+; \xNN escapes write the byte NN as-is instead of encoding it as UTF-8, so \xFF is a single byte.
 string: .string string_len "Hello\n\"Beautiful\"\n\\world\\!"
+; .asciiz: Same as .string, but writes a 0 byte after the string. The length label includes that 0 byte.
+.asciiz "null-terminated"
+; .sizeof: Defines the first label as the number of bytes written since the second label, which must come before it.
+; Useful to measure a block of data built from several directives.
+.sizeof string_size string
+; .preload/.endpreload: Everything between them is loaded at the specified address instead of being part of the program.
+; The first value is a name for the data, only used for debugging. The address must come after the program and any other
+; preloaded data, and be a multiple of the page size (1024 by default). Only data directives and labels can be used inside,
+; and labels get the address where the data is loaded as their value.
+.preload lookup_table 0x10000
+.word 1, 2, 4, 8
+.endpreload
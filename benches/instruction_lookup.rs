@@ -0,0 +1,132 @@
+//! Compares the current instruction lookup, which goes through a thread-local HashMap on every call,
+//! with a flat table indexed by instruction ID.
+//! Run with `cargo bench --bench instruction_lookup`
+
+use lakesis::opcodes::{Instruction, InstructionDescriptor};
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+/// Number of instructions in the benchmark stream
+const STREAM_LENGTH: usize = 1_000_000;
+/// Number of times the whole stream is dispatched, the fastest run is reported
+const RUNS: usize = 10;
+
+/// Lookup table indexed by instruction ID, with an entry for every possible ID
+struct ArrayLookup {
+    descriptors: [Option<(Instruction, InstructionDescriptor)>; Instruction::MASK as usize + 1],
+}
+
+impl ArrayLookup {
+    fn new() -> ArrayLookup {
+        let mut descriptors = [None; Instruction::MASK as usize + 1];
+        for (id, entry) in descriptors.iter_mut().enumerate() {
+            *entry = hashmap_lookup(id as u8);
+        }
+
+        ArrayLookup { descriptors }
+    }
+
+    fn lookup(&self, id: u8) -> Option<(Instruction, InstructionDescriptor)> {
+        self.descriptors[(id & Instruction::MASK) as usize]
+    }
+}
+
+fn hashmap_lookup(id: u8) -> Option<(Instruction, InstructionDescriptor)> {
+    Instruction::from_value(id).map(|x| (x, x.descriptor()))
+}
+
+/// Both lookups must agree on every possible ID, including the ones without an instruction
+fn check_lookups_match(array: &ArrayLookup) {
+    for id in 0..=Instruction::MASK {
+        let expected = hashmap_lookup(id);
+        let actual = array.lookup(id);
+        assert!(
+            expected == actual,
+            "Lookups disagree on instruction ID {:02X}",
+            id
+        );
+    }
+}
+
+/// Builds a stream of instruction bytes with pseudo-random valid IDs and operand counts in the top bits
+fn build_stream() -> Vec<u8> {
+    let valid_ids: Vec<u8> = (0..=Instruction::MASK)
+        .filter(|x| Instruction::from_value(*x).is_some())
+        .collect();
+
+    // Fixed xorshift generator, so every run dispatches the same stream
+    let mut state = 0x2545_F491_4F6C_DD1Du64;
+    (0..STREAM_LENGTH)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            let id = valid_ids[(state % valid_ids.len() as u64) as usize];
+            let operand_count = ((state >> 32) % 4) as u8;
+            id | (operand_count << Instruction::SHIFT)
+        })
+        .collect()
+}
+
+/// Decodes every instruction byte in the stream and "dispatches" it by adding up its cycle cost
+fn dispatch(
+    stream: &[u8],
+    lookup: impl Fn(u8) -> Option<(Instruction, InstructionDescriptor)>,
+) -> u64 {
+    stream
+        .iter()
+        .map(|byte| match lookup(byte & Instruction::MASK) {
+            Some((_, descriptor)) => descriptor.cycles,
+            None => panic!("Stream contains an invalid instruction {:02X}", byte),
+        })
+        .sum()
+}
+
+fn measure(
+    stream: &[u8],
+    lookup: impl Fn(u8) -> Option<(Instruction, InstructionDescriptor)>,
+) -> (Duration, u64) {
+    let mut best = Duration::MAX;
+    let mut cycles = 0;
+
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        cycles = black_box(dispatch(black_box(stream), &lookup));
+        best = best.min(start.elapsed());
+    }
+
+    (best, cycles)
+}
+
+fn main() {
+    let array = ArrayLookup::new();
+    check_lookups_match(&array);
+
+    let stream = build_stream();
+    let (hashmap_time, hashmap_cycles) = measure(&stream, hashmap_lookup);
+    let (array_time, array_cycles) = measure(&stream, |id| array.lookup(id));
+    assert_eq!(
+        hashmap_cycles, array_cycles,
+        "Lookups dispatched the stream differently"
+    );
+
+    let per_instruction = |time: Duration| time.as_nanos() as f64 / STREAM_LENGTH as f64;
+    println!(
+        "Dispatching {} instructions, best of {} runs",
+        STREAM_LENGTH, RUNS
+    );
+    println!(
+        "  HashMap: {:>10.3?} ({:.2} ns/instruction)",
+        hashmap_time,
+        per_instruction(hashmap_time)
+    );
+    println!(
+        "  Array:   {:>10.3?} ({:.2} ns/instruction)",
+        array_time,
+        per_instruction(array_time)
+    );
+    println!(
+        "  Array lookup is {:.1}x faster",
+        hashmap_time.as_secs_f64() / array_time.as_secs_f64()
+    );
+}
@@ -0,0 +1,86 @@
+//! Measures the fill native, which writes the bytes of a buffer and clears the reference bit of every word it touches.
+//! Run with `cargo bench --bench memory_fill`
+
+use lakesis::assembler::{self, AssemblerOptions};
+use lakesis::interpreter::Machine;
+use std::time::{Duration, Instant};
+
+/// Size of the buffer that gets filled, in bytes
+const BUFFER_SIZE: u64 = 4 * 1024 * 1024;
+/// Number of fills each program does
+const FILLS: u64 = 64;
+/// Number of times each program is run, the fastest run is reported
+const RUNS: usize = 5;
+
+/// Builds a program that fills `length` bytes of a buffer starting `offset` bytes into it, `FILLS` times
+fn build_program(offset: u64, length: u64) -> Vec<u8> {
+    let source = format!(
+        "
+        new {size}, r0
+        add {offset}, r0
+        mov {fills}, r1
+        loop:
+            push 0xAA
+            push {length}
+            push r0
+            native 3
+            pop r2
+            pop r2
+            pop r2
+            sub 1, r1
+            cmp r1, 0
+            jne loop
+        halt
+        ",
+        size = BUFFER_SIZE,
+        offset = offset,
+        fills = FILLS,
+        length = length,
+    );
+
+    let mut program = Vec::new();
+    assembler::assemble(
+        &mut source.as_bytes(),
+        &mut program,
+        &AssemblerOptions::default(),
+    )
+    .unwrap();
+    program
+}
+
+fn measure(program: &[u8]) -> Duration {
+    let mut best = Duration::MAX;
+
+    for _ in 0..RUNS {
+        // Loading allocates the stack and the program, which isn't what's being measured
+        let mut machine = Machine::load(&mut &program[..]).unwrap();
+        let start = Instant::now();
+        machine.run().unwrap();
+        best = best.min(start.elapsed());
+    }
+
+    best
+}
+
+fn main() {
+    println!(
+        "Filling a {} byte buffer {} times, best of {} runs",
+        BUFFER_SIZE, FILLS, RUNS
+    );
+
+    // Unaligned fills only touch part of their first and last words
+    let cases = [
+        ("Aligned", 0, BUFFER_SIZE),
+        ("Unaligned", 3, BUFFER_SIZE - 8),
+    ];
+    for (name, offset, length) in cases.iter() {
+        let time = measure(&build_program(*offset, *length));
+        let total_bytes = (length * FILLS) as f64;
+        println!(
+            "  {:<10} {:>10.3?} ({:.2} GiB/s)",
+            name,
+            time,
+            total_bytes / time.as_secs_f64() / (1024.0 * 1024.0 * 1024.0)
+        );
+    }
+}
@@ -1,46 +1,113 @@
-use super::parser::{Operand, Token, TokenValue};
-use super::{Error, FileRange, Result, VoidResult};
-use crate::core::UWord;
+use super::parser::{DefineValue, Operand, Token, TokenValue};
+use super::{Error, FileRange, Phase, Reports, Result, Symbols, VoidResult, Warning};
+use crate::core::{IWord, UWord, MAX_MEMORY_SIZE, WORD_BYTE_SIZE};
+use crate::executable::{PreloadSection, Section, SectionKind};
 use crate::opcodes::{Instruction, Operand as CoreOperand};
-use std::collections::HashMap;
-use std::io::{Seek, SeekFrom, Write};
+use std::cmp::min;
+use std::collections::{HashMap, HashSet};
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
 use std::slice;
 
-struct Encoder<'a, T>
+struct Encoder<'a, 'r, T>
 where
-    T: Write + Seek,
+    T: Read + Write + Seek,
 {
     tokens: &'a [Token],
     output: &'a mut T,
+    reports: Reports<'r>,
     index: usize,
     label_values: HashMap<String, u64>,
-    fixups: HashMap<u64, String>,
+    /// Where every code label was declared, used to report the ones that are never referenced
+    label_ranges: HashMap<String, FileRange>,
+    used_labels: HashSet<String>,
+    /// Most recent code label that doesn't start with a dot, which local labels are scoped to
+    global_label: Option<String>,
+    /// Label and constant offset whose sum must be written at each output offset once all labels are known,
+    /// along with where they are in the source code
+    fixups: HashMap<u64, (String, IWord, FileRange)>,
+    sections: Vec<Section>,
+    current_section: Option<(SectionKind, u64)>,
+    /// Preloaded data block that receives everything written until `.endpreload`, along with where it was declared
+    preload: Option<(PreloadSection, FileRange)>,
+    /// Every finished preloaded data block, in source order
+    preloads: Vec<(PreloadSection, FileRange)>,
+    conditions: Vec<Condition>,
+    /// Output range and index of every token that was assembled, only filled if a listing was requested
+    listing: Vec<(u64, u64, usize)>,
+    /// Where the data directive that wrote the first bytes of the program was declared, if it wasn't an instruction
+    data_at_start: Option<FileRange>,
+    /// Warnings found while encoding, in source order
+    warnings: Vec<Warning>,
+    /// If a jump to the entry label is placed before everything else
+    entry_jump: bool,
+    /// Where the entry label was declared, if it was assembled
+    entry_label: Option<FileRange>,
 }
 
-struct OperandData<'a> {
-    addressing_mode: u8,
-    register_number: u8,
-    value_is_positive: bool,
-    value_absolute: UWord,
-    label: Option<&'a str>,
+/// Everything produced by encoding a program, other than the program itself
+pub struct EncodedProgram {
+    pub sections: Vec<Section>,
+    pub preloads: Vec<PreloadSection>,
+    pub warnings: Vec<Warning>,
+    pub symbols: Symbols,
 }
 
-impl<T> Encoder<'_, T>
+/// Label where execution starts, if the program declares it
+const ENTRY_LABEL: &str = "_start";
+
+/// An `.if` block that hasn't been closed yet
+struct Condition {
+    /// If the block containing this `.if` is being assembled
+    parent_active: bool,
+    /// Result of evaluating the `.if` symbol
+    value: bool,
+    /// If the `.else` of this block was already seen
+    in_else: bool,
+    range: FileRange,
+}
+
+impl<'r, T> Encoder<'_, 'r, T>
 where
-    T: Write + Seek,
+    T: Read + Write + Seek,
 {
-    fn new<'a>(tokens: &'a [Token], output: &'a mut T) -> Encoder<'a, T> {
+    fn new<'a>(
+        tokens: &'a [Token],
+        output: &'a mut T,
+        reports: Reports<'r>,
+        entry_jump: bool,
+    ) -> Encoder<'a, 'r, T> {
         Encoder {
             tokens,
             output,
+            reports,
             index: 0,
             label_values: HashMap::new(),
+            label_ranges: HashMap::new(),
+            used_labels: HashSet::new(),
+            global_label: None,
             fixups: HashMap::new(),
+            sections: Vec::new(),
+            current_section: None,
+            preload: None,
+            preloads: Vec::new(),
+            conditions: Vec::new(),
+            listing: Vec::new(),
+            data_at_start: None,
+            warnings: Vec::new(),
+            entry_jump,
+            entry_label: None,
         }
     }
 
+    /// Address of the next byte that will be written, which is inside the preloaded data block if there is one
     fn offset(&mut self) -> Result<u64> {
-        Ok(self.output.seek(SeekFrom::Current(0))?)
+        if let Some((preload, _)) = &self.preload {
+            return Ok(preload.address + preload.data.len() as u64);
+        }
+
+        self.output
+            .stream_position()
+            .map_err(|e| Error::from_io(Phase::Encoder, e))
     }
 
     fn is_eof(&self) -> bool {
@@ -66,7 +133,8 @@ where
     fn make_error(&self, msg: &str) -> Error {
         Error {
             message: msg.to_owned(),
-            range: self.range(),
+            range: Some(self.range()),
+            phase: Phase::Encoder,
         }
     }
 
@@ -80,82 +148,526 @@ where
     }
 
     fn write(&mut self, bytes: &[u8]) -> VoidResult {
-        self.output.write_all(bytes)?;
-        Ok(())
+        if let Some((preload, _)) = &mut self.preload {
+            preload.data.extend_from_slice(bytes);
+            return Ok(());
+        }
+
+        self.output
+            .write_all(bytes)
+            .map_err(|e| Error::from_io(Phase::Encoder, e))
     }
 
     fn write_byte(&mut self, byte: u8) -> VoidResult {
         self.write(slice::from_ref(&byte))
     }
 
-    fn encode(mut self) -> VoidResult {
+    fn encode(mut self) -> Result<EncodedProgram> {
+        self.encode_tokens()?;
+
+        if let Some(condition) = self.conditions.last() {
+            return Err(Error {
+                message: "Missing .endif for .if".to_owned(),
+                range: Some(condition.range),
+                phase: Phase::Encoder,
+            });
+        }
+
+        if let Some((_, range)) = &self.preload {
+            return Err(Error {
+                message: "Missing .endpreload for .preload".to_owned(),
+                range: Some(*range),
+                phase: Phase::Encoder,
+            });
+        }
+
+        self.check_entry_label()?;
+        self.end_section()?;
+        self.check_preloads()?;
+        self.fixup()?;
+        self.write_listing()?;
+
+        let mut warnings = std::mem::take(&mut self.warnings);
+        warnings.extend(self.unused_label_warnings());
+        if let Some(range) = self.data_at_start {
+            // Execution always starts at offset 0, so this data would be run as if it were code
+            warnings.insert(
+                0,
+                Warning {
+                    message: "Program starts with data instead of an instruction, add a jmp to the entry point before it".to_owned(),
+                    range,
+                },
+            );
+        }
+
+        Ok(EncodedProgram {
+            sections: self.sections,
+            preloads: self.preloads.into_iter().map(|(x, _)| x).collect(),
+            warnings,
+            symbols: self.label_values,
+        })
+    }
+
+    fn encode_tokens(&mut self) -> VoidResult {
+        if self.entry_jump {
+            self.encode_entry_jump()?;
+        }
+
         while !self.is_eof() {
             self.encode_single()?;
         }
 
-        self.fixup()?;
         Ok(())
     }
 
+    /// Execution always starts at offset 0, so programs with an entry label get a jump to it before anything else
+    fn encode_entry_jump(&mut self) -> VoidResult {
+        // Errors about the jump itself point at the first declaration, as the one that's assembled isn't known yet
+        let range = self
+            .tokens
+            .iter()
+            .find(|t| matches!(&t.value, TokenValue::Label(name) if name == ENTRY_LABEL))
+            .map_or_else(|| self.range(), |t| t.range);
+
+        self.encode_opcode(
+            Instruction::Jump,
+            &[Operand::Label {
+                name: ENTRY_LABEL.to_owned(),
+                offset: 0,
+                range,
+            }],
+        )
+    }
+
+    /// Whether to place the jump to the entry label is decided by assembling the program with the jump first.
+    /// Without the jump, labels move and an `.if` that depends on their addresses could assemble the entry label
+    /// after all, which would leave it unreachable
+    fn check_entry_label(&self) -> VoidResult {
+        match self.entry_label {
+            Some(range) if !self.entry_jump => Err(Error {
+                message: format!(
+                    "{} is only assembled when there's no jump to it, make the .if around it not depend on label addresses",
+                    ENTRY_LABEL
+                ),
+                range: Some(range),
+                phase: Phase::Encoder,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    fn unused_label_warnings(&self) -> Vec<Warning> {
+        let mut warnings: Vec<Warning> = self
+            .label_ranges
+            .iter()
+            .filter(|(label, _)| !self.used_labels.contains(*label))
+            .map(|(label, range)| Warning {
+                message: format!("Label {} is never used", label),
+                range: *range,
+            })
+            .collect();
+
+        warnings.sort_unstable_by_key(|w| (w.range.start.line, w.range.start.column));
+        warnings
+    }
+
     fn encode_single(&mut self) -> VoidResult {
+        let is_active = self.is_active();
+        let start = self.offset()?;
+        let is_condition = matches!(
+            self.peek(),
+            TokenValue::If(_) | TokenValue::Else | TokenValue::EndIf
+        );
+
         match self.peek().clone() {
-            TokenValue::Label(s) => self.remember_label(&s)?,
-            TokenValue::Define { label, value } => {
-                self.set_label_value_without_override(&label, value as u64)?
+            TokenValue::If(symbol) => self.start_condition(&symbol)?,
+            TokenValue::Else => self.else_condition()?,
+            TokenValue::EndIf => self.end_condition()?,
+            _ if !is_active => {}
+
+            TokenValue::Opcode { .. } | TokenValue::Section(_) | TokenValue::Org(_)
+                if self.preload.is_some() =>
+            {
+                return Err(
+                    self.make_error("Only data can be preloaded, add .endpreload before this")
+                )
             }
+            TokenValue::Label(s) => self.remember_label(&s)?,
+            TokenValue::Define { label, value } => self.define_symbol(&label, &value)?,
             TokenValue::String {
                 length_label,
                 value,
-            } => self.encode_string(length_label.as_ref(), &value)?,
-            TokenValue::Align(n) => self.align_output(n)?,
+                null_terminated,
+            } => self.encode_string(length_label.as_ref(), &value, null_terminated)?,
+            TokenValue::Bytes(bytes) => self.write(&bytes)?,
+            TokenValue::Words {
+                length_label,
+                values,
+            } => self.encode_words(length_label.as_ref(), &values)?,
+            TokenValue::Zero(count) => self.zero_output(count)?,
+            TokenValue::SizeOf { label, start } => self.define_size_since(&label, &start)?,
+            TokenValue::Align { alignment, fill } => self.align_output(alignment, fill)?,
+            TokenValue::Section(kind) => self.start_section(kind)?,
+            TokenValue::Org(offset) => self.org_output(offset)?,
+            TokenValue::Preload { name, address } => self.start_preload(&name, address)?,
+            TokenValue::EndPreload => self.end_preload()?,
             TokenValue::Opcode {
                 instruction,
                 operands,
             } => self.encode_opcode(instruction, &operands)?,
         }
 
+        let end = self.offset()?;
+        // Entering or leaving a preloaded data block moves the output somewhere else without writing anything
+        let start = if matches!(
+            self.peek(),
+            TokenValue::Preload { .. } | TokenValue::EndPreload
+        ) {
+            end
+        } else {
+            start
+        };
+        let is_data = matches!(
+            self.peek(),
+            TokenValue::String { .. } | TokenValue::Bytes(_) | TokenValue::Words { .. }
+        );
+        if start == 0 && end > 0 && is_data && self.preload.is_none() {
+            self.data_at_start = Some(self.range());
+        }
+
+        if self.reports.listing.is_some() && (is_active || is_condition) {
+            self.listing.push((start, end, self.index));
+        }
+
         self.consume();
         Ok(())
     }
 
-    fn align_output(&mut self, alignment: UWord) -> VoidResult {
+    /// Checks if tokens are being assembled, or skipped because they're in an untaken `.if` branch
+    fn is_active(&self) -> bool {
+        match self.conditions.last() {
+            Some(condition) => condition.parent_active && (condition.value != condition.in_else),
+            None => true,
+        }
+    }
+
+    fn start_condition(&mut self, symbol: &str) -> VoidResult {
+        let parent_active = self.is_active();
+
+        // Symbols inside untaken branches might not be defined, so don't even try to evaluate them
+        let value = if parent_active {
+            let symbol = self.qualify_label(symbol)?;
+            self.used_labels.insert(symbol.clone());
+            match self.label_values.get(&symbol) {
+                Some(x) => *x != 0,
+                None => {
+                    return Err(self.make_error(&format!(
+                        "Symbol {} must be defined before being used in .if",
+                        symbol
+                    )))
+                }
+            }
+        } else {
+            false
+        };
+
+        self.conditions.push(Condition {
+            parent_active,
+            value,
+            in_else: false,
+            range: self.range(),
+        });
+
+        Ok(())
+    }
+
+    fn else_condition(&mut self) -> VoidResult {
+        let range = self.range();
+        match self.conditions.last_mut() {
+            Some(condition) if !condition.in_else => {
+                condition.in_else = true;
+                Ok(())
+            }
+            Some(_) => Err(Error {
+                message: "Duplicate .else for the same .if".to_owned(),
+                range: Some(range),
+                phase: Phase::Encoder,
+            }),
+            None => Err(self.make_error(".else without a matching .if")),
+        }
+    }
+
+    fn end_condition(&mut self) -> VoidResult {
+        match self.conditions.pop() {
+            Some(_) => Ok(()),
+            None => Err(self.make_error(".endif without a matching .if")),
+        }
+    }
+
+    fn align_output(&mut self, alignment: UWord, fill: u8) -> VoidResult {
         if alignment <= 1 {
             return Err(self.make_error("Alignment must be bigger than 1"));
         }
 
-        while self.offset()? % alignment != 0 {
-            self.write_byte(0)?;
+        let offset = self.offset()?;
+        let padding = (alignment - offset % alignment) % alignment;
+        self.pad_output(padding, fill)
+    }
+
+    fn zero_output(&mut self, count: UWord) -> VoidResult {
+        if count == 0 {
+            return Err(self.make_error(".zero must write at least 1 byte"));
+        }
+
+        self.pad_output(count, 0)
+    }
+
+    fn org_output(&mut self, target: UWord) -> VoidResult {
+        let current = self.offset()?;
+        if target < current {
+            return Err(self.make_error(&format!(
+                "Cannot move backwards to offset {:#X}, output is already at offset {:#X}",
+                target, current
+            )));
+        }
+
+        self.pad_output(target - current, 0)
+    }
+
+    /// Writes `count` copies of `fill`, as long as the program still fits in the machine's memory afterwards.
+    /// Stops typos in `.zero`, `.org` and `.align` from trying to write a huge amount of bytes
+    fn pad_output(&mut self, count: UWord, fill: u8) -> VoidResult {
+        let offset = self.offset()?;
+        // Preloaded data has the same limit as the program, no matter which address it's loaded at
+        let size = match &self.preload {
+            Some((preload, _)) => preload.data.len() as UWord,
+            None => offset,
+        };
+        match size.checked_add(count) {
+            Some(end) if end <= MAX_MEMORY_SIZE as UWord => {}
+            _ => {
+                return Err(self.make_error(&format!(
+                    "Writing {} more bytes at offset {:#X} would make the program bigger than the maximum of {} bytes",
+                    count, offset, MAX_MEMORY_SIZE
+                )))
+            }
+        }
+
+        self.write(&vec![fill; count as usize])
+    }
+
+    fn start_section(&mut self, kind: SectionKind) -> VoidResult {
+        // Anything before the first section is treated as code
+        if self.current_section.is_none() {
+            self.current_section = Some((SectionKind::Code, 0));
+        }
+
+        self.end_section()?;
+        self.current_section = Some((kind, self.offset()?));
+        Ok(())
+    }
+
+    fn end_section(&mut self) -> VoidResult {
+        let (kind, start) = match self.current_section.take() {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+
+        let end = self.offset()?;
+        if start < end {
+            self.sections.push(Section { kind, start, end });
+        }
+
+        Ok(())
+    }
+
+    fn start_preload(&mut self, name: &str, address: UWord) -> VoidResult {
+        if self.preload.is_some() {
+            return Err(self
+                .make_error("Preloaded data blocks can't be nested, add .endpreload before this"));
+        }
+
+        let preload = PreloadSection {
+            name: name.to_owned(),
+            address,
+            data: Vec::new(),
+        };
+        self.preload = Some((preload, self.range()));
+        Ok(())
+    }
+
+    fn end_preload(&mut self) -> VoidResult {
+        match self.preload.take() {
+            Some(x) => {
+                self.preloads.push(x);
+                Ok(())
+            }
+            None => Err(self.make_error(".endpreload without a matching .preload")),
+        }
+    }
+
+    /// Preloaded data is placed in memory after the program, so it can't overlap the program or other preloaded data.
+    /// Alignment isn't checked here, as it depends on the page size of the machine that loads the program
+    fn check_preloads(&mut self) -> VoidResult {
+        let mut end = self.offset()?;
+        let mut preloads: Vec<&(PreloadSection, FileRange)> = self.preloads.iter().collect();
+        preloads.sort_by_key(|(x, _)| x.address);
+
+        for (preload, range) in preloads {
+            if preload.address < end {
+                return Err(Error {
+                    message: format!(
+                        "Preloaded data {} at {:#X} overlaps the program or other preloaded data, which end at {:#X}",
+                        preload.name, preload.address, end
+                    ),
+                    range: Some(*range),
+                    phase: Phase::Encoder,
+                });
+            }
+
+            end = preload.address + preload.data.len() as u64;
         }
 
         Ok(())
     }
 
     fn remember_label(&mut self, name: &str) -> VoidResult {
+        if name == ENTRY_LABEL {
+            if self.preload.is_some() {
+                return Err(self.make_error(&format!(
+                    "{} is where execution starts, so it can't be in preloaded data",
+                    ENTRY_LABEL
+                )));
+            }
+            self.entry_label = Some(self.range());
+        }
+
+        if !name.starts_with('.') {
+            self.global_label = Some(name.to_owned());
+        }
+
+        let name = self.qualify_label(name)?;
         let offset = self.offset()?;
-        self.set_label_value_without_override(name, offset)
+        self.set_label_value_without_override(&name, offset)?;
+        self.label_ranges.insert(name, self.range());
+        Ok(())
     }
 
     fn set_label_value_without_override(&mut self, name: &str, value: u64) -> VoidResult {
-        match self.label_values.insert(name.to_owned(), value) {
+        let name = self.qualify_label(name)?;
+        match self.label_values.insert(name.clone(), value) {
             None => Ok(()),
             Some(_) => Err(self.make_error(&format!("Redefinition of label {}", name))),
         }
     }
 
-    fn encode_string(&mut self, length_label: Option<&String>, value: &str) -> VoidResult {
-        let bytes = value.as_bytes();
+    /// Symbols are resolved right away, so they must be defined before the `.define` that uses them
+    fn define_symbol(&mut self, label: &str, value: &DefineValue) -> VoidResult {
+        let value = match value {
+            DefineValue::Number(n) => *n as u64,
+            DefineValue::Symbol(symbol) => {
+                let symbol = self.qualify_label(symbol)?;
+                self.used_labels.insert(symbol.clone());
+                match self.label_values.get(&symbol) {
+                    Some(x) => *x,
+                    None => {
+                        return Err(self.make_error(&format!(
+                            "Symbol {} must be defined before being used in .define",
+                            symbol
+                        )))
+                    }
+                }
+            }
+        };
+
+        self.set_label_value_without_override(label, value)
+    }
+
+    /// Local labels start with a dot and are scoped to the most recent global label,
+    /// so `.loop` after `foo:` is stored as `foo.loop`. Other labels are returned as-is
+    fn qualify_label(&self, name: &str) -> Result<String> {
+        if !name.starts_with('.') {
+            return Ok(name.to_owned());
+        }
+
+        match &self.global_label {
+            Some(global) => Ok(format!("{}{}", global, name)),
+            None => Err(self.make_error(&format!(
+                "Local label {} must come after a global label",
+                name
+            ))),
+        }
+    }
+
+    /// Defines `label` as the number of bytes written since the label `start`, which must come before it
+    fn define_size_since(&mut self, label: &str, start: &str) -> VoidResult {
+        let start = self.qualify_label(start)?;
+        let start_offset = match self.label_values.get(&start) {
+            Some(x) => *x,
+            None => {
+                return Err(self.make_error(&format!(
+                    "Label {} must be defined before it can be measured from",
+                    start
+                )))
+            }
+        };
+
+        let offset = self.offset()?;
+        if offset < start_offset {
+            return Err(self.make_error(&format!("Label {} is after the current offset", start)));
+        }
+
+        self.used_labels.insert(start);
+        self.set_label_value_without_override(label, offset - start_offset)
+    }
+
+    /// The length label includes the 0 byte of null-terminated strings
+    fn encode_string(
+        &mut self,
+        length_label: Option<&String>,
+        bytes: &[u8],
+        null_terminated: bool,
+    ) -> VoidResult {
         if let Some(label) = length_label {
-            self.set_label_value_without_override(label, bytes.len() as u64)?;
+            let length = bytes.len() + null_terminated as usize;
+            self.set_label_value_without_override(label, length as u64)?;
+        }
+
+        self.write(bytes)?;
+        if null_terminated {
+            self.write_byte(0)?;
         }
 
-        self.write(bytes)
+        Ok(())
+    }
+
+    fn encode_words(&mut self, length_label: Option<&String>, values: &[IWord]) -> VoidResult {
+        // Words are read with aligned memory accesses, so they can't straddle a word boundary
+        if self.offset()? % WORD_BYTE_SIZE != 0 {
+            return Err(self.make_error(
+                ".word must start at a multiple of the word size, use .align 1w before it",
+            ));
+        }
+
+        if let Some(label) = length_label {
+            self.set_label_value_without_override(label, values.len() as u64)?;
+        }
+
+        for value in values {
+            self.write(&value.to_le_bytes())?;
+        }
+
+        Ok(())
     }
 
     fn encode_opcode(&mut self, instr: Instruction, operands: &[Operand]) -> VoidResult {
-        let mut value = instr as u8 & Instruction::MASK;
-        value |= ((operands.len() as u8) << Instruction::SHIFT) & !Instruction::MASK;
+        if instr.descriptor().is_jump {
+            self.check_jump_target(operands);
+        }
 
-        self.write_byte(value)?;
+        instr
+            .encode_header(operands.len(), self.output)
+            .map_err(|e| self.make_error(&e.to_string()))?;
         for operand in operands {
             self.encode_operand(operand)?;
         }
@@ -163,103 +675,469 @@ where
         Ok(())
     }
 
+    /// Warns about jumps to negative immediates, which the machine reads as unsigned and so wrap around to the end of memory
+    fn check_jump_target(&mut self, operands: &[Operand]) {
+        if let Some(Operand::Immediate(x)) = operands.first() {
+            if *x < 0 {
+                self.warnings.push(Warning {
+                    message: format!(
+                        "Jump target {} is negative and wraps around to address {:016X}",
+                        x, *x as UWord
+                    ),
+                    range: self.range(),
+                });
+            }
+        }
+    }
+
     fn encode_operand(&mut self, operand: &Operand) -> VoidResult {
-        let data = Self::get_operand_data(operand);
-        let mut first_byte = 0;
+        let core_operand = match *operand {
+            Operand::Label {
+                ref name,
+                offset: label_offset,
+                range,
+            } => {
+                let label = self.qualify_label(name)?;
+                let value_offset = self.offset()? + 1;
+                self.fixups
+                    .insert(value_offset, (label.clone(), label_offset, range));
+                self.used_labels.insert(label);
 
-        first_byte |= (data.addressing_mode << CoreOperand::ADDRESSING_MODE_SHIFT)
-            & CoreOperand::ADDRESSING_MODE_MASK;
+                // The value of the label is only known once the whole program is encoded,
+                // so leave room for any value and let `fixup` fill it in
+                return self
+                    .encode_core_operand(CoreOperand::Immediate(0), CoreOperand::MAX_VALUE_SIZE);
+            }
+            Operand::Immediate(x) => CoreOperand::Immediate(x),
+            Operand::Register(r) => CoreOperand::Register(r),
+            Operand::Reference { register, offset } => CoreOperand::Reference { register, offset },
+            Operand::Stack(x) => CoreOperand::Stack(x),
+            Operand::Indexed { base, index } => CoreOperand::Indexed { base, index },
+        };
 
-        first_byte |= (data.register_number << CoreOperand::REGISTER_NUM_SHIFT)
-            & CoreOperand::REGISTER_NUM_MASK;
+        self.encode_core_operand(core_operand, core_operand.encoded_size() - 1)
+    }
 
-        if !data.value_is_positive {
-            first_byte |= CoreOperand::SIGN_MASK;
-        }
+    fn encode_core_operand(&mut self, operand: CoreOperand, value_size: usize) -> VoidResult {
+        operand
+            .encode_with_value_size(self.output, value_size)
+            .map_err(|e| self.make_error(&e.to_string()))
+    }
 
-        let mut value_bytes: Vec<u8> = data.value_absolute.to_le_bytes().iter().cloned().collect();
-        if data.label.is_some() {
-            value_bytes.pop();
-        } else {
-            while value_bytes.ends_with(&[0]) {
-                value_bytes.pop();
-            }
-        }
+    /// Writes the address and bytes of every assembled token to the listing report, along with where it came from
+    /// in the source code. Only the first few bytes of long directives are shown
+    fn write_listing(&mut self) -> VoidResult {
+        const MAX_BYTES: u64 = 8;
 
-        if value_bytes.len() > 7 {
-            return Err(self.make_error("Operand value cannot be longer than 7 bytes"));
-        }
+        let report = match self.reports.listing.take() {
+            Some(x) => x,
+            None => return Ok(()),
+        };
 
-        first_byte |= ((value_bytes.len() as u8) << CoreOperand::VALUE_SIZE_SHIFT)
-            & CoreOperand::VALUE_SIZE_MASK;
+        for (start, end, index) in std::mem::take(&mut self.listing) {
+            let bytes = self.written_bytes(start, min(end - start, MAX_BYTES))?;
 
-        self.write_byte(first_byte)?;
+            let mut hex: Vec<String> = bytes.iter().map(|x| format!("{:02X}", x)).collect();
+            if end - start > MAX_BYTES {
+                hex.push("...".to_owned());
+            }
 
-        let offset = self.offset()?;
-        if let Some(label) = data.label {
-            self.fixups.insert(offset, label.to_owned());
+            let token = &self.tokens[index];
+            writeln!(
+                report,
+                "LISTING | {:016X} | {:<27} | {:<15} | {}",
+                start,
+                hex.join(" "),
+                token.range,
+                token.value
+            )
+            .map_err(|e| Error::from_io(Phase::Encoder, e))?;
         }
 
-        self.write(&value_bytes)?;
         Ok(())
     }
 
-    fn get_operand_data(operand: &Operand) -> OperandData {
-        match operand {
-            Operand::Label(l) => OperandData {
-                addressing_mode: 0,
-                register_number: 0,
-                value_is_positive: true,
-                value_absolute: 0,
-                label: Some(l),
-            },
-            Operand::Immediate(x) => OperandData {
-                addressing_mode: 0,
-                register_number: 0,
-                value_is_positive: *x >= 0,
-                value_absolute: x.abs() as UWord,
-                label: None,
-            },
-            Operand::Register(r) => OperandData {
-                addressing_mode: 1,
-                register_number: *r,
-                value_is_positive: true,
-                value_absolute: 0,
-                label: None,
-            },
-            Operand::Reference { register, offset } => OperandData {
-                addressing_mode: 2,
-                register_number: *register,
-                value_is_positive: *offset >= 0,
-                value_absolute: offset.abs() as UWord,
-                label: None,
-            },
-            Operand::Stack(o) => OperandData {
-                addressing_mode: 3,
-                register_number: 0,
-                value_is_positive: true,
-                value_absolute: *o,
-                label: None,
-            },
+    /// Reads back `length` bytes that were written at `start`, which is either in the program or in a preloaded data block
+    fn written_bytes(&mut self, start: u64, length: u64) -> Result<Vec<u8>> {
+        let preload = self
+            .preloads
+            .iter()
+            .find(|(x, _)| start >= x.address && start + length <= x.address + x.data.len() as u64);
+        if let Some((preload, _)) = preload {
+            let offset = (start - preload.address) as usize;
+            return Ok(preload.data[offset..offset + length as usize].to_vec());
         }
+
+        let mut bytes = vec![0; length as usize];
+        self.output
+            .seek(SeekFrom::Start(start))
+            .and_then(|_| self.output.read_exact(&mut bytes))
+            .map_err(|e| Error::from_io(Phase::Encoder, e))?;
+        Ok(bytes)
     }
 
     fn fixup(&mut self) -> VoidResult {
-        for (offset, label) in &self.fixups {
+        let mut sorted_fixups: Vec<(&u64, &(String, IWord, FileRange))> =
+            self.fixups.iter().collect();
+        sorted_fixups.sort_unstable_by_key(|(offset, _)| **offset);
+
+        for (offset, (label, label_offset, range)) in sorted_fixups {
+            let error = |message: String| Error {
+                message,
+                range: Some(*range),
+                phase: Phase::Encoder,
+            };
+
             let label_value = match self.label_values.get(label) {
                 Some(x) => *x,
-                None => return Err(Error::from_message(&format!("Label {} not found", label))),
+                None => return Err(error(format!("Label {} not found", label))),
             };
 
-            self.output.seek(SeekFrom::Start(*offset))?;
-            let bytes = label_value.to_le_bytes();
-            self.output.write_all(&bytes[0..7])?;
+            // Constants can be negative, so the value is written as a signed immediate like any other number
+            let label_value = match (label_value as IWord).checked_add(*label_offset) {
+                Some(x) => x,
+                None => {
+                    return Err(error(format!(
+                        "Label {} plus {} is out of range",
+                        label, label_offset
+                    )))
+                }
+            };
+
+            if let Some(report) = self.reports.fixups.as_mut() {
+                writeln!(
+                    report,
+                    "FIXUP | {:016X} {} -> {:016X}",
+                    offset, label, label_value as UWord
+                )
+                .map_err(|e| Error::from_io(Phase::Encoder, e))?;
+            }
+
+            // The whole operand is written again, as the sign of the value is in its first byte
+            self.output
+                .seek(SeekFrom::Start(*offset - 1))
+                .map_err(|e| Error::from_io(Phase::Encoder, e))?;
+            CoreOperand::Immediate(label_value)
+                .encode_with_value_size(self.output, CoreOperand::MAX_VALUE_SIZE)
+                .map_err(|e| error(e.to_string()))?;
         }
 
         Ok(())
     }
 }
 
-pub fn encode(tokens: &[Token], output: &mut (impl Write + Seek)) -> VoidResult {
-    Encoder::new(tokens, output).encode()
+/// Encodes the tokens into `output`, returning the sections and preloaded data declared by the program, any warnings found,
+/// and the final value of every label and constant. Code labels are absolute offsets into `output`,
+/// except for labels inside preloaded data, which are addresses where that data gets loaded
+pub fn encode_with_symbols(
+    tokens: &[Token],
+    output: &mut (impl Read + Write + Seek),
+    reports: Reports<'_>,
+) -> Result<EncodedProgram> {
+    let entry_jump = assembles_entry_label(tokens);
+    Encoder::new(tokens, output, reports, entry_jump).encode()
+}
+
+/// Checks if the entry label is declared outside of any untaken `.if` branch, which can only be known by
+/// assembling the program. The jump to the label is assumed to be there, as that's the only way it can be reached
+fn assembles_entry_label(tokens: &[Token]) -> bool {
+    let declared = tokens
+        .iter()
+        .any(|t| matches!(&t.value, TokenValue::Label(name) if name == ENTRY_LABEL));
+    if !declared {
+        return false;
+    }
+
+    let mut output = Cursor::new(Vec::new());
+    let mut encoder = Encoder::new(tokens, &mut output, Reports::default(), true);
+    match encoder.encode_tokens() {
+        Ok(()) => encoder.entry_label.is_some(),
+        // Assembling again with the jump fails with the same error, which is reported from there
+        Err(_) => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::{
+        assemble, assemble_with_symbols, AssemblerOptions, Error, FilePosition, Reports,
+    };
+    use crate::core::IWord;
+    use crate::opcodes::{Opcode, Operand as CoreOperand};
+
+    fn assemble_source(source: &str) -> Result<Vec<u8>, Error> {
+        let mut program = Vec::new();
+        assemble(
+            &mut source.as_bytes(),
+            &mut program,
+            &AssemblerOptions::default(),
+        )?;
+        Ok(program)
+    }
+
+    #[test]
+    fn fixup_report_lists_forward_and_backward_references() {
+        let source = "start:\n    jmp end\nend:\n    jmp start\n";
+        let mut report = Vec::new();
+        let reports = Reports {
+            fixups: Some(&mut report),
+            ..Default::default()
+        };
+
+        assemble_with_symbols(
+            &mut source.as_bytes(),
+            &mut Vec::new(),
+            &AssemblerOptions::default(),
+            reports,
+        )
+        .unwrap();
+
+        // Each jmp is 10 bytes long, and the label's value starts 2 bytes into it
+        assert_eq!(
+            String::from_utf8(report).unwrap(),
+            "FIXUP | 0000000000000002 end -> 000000000000000A\n\
+             FIXUP | 000000000000000C start -> 0000000000000000\n"
+        );
+    }
+
+    #[test]
+    fn label_operands_can_be_negative_constants_or_offset_labels() {
+        let source =
+            ".define NEG -5\nmov NEG, r0\nmov table+8, r1\nhalt\n.align 1w\ntable: .word 1, 2\n";
+        let mut program = Vec::new();
+        let (_, symbols) = assemble_with_symbols(
+            &mut source.as_bytes(),
+            &mut program,
+            &AssemblerOptions::default(),
+            Reports::default(),
+        )
+        .unwrap();
+
+        let mut reader = program.as_slice();
+        let negative = Opcode::decode(&mut reader).unwrap();
+        assert_eq!(negative.operands[0], CoreOperand::Immediate(-5));
+        let offset = Opcode::decode(&mut reader).unwrap();
+        assert_eq!(
+            offset.operands[0],
+            CoreOperand::Immediate(symbols["table"] as IWord + 8)
+        );
+    }
+
+    #[test]
+    fn fixup_errors_point_at_the_operand() {
+        let error = assemble_source("halt\nmov missing+8, r0\n").unwrap_err();
+
+        assert_eq!(error.message, "Label missing not found");
+        assert_eq!(
+            error.range.unwrap().start,
+            FilePosition { line: 2, column: 5 }
+        );
+        assert_eq!(
+            error.range.unwrap().end,
+            FilePosition {
+                line: 2,
+                column: 14
+            }
+        );
+    }
+
+    #[test]
+    fn local_label_can_be_referenced_from_another_global_label() {
+        let source = "foo:\n.loop: halt\nbar:\n.loop: jmp foo.loop\n";
+        let mut report = Vec::new();
+        let reports = Reports {
+            fixups: Some(&mut report),
+            ..Default::default()
+        };
+
+        assemble_with_symbols(
+            &mut source.as_bytes(),
+            &mut Vec::new(),
+            &AssemblerOptions::default(),
+            reports,
+        )
+        .unwrap();
+
+        assert_eq!(
+            String::from_utf8(report).unwrap(),
+            "FIXUP | 0000000000000003 foo.loop -> 0000000000000000\n"
+        );
+    }
+
+    #[test]
+    fn listing_report_shows_address_bytes_and_source_of_every_token() {
+        let source = "mov 1, r0\nend: halt\n";
+        let mut report = Vec::new();
+        let reports = Reports {
+            listing: Some(&mut report),
+            ..Default::default()
+        };
+
+        assemble_with_symbols(
+            &mut source.as_bytes(),
+            &mut Vec::new(),
+            &AssemblerOptions::default(),
+            reports,
+        )
+        .unwrap();
+
+        let report = String::from_utf8(report).unwrap();
+        let lines: Vec<&str> = report.lines().map(str::trim_end).collect();
+        assert_eq!(
+            lines,
+            vec![
+                "LISTING | 0000000000000000 | 81 01 01 40                 | 1:1-1:10 |     mov 1, R0",
+                "LISTING | 0000000000000004 |                             | 2:1-2:5 | end:",
+                "LISTING | 0000000000000004 | 3F                          | 2:6-2:10 |     halt",
+            ]
+        );
+    }
+
+    #[test]
+    fn if_chooses_block_based_on_defined_value() {
+        let source = ".if DEBUG\nmov 1, r0\n.else\nmov 2, r0\n.endif\nhalt\n";
+
+        assert_eq!(
+            assemble_source(&format!(".define DEBUG 1\n{}", source)).unwrap(),
+            assemble_source("mov 1, r0\nhalt\n").unwrap()
+        );
+        assert_eq!(
+            assemble_source(&format!(".define DEBUG 0\n{}", source)).unwrap(),
+            assemble_source("mov 2, r0\nhalt\n").unwrap()
+        );
+    }
+
+    #[test]
+    fn unbalanced_if_is_an_error() {
+        let missing_endif = assemble_source(".define X 1\n.if X\nhalt\n").unwrap_err();
+        assert_eq!(missing_endif.message, "Missing .endif for .if");
+
+        let extra_endif = assemble_source("halt\n.endif\n").unwrap_err();
+        assert_eq!(extra_endif.message, ".endif without a matching .if");
+    }
+
+    #[test]
+    fn preloaded_data_must_be_closed_and_only_hold_data() {
+        let missing_end = assemble_source("halt\n.preload a 0x400\n.byte 1\n").unwrap_err();
+        assert_eq!(missing_end.message, "Missing .endpreload for .preload");
+        assert_eq!(missing_end.range.unwrap().start.line, 2);
+
+        let instruction = assemble_source(".preload a 0x400\nhalt\n.endpreload\n").unwrap_err();
+        assert_eq!(
+            instruction.message,
+            "Only data can be preloaded, add .endpreload before this"
+        );
+
+        let overlap =
+            assemble_source("halt\n.preload a 0x400\n.zero 8\n.endpreload\n.preload b 0x404\n.byte 1\n.endpreload\n")
+                .unwrap_err();
+        assert_eq!(overlap.range.unwrap().start.line, 5);
+        assert!(overlap.message.contains("overlaps"), "{}", overlap.message);
+    }
+
+    #[test]
+    fn entry_jump_is_only_placed_when_the_entry_label_is_assembled() {
+        let source = ".if ENTRY\n_start:\n.endif\nhalt\n";
+
+        assert_eq!(
+            assemble_source(&format!(".define ENTRY 0\n{}", source)).unwrap(),
+            assemble_source("halt\n").unwrap()
+        );
+        assert_eq!(
+            assemble_source(&format!(".define ENTRY 1\n{}", source)).unwrap(),
+            assemble_source("jmp main\nmain:\nhalt\n").unwrap()
+        );
+
+        // Exactly one of the branches declares it
+        assemble_source(".define ENTRY 1\n.if ENTRY\n_start:\n.else\n_start:\n.endif\nhalt\n")
+            .unwrap();
+    }
+
+    #[test]
+    fn entry_label_must_be_assembled_once_outside_preloaded_data() {
+        let twice = assemble_source("_start:\nhalt\n_start:\nhalt\n").unwrap_err();
+        assert_eq!(twice.message, "Redefinition of label _start");
+        assert_eq!(twice.range.unwrap().start.line, 3);
+
+        let preloaded =
+            assemble_source("halt\n.preload a 0x400\n_start:\n.byte 1\n.endpreload\n").unwrap_err();
+        assert_eq!(
+            preloaded.message,
+            "_start is where execution starts, so it can't be in preloaded data"
+        );
+        assert_eq!(preloaded.range.unwrap().start.line, 3);
+
+        // The jump moves start to 10, which skips the branch that declares the entry label
+        let depends_on_jump =
+            assemble_source("start:\n.if start\n.else\n_start:\n.endif\nhalt\n").unwrap_err();
+        assert_eq!(
+            depends_on_jump.message,
+            "_start is only assembled when there's no jump to it, make the .if around it not depend on label addresses"
+        );
+        assert_eq!(depends_on_jump.range.unwrap().start.line, 4);
+    }
+
+    #[test]
+    fn org_pads_to_the_offset_and_cannot_move_backwards() {
+        let mut program = Vec::new();
+        let (_, symbols) = assemble_with_symbols(
+            &mut "halt\n.org 0x20\nafter: halt\n".as_bytes(),
+            &mut program,
+            &AssemblerOptions::default(),
+            Reports::default(),
+        )
+        .unwrap();
+
+        assert_eq!(symbols["after"], 0x20);
+        assert_eq!(program.len(), 0x21);
+        assert!(program[1..0x20].iter().all(|&x| x == 0));
+
+        // The mov takes up 4 bytes
+        let backwards = assemble_source("mov 1, r0\n.org 2\nhalt\n").unwrap_err();
+        assert_eq!(
+            backwards.message,
+            "Cannot move backwards to offset 0x2, output is already at offset 0x4"
+        );
+        assert_eq!(backwards.range.unwrap().start.line, 2);
+    }
+
+    #[test]
+    fn leading_data_warns_unless_an_instruction_comes_first() {
+        let warnings = |source: &str| {
+            assemble(
+                &mut source.as_bytes(),
+                &mut Vec::new(),
+                &AssemblerOptions::default(),
+            )
+            .unwrap()
+        };
+
+        let data_first = warnings(".string \"hi\"\nhalt\n");
+        assert_eq!(data_first.len(), 1);
+        assert_eq!(
+            data_first[0].message,
+            "Program starts with data instead of an instruction, add a jmp to the entry point before it"
+        );
+        assert_eq!(data_first[0].range.start.line, 1);
+
+        assert!(warnings("halt\n.string \"hi\"\n").is_empty());
+        assert!(warnings("jmp main\n.string \"hi\"\nmain:\nhalt\n").is_empty());
+    }
+
+    #[test]
+    fn negative_jump_target_warns() {
+        let warnings = assemble(
+            &mut "jmp -1\nhalt\n".as_bytes(),
+            &mut Vec::new(),
+            &AssemblerOptions::default(),
+        )
+        .unwrap();
+
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(
+            warnings[0].message,
+            "Jump target -1 is negative and wraps around to address FFFFFFFFFFFFFFFF"
+        );
+        assert_eq!(warnings[0].range.start.line, 1);
+    }
 }
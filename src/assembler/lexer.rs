@@ -1,8 +1,15 @@
-use super::{Error, FilePosition, FileRange, Result, VoidResult};
+use super::{Error, FilePosition, FileRange, Phase, Result, VoidResult};
 use crate::core::{IWord, RegisterIndex, REGISTER_NUM, WORD_BYTE_SIZE};
 use crate::opcodes::Instruction;
+use std::cell::RefCell;
+use std::char::REPLACEMENT_CHARACTER;
+use std::collections::VecDeque;
 use std::fmt::{Debug, Display, Formatter, Result as FmtResult};
-use std::io::Read;
+use std::io::{self, ErrorKind, Read};
+use std::str;
+
+/// How many bytes a streaming lexer reads from its source at a time
+const STREAM_CHUNK_SIZE: usize = 4096;
 
 #[derive(PartialEq, Eq, Debug, Clone)]
 pub enum TokenValue {
@@ -18,8 +25,11 @@ pub enum TokenValue {
     OffsetPositive,
     OffsetNegative,
     Directive(Directive),
-    StringLiteral(String),
+    /// Raw bytes, as `\\x` escapes can produce bytes that aren't valid UTF-8
+    StringLiteral(Vec<u8>),
     CharacterLiteral(char),
+    /// Only emitted by `lex_with_comments`, contains the full text of the comment
+    Comment(String),
 }
 
 #[derive(PartialEq, Eq, Debug, Copy, Clone)]
@@ -27,6 +37,20 @@ pub enum Directive {
     String,
     Align,
     Define,
+    Section,
+    Org,
+    If,
+    Else,
+    EndIf,
+    Byte,
+    Word,
+    Zero,
+    SizeOf,
+    Asciiz,
+    Rept,
+    EndRept,
+    Preload,
+    EndPreload,
 }
 
 #[derive(PartialEq, Eq, Debug, Clone)]
@@ -35,19 +59,36 @@ pub struct Token {
     pub range: FileRange,
 }
 
-#[derive(Debug)]
-struct TrackingFileReader {
-    buffer: Vec<char>,
-    index: usize,
+struct TrackingFileReader<'a> {
+    /// Characters that were read from the source but not consumed yet
+    pending: RefCell<VecDeque<char>>,
+    /// Where more characters come from once `pending` runs out, if the source is being streamed
+    source: Option<RefCell<CharSource<'a>>>,
     pos: FilePosition,
 }
 
-#[derive(Debug)]
-struct Lexer {
-    reader: TrackingFileReader,
+/// Decodes characters from a reader a chunk at a time, replacing invalid UTF-8 like `String::from_utf8_lossy`
+struct CharSource<'a> {
+    reader: &'a mut dyn Read,
+    /// Bytes at the end of the last chunk that don't form a complete character yet
+    partial: Vec<u8>,
+    error: Option<io::Error>,
+    done: bool,
+}
+
+struct Lexer<'a> {
+    reader: TrackingFileReader<'a>,
     tokens: Vec<Token>,
     token_start: FilePosition,
     inside_ref: bool,
+    keep_comments: bool,
+}
+
+/// Value of an escape sequence in a string or character literal
+enum Escape {
+    Character(char),
+    /// Written to strings as-is, instead of being encoded as UTF-8
+    Byte(u8),
 }
 
 impl Display for TokenValue {
@@ -62,36 +103,67 @@ impl Display for Token {
     }
 }
 
-impl TrackingFileReader {
-    fn from_reader(reader: &mut impl Read) -> Result<TrackingFileReader> {
+impl<'a> TrackingFileReader<'a> {
+    fn from_reader(reader: &mut impl Read) -> Result<TrackingFileReader<'a>> {
         let mut byte_buffer = Vec::new();
-        reader.read_to_end(&mut byte_buffer)?;
+        reader
+            .read_to_end(&mut byte_buffer)
+            .map_err(|e| Error::from_io(Phase::Lexer, e))?;
         let char_buffer = String::from_utf8_lossy(&byte_buffer).chars().collect();
 
         Ok(Self::from_buffer(char_buffer))
     }
 
-    fn from_buffer(buffer: Vec<char>) -> TrackingFileReader {
+    fn from_buffer(buffer: Vec<char>) -> TrackingFileReader<'a> {
+        TrackingFileReader {
+            pending: RefCell::new(buffer.into()),
+            source: None,
+            pos: FilePosition::start(),
+        }
+    }
+
+    /// Reads characters from `reader` only as they're needed
+    fn from_stream(reader: &'a mut dyn Read) -> TrackingFileReader<'a> {
         TrackingFileReader {
-            buffer,
-            index: 0,
+            pending: RefCell::new(VecDeque::new()),
+            source: Some(RefCell::new(CharSource {
+                reader,
+                partial: Vec::new(),
+                error: None,
+                done: false,
+            })),
             pos: FilePosition::start(),
         }
     }
 
+    /// Reads from the source until there are at least `count` pending characters or the source runs out
+    fn fill(&self, count: usize) {
+        if let Some(source) = &self.source {
+            let mut pending = self.pending.borrow_mut();
+            let mut source = source.borrow_mut();
+            while pending.len() < count && source.read_into(&mut pending) {}
+        }
+    }
+
+    /// Error that stopped the source from being read, which makes the reader look like it reached the end of file
+    fn take_error(&mut self) -> Option<io::Error> {
+        self.source.as_mut()?.get_mut().error.take()
+    }
+
     fn is_eof(&self) -> bool {
-        self.index >= self.buffer.len()
+        self.fill(1);
+        self.pending.borrow().is_empty()
     }
 
     fn position(&self) -> FilePosition {
-        self.pos.clone()
+        self.pos
     }
 
     fn peek(&self) -> char {
         self.peek_around(0)
     }
 
-    fn peek_around(&self, offset: isize) -> char {
+    fn peek_around(&self, offset: usize) -> char {
         let result = self.peek_around_raw(offset);
 
         // Normalize line endings to \n
@@ -108,11 +180,9 @@ impl TrackingFileReader {
     }
 
     /// Peek around without end-of-line normalization
-    fn peek_around_raw(&self, offset: isize) -> char {
-        self.buffer
-            .get(((self.index as isize) + offset) as usize)
-            .map(Clone::clone)
-            .unwrap_or('\0')
+    fn peek_around_raw(&self, offset: usize) -> char {
+        self.fill(offset + 1);
+        self.pending.borrow().get(offset).copied().unwrap_or('\0')
     }
 
     /// Consumes the current character. Returns true if there are more characters coming next
@@ -129,11 +199,12 @@ impl TrackingFileReader {
 
         // Treat \r\n as a single logical character
 
-        if self.peek_raw() == '\r' && self.peek_around_raw(1) == '\n' {
-            self.index += 2;
+        let length = if self.peek_raw() == '\r' && self.peek_around_raw(1) == '\n' {
+            2
         } else {
-            self.index += 1;
-        }
+            1
+        };
+        self.pending.get_mut().drain(..length);
 
         !self.is_eof()
     }
@@ -158,19 +229,78 @@ impl TrackingFileReader {
         } else {
             Err(Error {
                 message: "Unexpected end of file".to_owned(),
-                range: FileRange::single(&self.pos),
+                range: Some(FileRange::single(&self.pos)),
+                phase: Phase::Lexer,
             })
         }
     }
 }
 
-impl Lexer {
-    fn new(reader: TrackingFileReader) -> Lexer {
+impl CharSource<'_> {
+    /// Decodes the next chunk of the reader into `chars`. Returns false if there is nothing left to read
+    fn read_into(&mut self, chars: &mut VecDeque<char>) -> bool {
+        if self.done {
+            return false;
+        }
+
+        let mut chunk = [0; STREAM_CHUNK_SIZE];
+        let read = match self.reader.read(&mut chunk) {
+            Ok(0) => {
+                self.done = true;
+                // A character cut off by the end of the file is invalid as a whole
+                if self.partial.is_empty() {
+                    return false;
+                }
+
+                self.partial.clear();
+                chars.push_back(REPLACEMENT_CHARACTER);
+                return true;
+            }
+            Ok(x) => x,
+            Err(e) if e.kind() == ErrorKind::Interrupted => return true,
+            Err(e) => {
+                self.error = Some(e);
+                self.done = true;
+                return false;
+            }
+        };
+
+        self.partial.extend_from_slice(&chunk[..read]);
+        loop {
+            let error = match str::from_utf8(&self.partial) {
+                Ok(text) => {
+                    chars.extend(text.chars());
+                    self.partial.clear();
+                    return true;
+                }
+                Err(e) => e,
+            };
+
+            let valid = error.valid_up_to();
+            chars.extend(str::from_utf8(&self.partial[..valid]).unwrap().chars());
+            match error.error_len() {
+                Some(invalid) => {
+                    chars.push_back(REPLACEMENT_CHARACTER);
+                    self.partial.drain(..valid + invalid);
+                }
+                // The rest of the character is in the next chunk
+                None => {
+                    self.partial.drain(..valid);
+                    return true;
+                }
+            }
+        }
+    }
+}
+
+impl<'a> Lexer<'a> {
+    fn new(reader: TrackingFileReader<'a>, keep_comments: bool) -> Lexer<'a> {
         Lexer {
             reader,
             tokens: Vec::new(),
             token_start: FilePosition::start(),
             inside_ref: false,
+            keep_comments,
         }
     }
 
@@ -191,20 +321,52 @@ impl Lexer {
     fn make_error(&self, msg: &str) -> Error {
         Error {
             message: msg.to_owned(),
-            range: self.range(),
+            range: Some(self.range()),
+            phase: Phase::Lexer,
+        }
+    }
+
+    /// Creates an error for an unknown escape sequence that starts at `escape_start` and whose
+    /// escaped character is the current character
+    fn make_escape_error(&self, escape_start: FilePosition, supported: &[&str]) -> Error {
+        let mut end = self.reader.position();
+        end.next_column();
+
+        Error {
+            message: format!(
+                "Unknown escape sequence \\{}. Supported escape sequences are {}",
+                self.reader.peek(),
+                supported.join(" ")
+            ),
+            range: Some(FileRange {
+                start: escape_start,
+                end,
+            }),
+            phase: Phase::Lexer,
         }
     }
 
     fn lex(mut self) -> Result<Vec<Token>> {
         if !self.tokens.is_empty() {
             return Err(Error::from_message(
+                Phase::Lexer,
                 "Lexer cannot be reused after lex() is called",
             ));
         }
 
         while !self.reader.is_eof() {
             self.token_start = self.reader.position();
-            self.lex_single()?;
+            let result = self.lex_single();
+
+            // A read error looks like the end of the file to the lexer, which is probably what the result is about
+            if let Some(e) = self.reader.take_error() {
+                return Err(Error::from_io(Phase::Lexer, e));
+            }
+            result?;
+        }
+
+        if let Some(e) = self.reader.take_error() {
+            return Err(Error::from_io(Phase::Lexer, e));
         }
 
         Ok(self.tokens)
@@ -215,10 +377,13 @@ impl Lexer {
             self.lex_whitespace();
             return Ok(());
         }
-        if self.reader.peek() == ';' {
+        if self.reader.peek() == ';' || self.reader.peek() == '#' {
             self.lex_comment();
             return Ok(());
         }
+        if self.reader.peek() == '/' && self.reader.peek_around(1) == '*' {
+            return self.lex_block_comment();
+        }
 
         if self.reader.peek() == '[' {
             self.reader.consume();
@@ -274,7 +439,7 @@ impl Lexer {
             return self.lex_character();
         }
 
-        if self.reader.peek().is_digit(10) {
+        if self.reader.peek().is_ascii_digit() {
             return self.lex_number();
         }
 
@@ -290,7 +455,52 @@ impl Lexer {
     }
 
     fn lex_comment(&mut self) {
-        while self.reader.peek() != '\n' && self.reader.consume() {}
+        let mut text = String::new();
+
+        while !self.reader.is_eof() && self.reader.peek() != '\n' {
+            text.push(self.reader.peek());
+            self.reader.consume();
+        }
+
+        if self.keep_comments {
+            self.make_token(TokenValue::Comment(text));
+        }
+    }
+
+    /// Block comments can span multiple lines, but can't be nested
+    fn lex_block_comment(&mut self) -> VoidResult {
+        let mut text = String::new();
+
+        loop {
+            if self.reader.is_eof() {
+                let mut end = self.token_start;
+                end.next_column();
+                end.next_column();
+
+                return Err(Error {
+                    message: "Block comment is never closed, expected */".to_owned(),
+                    range: Some(FileRange {
+                        start: self.token_start,
+                        end,
+                    }),
+                    phase: Phase::Lexer,
+                });
+            }
+
+            let is_end = text.len() > 2 && text.ends_with('*') && self.reader.peek() == '/';
+            text.push(self.reader.peek());
+            self.reader.consume();
+
+            if is_end {
+                break;
+            }
+        }
+
+        if self.keep_comments {
+            self.make_token(TokenValue::Comment(text));
+        }
+
+        Ok(())
     }
 
     fn lex_number(&mut self) -> VoidResult {
@@ -310,6 +520,9 @@ impl Lexer {
         } else if self.reader.peek() == '0' && self.reader.peek_around(1) == 'b' {
             self.reader.consume_many_or_error(2)?;
             2
+        } else if self.reader.peek() == '0' && self.reader.peek_around(1) == 'o' {
+            self.reader.consume_many_or_error(2)?;
+            8
         } else {
             10
         };
@@ -342,7 +555,10 @@ impl Lexer {
         };
 
         let signed_num = if is_positive { raw_num } else { -raw_num };
-        let num = signed_num * multiplier;
+        let num = match signed_num.checked_mul(multiplier) {
+            Some(x) => x,
+            None => return Err(self.make_error("Number is too large to fit in a word")),
+        };
 
         self.make_token(TokenValue::Number(num));
 
@@ -350,40 +566,100 @@ impl Lexer {
     }
 
     fn lex_string(&mut self) -> VoidResult {
-        let mut string = String::new();
+        let mut bytes = Vec::new();
+        let mut utf8_buffer = [0; 4];
 
         while self.reader.peek() != '"' {
-            if self.reader.peek() == '\\' {
-                self.reader.consume_or_error()?;
-
-                match self.reader.peek() {
-                    'n' => string.push('\n'),
-                    '"' => string.push('\"'),
-                    '\\' => string.push('\\'),
-                    x => return Err(self.make_error(&format!("Unknown escape sequence \\{}", x))),
+            let character = if self.reader.peek() == '\\' {
+                match self.lex_escape()? {
+                    Escape::Character(c) => c,
+                    Escape::Byte(b) => {
+                        bytes.push(b);
+                        self.reader.consume_or_error()?;
+                        continue;
+                    }
                 }
             } else {
-                string.push(self.reader.peek());
-            }
+                self.reader.peek()
+            };
 
+            bytes.extend_from_slice(character.encode_utf8(&mut utf8_buffer).as_bytes());
             self.reader.consume_or_error()?;
         }
 
         self.reader.consume();
-        self.make_token(TokenValue::StringLiteral(string));
+        self.make_token(TokenValue::StringLiteral(bytes));
 
         Ok(())
     }
 
-    fn lex_character(&mut self) -> VoidResult {
-        let character = if self.reader.peek() == '\\' {
+    /// Lexes the escape sequence starting at the current backslash, stopping at its last character
+    fn lex_escape(&mut self) -> Result<Escape> {
+        let escape_start = self.reader.position();
+        self.reader.consume_or_error()?;
+
+        let character = match self.reader.peek() {
+            'n' => '\n',
+            't' => '\t',
+            'r' => '\r',
+            '0' => '\0',
+            '\'' => '\'',
+            '"' => '"',
+            '\\' => '\\',
+            'x' => return self.lex_hex_escape(escape_start),
+            _ => {
+                return Err(self.make_escape_error(
+                    escape_start,
+                    &["\\n", "\\t", "\\r", "\\0", "\\'", "\\\"", "\\\\", "\\xNN"],
+                ))
+            }
+        };
+
+        Ok(Escape::Character(character))
+    }
+
+    /// Lexes the two hexadecimal digits after a `\x`, stopping at the last one
+    fn lex_hex_escape(&mut self, escape_start: FilePosition) -> Result<Escape> {
+        let mut value = 0;
+
+        for _ in 0..2 {
             self.reader.consume_or_error()?;
 
-            match self.reader.peek() {
-                '\'' => '\'',
-                'n' => '\n',
-                '\\' => '\\',
-                x => return Err(self.make_error(&format!("Unknown escape sequence \\{}", x))),
+            let digit = match self.reader.peek().to_digit(16) {
+                Some(x) => x as u8,
+                None => {
+                    let mut end = self.reader.position();
+                    // Underline the character that isn't a digit, unless the line or the file ended instead
+                    if !self.reader.is_eof() && self.reader.peek() != '\n' {
+                        end.next_column();
+                    }
+
+                    return Err(Error {
+                        message: "\\x escapes need exactly two hexadecimal digits".to_owned(),
+                        range: Some(FileRange {
+                            start: escape_start,
+                            end,
+                        }),
+                        phase: Phase::Lexer,
+                    });
+                }
+            };
+
+            value = value * 16 + digit;
+        }
+
+        Ok(Escape::Byte(value))
+    }
+
+    fn lex_character(&mut self) -> VoidResult {
+        if self.reader.peek() == '\'' {
+            return Err(self.make_error("Empty character literal"));
+        }
+
+        let character = if self.reader.peek() == '\\' {
+            match self.lex_escape()? {
+                Escape::Character(c) => c,
+                Escape::Byte(b) => b as char,
             }
         } else {
             self.reader.peek()
@@ -391,6 +667,14 @@ impl Lexer {
 
         self.reader.consume_or_error()?;
         if self.reader.peek() != '\'' {
+            let mut offset = 0;
+            while !matches!(self.reader.peek_around(offset), '\'' | '\n' | '\0') {
+                offset += 1;
+            }
+
+            if self.reader.peek_around(offset) == '\'' {
+                return Err(self.make_error("Character literals must contain a single character"));
+            }
             return Err(self.make_error("Unterminated character literal"));
         }
 
@@ -402,16 +686,28 @@ impl Lexer {
 
     fn lex_identifier(&mut self) -> VoidResult {
         let mut name = String::new();
-
-        while Self::is_valid_identifier_middle(self.reader.peek()) {
-            name.push(self.reader.peek());
-            if !self.reader.consume() {
-                break;
-            }
+        self.lex_identifier_middle(&mut name);
+
+        // A dot followed by a name refers to a local label from outside its global label, like `foo.loop`
+        while !name.ends_with(':')
+            && self.reader.peek() == '.'
+            && Self::is_valid_identifier_start(self.reader.peek_around(1))
+        {
+            name.push('.');
+            self.reader.consume();
+            self.lex_identifier_middle(&mut name);
         }
 
         if name.ends_with(':') {
             let label_name = name.trim_end_matches(':').to_owned();
+            if let Some(dot) = label_name.rfind('.') {
+                return Err(self.make_error(&format!(
+                    "Qualified labels can't be defined, define {} after {} instead",
+                    &label_name[dot..],
+                    &label_name[..dot]
+                )));
+            }
+
             self.make_token(TokenValue::LabelDefinition(label_name));
             return Ok(());
         }
@@ -429,39 +725,64 @@ impl Lexer {
         Ok(())
     }
 
+    /// Lexes a directive, or a local label if the name after the dot isn't a directive
     fn lex_directive(&mut self) -> VoidResult {
         let mut name = String::new();
+        self.lex_identifier_middle(&mut name);
 
-        while self.reader.peek().is_ascii_alphabetic() {
-            name.push(self.reader.peek());
-            if !self.reader.consume() {
-                break;
-            }
+        if name.is_empty() {
+            return Err(self.make_error("Expected a directive or local label name after the dot"));
+        }
+
+        if name.ends_with(':') {
+            let label_name = format!(".{}", name.trim_end_matches(':'));
+            self.make_token(TokenValue::LabelDefinition(label_name));
+            return Ok(());
         }
 
         self.make_token(TokenValue::Directive(match name.as_str() {
             "string" => Directive::String,
             "align" => Directive::Align,
             "define" => Directive::Define,
-            x => return Err(self.make_error(&format!("Unknown directive '{}'", x))),
+            "section" => Directive::Section,
+            "org" => Directive::Org,
+            "if" => Directive::If,
+            "else" => Directive::Else,
+            "endif" => Directive::EndIf,
+            "byte" => Directive::Byte,
+            "word" => Directive::Word,
+            "zero" => Directive::Zero,
+            "sizeof" => Directive::SizeOf,
+            "asciiz" => Directive::Asciiz,
+            "rept" => Directive::Rept,
+            "endr" => Directive::EndRept,
+            "preload" => Directive::Preload,
+            "endpreload" => Directive::EndPreload,
+            _ => {
+                self.make_token(TokenValue::LabelReference(format!(".{}", name)));
+                return Ok(());
+            }
         }));
 
         Ok(())
     }
 
-    fn lex_register(&mut self, identifier: &str) -> Result<bool> {
-        if !identifier.starts_with('R') && !identifier.starts_with('r') {
-            return Ok(false);
-        }
-
-        let reg_num_str = identifier.trim_start_matches('R').trim_start_matches('r');
-        if !reg_num_str.chars().count() == 1 {
-            return Ok(false);
+    /// Adds characters to `name` for as long as they can be part of an identifier
+    fn lex_identifier_middle(&mut self, name: &mut String) {
+        while Self::is_valid_identifier_middle(self.reader.peek()) {
+            name.push(self.reader.peek());
+            if !self.reader.consume() {
+                break;
+            }
         }
+    }
 
-        let reg_num: RegisterIndex = match reg_num_str.parse() {
-            Err(_) => return Ok(false),
-            Ok(x) => x,
+    fn lex_register(&mut self, identifier: &str) -> Result<bool> {
+        // Registers are an R followed by a single digit, anything else is a label
+        let mut chars = identifier.chars();
+        let reg_num = match (chars.next(), chars.next(), chars.next()) {
+            (Some('R' | 'r'), Some(digit @ '0'..='9'), None) => digit as RegisterIndex - b'0',
+            _ => return Ok(false),
         };
 
         if reg_num >= REGISTER_NUM as u8 {
@@ -496,5 +817,297 @@ impl Lexer {
 
 pub fn lex(read: &mut impl Read) -> Result<Vec<Token>> {
     let reader = TrackingFileReader::from_reader(read)?;
-    Lexer::new(reader).lex()
+    Lexer::new(reader, false).lex()
+}
+
+/// Same as `lex`, but reads the source as the tokens are produced instead of all at once up front,
+/// so the source never has to be fully in memory as text
+pub fn lex_streaming(read: &mut impl Read) -> Result<Vec<Token>> {
+    let reader = TrackingFileReader::from_stream(read);
+    Lexer::new(reader, false).lex()
+}
+
+/// Like `lex`, but also emits a `Comment` token for every comment in the source.
+/// Meant for tooling that needs to preserve comments, the parser ignores them.
+pub fn lex_with_comments(read: &mut impl Read) -> Result<Vec<Token>> {
+    let reader = TrackingFileReader::from_reader(read)?;
+    Lexer::new(reader, true).lex()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(start: (u64, u64), end: (u64, u64)) -> FileRange {
+        FileRange {
+            start: FilePosition {
+                line: start.0,
+                column: start.1,
+            },
+            end: FilePosition {
+                line: end.0,
+                column: end.1,
+            },
+        }
+    }
+
+    fn comments(tokens: &[Token]) -> Vec<(String, FileRange)> {
+        tokens
+            .iter()
+            .filter_map(|t| match &t.value {
+                TokenValue::Comment(text) => Some((text.clone(), t.range)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn lex_with_comments_keeps_comments_at_their_positions() {
+        let source = "mov r0, r1 ; copy\n; whole line\n  halt ; stop";
+        let tokens = lex_with_comments(&mut source.as_bytes()).unwrap();
+
+        assert_eq!(
+            comments(&tokens),
+            vec![
+                ("; copy".to_owned(), range((1, 12), (1, 18))),
+                ("; whole line".to_owned(), range((2, 1), (2, 13))),
+                ("; stop".to_owned(), range((3, 8), (3, 14))),
+            ]
+        );
+
+        let without_comments: Vec<Token> = tokens
+            .into_iter()
+            .filter(|t| !matches!(t.value, TokenValue::Comment(_)))
+            .collect();
+        assert_eq!(without_comments, lex(&mut source.as_bytes()).unwrap());
+    }
+
+    #[test]
+    fn unknown_escape_lists_supported_escapes_at_its_position() {
+        let error = lex(&mut "mov \"ab\\q\", r0".as_bytes()).unwrap_err();
+
+        assert_eq!(
+            error.message,
+            "Unknown escape sequence \\q. Supported escape sequences are \\n \\t \\r \\0 \\' \\\" \\\\ \\xNN"
+        );
+        assert_eq!(error.range, Some(range((1, 8), (1, 10))));
+    }
+
+    #[test]
+    fn word_multiple_that_overflows_is_an_error() {
+        let error = lex(&mut "mov 0x1000000000000000w, r0".as_bytes()).unwrap_err();
+
+        assert_eq!(error.message, "Number is too large to fit in a word");
+        assert_eq!(error.range, Some(range((1, 5), (1, 24))));
+    }
+
+    #[test]
+    fn hex_escape_is_a_single_raw_byte() {
+        let tokens = lex(&mut ".string \"a\\xFF\\x1bé\"".as_bytes()).unwrap();
+
+        assert_eq!(
+            tokens[1].value,
+            TokenValue::StringLiteral(vec![b'a', 0xFF, 0x1B, 0xC3, 0xA9])
+        );
+
+        // The bytes are written as they are, instead of being encoded as UTF-8 again
+        let mut program = Vec::new();
+        crate::assembler::assemble(
+            &mut "halt\n.string \"\\xFF\"".as_bytes(),
+            &mut program,
+            &Default::default(),
+        )
+        .unwrap();
+        assert_eq!(program, vec![0x3F, 0xFF]);
+    }
+
+    #[test]
+    fn malformed_hex_escape_is_reported_at_its_position() {
+        let error = lex(&mut ".string \"\\xG1\"".as_bytes()).unwrap_err();
+
+        assert_eq!(
+            error.message,
+            "\\x escapes need exactly two hexadecimal digits"
+        );
+        assert_eq!(error.range, Some(range((1, 10), (1, 13))));
+
+        // Nothing past the end of the line is underlined
+        let at_end = lex(&mut ".string \"\\x\n".as_bytes()).unwrap_err();
+        assert_eq!(at_end.range, Some(range((1, 10), (1, 12))));
+    }
+
+    #[test]
+    fn registers_are_r_and_a_digit_below_the_register_count() {
+        let tokens = lex(&mut "R0 r3 Rx R10".as_bytes()).unwrap();
+        let values: Vec<TokenValue> = tokens.into_iter().map(|t| t.value).collect();
+
+        assert_eq!(
+            values,
+            vec![
+                TokenValue::Register(0),
+                TokenValue::Register(3),
+                TokenValue::LabelReference("Rx".to_owned()),
+                TokenValue::LabelReference("R10".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn register_past_the_last_one_is_an_error() {
+        let error = lex(&mut "push R4".as_bytes()).unwrap_err();
+
+        assert_eq!(
+            error.message,
+            "Invalid register index. Must be a number from 0 to 3 inclusive."
+        );
+        assert_eq!(error.range, Some(range((1, 6), (1, 8))));
+    }
+
+    #[test]
+    fn qualified_label_is_a_single_reference_but_cannot_be_defined() {
+        let tokens = lex(&mut "jmp foo.loop".as_bytes()).unwrap();
+        assert_eq!(
+            tokens[1].value,
+            TokenValue::LabelReference("foo.loop".to_owned())
+        );
+
+        let error = lex(&mut "foo.loop: halt".as_bytes()).unwrap_err();
+        assert_eq!(
+            error.message,
+            "Qualified labels can't be defined, define .loop after foo instead"
+        );
+    }
+
+    #[test]
+    fn semicolon_and_hash_comments_can_be_mixed() {
+        let source = "; header\nmov r0, r1 # copy\n# whole line\nhalt ; stop";
+        let tokens = lex_with_comments(&mut source.as_bytes()).unwrap();
+
+        assert_eq!(
+            comments(&tokens),
+            vec![
+                ("; header".to_owned(), range((1, 1), (1, 9))),
+                ("# copy".to_owned(), range((2, 12), (2, 18))),
+                ("# whole line".to_owned(), range((3, 1), (3, 13))),
+                ("; stop".to_owned(), range((4, 6), (4, 12))),
+            ]
+        );
+        assert_eq!(
+            lex(&mut source.as_bytes()).unwrap(),
+            lex(&mut "\nmov r0, r1\n\nhalt".as_bytes()).unwrap()
+        );
+    }
+
+    #[test]
+    fn block_comments_can_span_lines_and_sit_between_tokens() {
+        let source = "/* header\n   spans */ mov r0, r1 /**/\nhalt";
+        let tokens = lex_with_comments(&mut source.as_bytes()).unwrap();
+
+        assert_eq!(
+            comments(&tokens),
+            vec![
+                ("/* header\n   spans */".to_owned(), range((1, 1), (2, 12))),
+                ("/**/".to_owned(), range((2, 24), (2, 28))),
+            ]
+        );
+        assert_eq!(tokens[1].range, range((2, 13), (2, 16)));
+        let values = |tokens: Vec<Token>| tokens.into_iter().map(|t| t.value).collect::<Vec<_>>();
+        assert_eq!(
+            values(lex(&mut source.as_bytes()).unwrap()),
+            values(lex(&mut "mov r0, r1\nhalt".as_bytes()).unwrap())
+        );
+    }
+
+    #[test]
+    fn unterminated_block_comment_is_reported_at_its_start() {
+        let error = lex(&mut "halt\n  /* never closed *\nhalt".as_bytes()).unwrap_err();
+
+        assert_eq!(error.message, "Block comment is never closed, expected */");
+        assert_eq!(error.range, Some(range((2, 3), (2, 5))));
+    }
+
+    #[test]
+    fn read_errors_have_no_location_or_excerpt() {
+        struct FailingReader;
+
+        impl Read for FailingReader {
+            fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::other("disk on fire"))
+            }
+        }
+
+        let error = lex(&mut FailingReader).unwrap_err();
+
+        assert_eq!(error.range, None);
+        assert_eq!(error.source_excerpt("halt"), "");
+        assert_eq!(error.to_string(), "Lexer error: disk on fire\n");
+    }
+
+    #[test]
+    fn streaming_produces_the_same_tokens_as_buffering() {
+        /// Hands out a few bytes per read, so characters, `\r\n` and `0x` prefixes get split between reads
+        struct TrickleReader<'a> {
+            data: &'a [u8],
+            reads: usize,
+        }
+
+        impl Read for TrickleReader<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                self.reads += 1;
+                let len = buf.len().min(self.data.len()).min(1 + self.reads % 7);
+                buf[..len].copy_from_slice(&self.data[..len]);
+                self.data = &self.data[len..];
+                Ok(len)
+            }
+        }
+
+        const REPEATS: usize = 2000;
+
+        let mut source = String::new();
+        for i in 0..REPEATS {
+            let line_end = if i % 3 == 0 { "\r\n" } else { "\n" };
+            source.push_str(&format!(
+                "label_{}: mov 0x{:X}, r{} ; café ☕{}",
+                i,
+                i,
+                i % 4,
+                line_end
+            ));
+            source.push_str(&format!(".string \"ünïcødé {} \\x41\" # 🎉{}", i, line_end));
+            source.push_str(&format!(
+                "/* block{}comment {} */ add -{}w, [sp+1w]{}",
+                line_end, i, i, line_end
+            ));
+        }
+        assert!(source.len() > 16 * STREAM_CHUNK_SIZE);
+
+        let buffered = lex_with_comments(&mut source.as_bytes()).unwrap();
+        let chunked = {
+            let mut bytes = source.as_bytes();
+            let reader = TrackingFileReader::from_stream(&mut bytes);
+            Lexer::new(reader, true).lex().unwrap()
+        };
+        let trickled = {
+            let mut trickle = TrickleReader {
+                data: source.as_bytes(),
+                reads: 0,
+            };
+            let reader = TrackingFileReader::from_stream(&mut trickle);
+            Lexer::new(reader, true).lex().unwrap()
+        };
+
+        assert_eq!(buffered.len(), REPEATS * 18);
+        assert!(
+            chunked == buffered,
+            "streaming in chunks changed the tokens"
+        );
+        assert!(
+            trickled == buffered,
+            "streaming a few bytes at a time changed the tokens"
+        );
+        assert_eq!(
+            lex_streaming(&mut source.as_bytes()).unwrap(),
+            lex(&mut source.as_bytes()).unwrap()
+        );
+    }
 }
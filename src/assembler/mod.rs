@@ -1,17 +1,57 @@
 use crate::core::Error as CoreError;
+use crate::executable::Executable;
+use std::collections::HashMap;
 use std::fmt::{Display, Formatter, Result as FmtResult};
-use std::io::{Error as IoError, Read, Seek, Write};
+use std::io::{Cursor, Error as IoError, Read, Write};
 
 mod encoder;
-mod lexer;
-mod parser;
+pub mod lexer;
+pub mod parser;
 
 #[derive(Debug)]
 pub struct Error {
+    pub message: String,
+    /// Where in the source code the error happened, or `None` for errors that aren't tied to a location, such as I/O errors
+    pub range: Option<FileRange>,
+    pub phase: Phase,
+}
+
+/// The step of the assembly process where an error happened
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Phase {
+    /// Reading the source code and splitting it into tokens
+    Lexer,
+    /// Grouping tokens into directives and instructions
+    Parser,
+    /// Generating the executable and resolving labels
+    Encoder,
+}
+
+/// A suspicious construct in the source code that doesn't stop the program from being assembled
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Warning {
     pub message: String,
     pub range: FileRange,
 }
 
+/// Settings that change how a program is assembled
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub struct AssemblerOptions {
+    /// Fail to assemble the program if any warning is found
+    pub warnings_as_errors: bool,
+}
+
+/// Optional reports about how a program was assembled, each written to its own destination.
+/// Reports that are `None` aren't generated
+#[derive(Default)]
+pub struct Reports<'a> {
+    /// Receives every label reference along with the address it resolved to, as `FIXUP | offset label -> address` lines
+    pub fixups: Option<&'a mut dyn Write>,
+    /// Receives the address and bytes of every directive and instruction, along with its location in the source code,
+    /// as `LISTING | address | bytes | range | source` lines
+    pub listing: Option<&'a mut dyn Write>,
+}
+
 #[derive(PartialEq, Eq, Debug, Clone, Copy)]
 pub struct FilePosition {
     pub line: u64,
@@ -24,44 +64,93 @@ pub struct FileRange {
     pub end: FilePosition,
 }
 
+/// Final value of every label and constant in a program, by name
+pub type Symbols = HashMap<String, u64>;
+
 type Result<T> = std::result::Result<T, Error>;
 type VoidResult = Result<()>;
 
 impl Error {
-    fn from_message(msg: &str) -> Error {
+    fn from_message(phase: Phase, msg: &str) -> Error {
         Error {
             message: msg.to_owned(),
-            range: FileRange::invalid(),
+            range: None,
+            phase,
         }
     }
+
+    fn from_io(phase: Phase, e: IoError) -> Error {
+        Error::from_message(phase, &e.to_string())
+    }
+
+    /// Shows the line of `source` where the error happened, with carets under the range of the error.
+    /// Ranges spanning multiple lines are underlined until the end of their first line.
+    /// `source` must be the same source code that was assembled, otherwise the result is meaningless.
+    /// Errors that aren't tied to a location in the source code, such as I/O errors, have no excerpt
+    pub fn source_excerpt(&self, source: &str) -> String {
+        let range = match self.range {
+            Some(x) => x,
+            None => return String::new(),
+        };
+
+        let line = match source.lines().nth(range.start.line as usize - 1) {
+            Some(x) => x,
+            None => return String::new(),
+        };
+
+        let start = range.start.column as usize - 1;
+        let end = if range.end.line == range.start.line {
+            range.end.column as usize - 1
+        } else {
+            line.chars().count()
+        };
+
+        // Keep tabs so the carets line up with the line above no matter how wide tabs are displayed
+        let padding: String = line
+            .chars()
+            .take(start)
+            .map(|x| if x == '\t' { '\t' } else { ' ' })
+            .collect();
+        let carets = "^".repeat(end.saturating_sub(start).max(1));
+
+        let line_number = range.start.line.to_string();
+        let gutter = " ".repeat(line_number.len());
+        format!(
+            "{} |\n{} | {}\n{} | {}{}\n",
+            gutter, line_number, line, gutter, padding, carets
+        )
+    }
 }
 
 impl std::error::Error for Error {}
 
 impl Display for Error {
     fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
-        writeln!(f, "{} {}", self.range, self.message)
+        match self.range {
+            Some(range) => writeln!(f, "{} {} error: {}", range, self.phase, self.message),
+            None => writeln!(f, "{} error: {}", self.phase, self.message),
+        }
     }
 }
 
 impl From<Error> for CoreError {
     fn from(e: Error) -> Self {
-        let message = format!("{} {}", e.range, e.message);
-        CoreError::new(&message)
+        CoreError::new(e.to_string().trim_end())
     }
 }
 
-impl From<IoError> for Error {
-    fn from(e: IoError) -> Self {
-        Error::from_message(&e.to_string())
+impl Display for Warning {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "{} warning: {}", self.range, self.message)
     }
 }
 
-impl From<CoreError> for Error {
-    fn from(e: CoreError) -> Self {
-        Error {
-            message: e.to_string(),
-            range: FileRange::invalid(),
+impl Display for Phase {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Phase::Lexer => write!(f, "Lexer"),
+            Phase::Parser => write!(f, "Parser"),
+            Phase::Encoder => write!(f, "Encoder"),
         }
     }
 }
@@ -82,17 +171,10 @@ impl FilePosition {
 }
 
 impl FileRange {
-    fn invalid() -> FileRange {
-        FileRange {
-            start: FilePosition::start(),
-            end: FilePosition::start(),
-        }
-    }
-
     fn single(value: &FilePosition) -> FileRange {
         FileRange {
-            start: value.clone(),
-            end: value.clone(),
+            start: *value,
+            end: *value,
         }
     }
 }
@@ -109,9 +191,338 @@ impl Display for FileRange {
     }
 }
 
-pub fn assemble(source: &mut impl Read, result: &mut (impl Write + Seek)) -> VoidResult {
+/// Lexes and parses a program without encoding it, for tools that need to analyze its structure
+pub fn parse_program(source: &mut impl Read) -> Result<Vec<parser::Token>> {
     let lex_tokens = lexer::lex(source)?;
-    let parse_tokens = parser::parse(&lex_tokens)?;
-    encoder::encode(&parse_tokens, result)?;
-    Ok(())
+    parser::parse(&lex_tokens)
+}
+
+/// Assembles a program into `result`, returning the warnings found in the source code
+pub fn assemble(
+    source: &mut impl Read,
+    result: &mut impl Write,
+    options: &AssemblerOptions,
+) -> Result<Vec<Warning>> {
+    let (warnings, _) = assemble_with_symbols(source, result, options, Reports::default())?;
+    Ok(warnings)
+}
+
+/// Same as [`assemble`], but also returns the final value of every label and constant,
+/// so tools can find out where each label was placed in the program, and writes the requested `reports`
+pub fn assemble_with_symbols(
+    source: &mut impl Read,
+    result: &mut impl Write,
+    options: &AssemblerOptions,
+    reports: Reports<'_>,
+) -> Result<(Vec<Warning>, Symbols)> {
+    let parse_tokens = parse_program(source)?;
+
+    let mut program = Cursor::new(Vec::new());
+    let encoded = encoder::encode_with_symbols(&parse_tokens, &mut program, reports)?;
+
+    if options.warnings_as_errors {
+        if let Some(warning) = encoded.warnings.first() {
+            return Err(Error {
+                message: format!("{} (warnings are treated as errors)", warning.message),
+                range: Some(warning.range),
+                phase: Phase::Encoder,
+            });
+        }
+    }
+
+    let executable = Executable {
+        sections: encoded.sections,
+        preloads: encoded.preloads,
+        program: program.into_inner(),
+    };
+
+    executable
+        .write(result)
+        .map_err(|e| Error::from_message(Phase::Encoder, &e.to_string()))?;
+
+    Ok((encoded.warnings, encoded.symbols))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::opcodes::{Instruction, OperandMode};
+    use rand::rngs::StdRng;
+    use rand::seq::SliceRandom;
+    use rand::{Rng, SeedableRng};
+    use std::panic;
+
+    /// Seed of the generator, so a failure can always be reproduced
+    const SEED: u64 = 0x1A4E_5151;
+    /// How many programs are generated and assembled
+    const PROGRAMS: usize = 2000;
+    /// Maximum number of lines in a generated program
+    const MAX_LINES: usize = 24;
+
+    /// Labels come from a small pool, so references to them, redefinitions and missing labels all show up
+    const LABELS: &[&str] = &["a", "b", "loop", "_start", ".inner"];
+
+    /// Operands that can be written to, which can also be used anywhere else
+    const WRITABLE_OPERANDS: &[&str] = &[
+        "r0", "r3", "[r1]", "[r2+16]", "[r3-1w]", "[sp]", "[sp+1w]", "[r1+r2]",
+    ];
+
+    /// Operands that can only be read from
+    const READABLE_OPERANDS: &[&str] = &[
+        "0",
+        "-1",
+        "0x7FFFFFFFFFFFFFFF",
+        "'a'",
+        "a",
+        "b+8",
+        "loop-1w",
+        "a.inner",
+        "SIZE",
+    ];
+
+    /// Operands that are never valid
+    const INVALID_OPERANDS: &[&str] = &["r99", "[a]", "[sp+r1]", "-"];
+
+    /// Directives that write data, which can also be used inside `.preload` blocks
+    const DATA_DIRECTIVES: &[&str] = &[
+        ".byte 1, 255, 'x'",
+        ".align 1w\n.word 1, -2",
+        ".align 1w\n.word count 1, 2",
+        ".string \"hi\\n\"",
+        ".string len \"\\xFF\"",
+        ".asciiz \"z\"",
+        ".align 8, 0xFF",
+        ".zero 4",
+        ".sizeof size a",
+    ];
+
+    /// Directives that are invalid or only valid in the right place, such as block ends without a block
+    const MISPLACED_DIRECTIVES: &[&str] = &[
+        ".byte 256",
+        ".zero 0x7FFFFFFFFFFFFFFF",
+        ".rept 0x7FFFFFFFFFFFFFFF",
+        ".org 0x400",
+        ".org 0x7FFFFFFFFFFFFFFF",
+        ".define SIZE a",
+        ".if SIZE",
+        ".else",
+        ".endif",
+        ".endr",
+        ".endpreload",
+    ];
+
+    /// Fragments that are rarely valid where they end up, to reach the error paths
+    const JUNK: &[&str] = &[
+        ",", "[", "]", "+", ":", "\"", "'", "/*", "*/", "\\x", ";", "1w",
+    ];
+
+    fn generate_instruction(rng: &mut StdRng, instructions: &[Instruction]) -> String {
+        let descriptor = instructions.choose(rng).unwrap().descriptor();
+        let mut count = rng.gen_range(descriptor.min_operands..=descriptor.operands.len());
+        // Sometimes one operand too many, to also produce operand count errors
+        if rng.gen_ratio(1, 100) {
+            count += 1;
+        }
+
+        let operands: Vec<&str> = (0..count)
+            .map(|i| {
+                let pool = if rng.gen_ratio(1, 200) {
+                    INVALID_OPERANDS
+                } else if descriptor.operands.get(i) == Some(&OperandMode::ReadWrite)
+                    || rng.gen_bool(0.5)
+                {
+                    WRITABLE_OPERANDS
+                } else {
+                    READABLE_OPERANDS
+                };
+                *pool.choose(rng).unwrap()
+            })
+            .collect();
+        format!("{} {}", descriptor.mnemonic, operands.join(", "))
+    }
+
+    /// Generates up to `max_lines` lines of code, which can contain blocks nested up to `depth` levels deep
+    fn generate_code(
+        rng: &mut StdRng,
+        instructions: &[Instruction],
+        max_lines: usize,
+        depth: u32,
+    ) -> Vec<String> {
+        let mut lines = Vec::new();
+
+        for _ in 0..rng.gen_range(0..=max_lines) {
+            match rng.gen_range(0..10) {
+                0..=4 => lines.push(generate_instruction(rng, instructions)),
+                5..=6 => lines.push(format!("{}:", LABELS.choose(rng).unwrap())),
+                7 => lines.push(DATA_DIRECTIVES.choose(rng).unwrap().to_string()),
+                8 => lines.push(
+                    [".section code", ".section data"]
+                        .choose(rng)
+                        .unwrap()
+                        .to_string(),
+                ),
+                _ if depth == 0 => {}
+                _ => match rng.gen_range(0..3) {
+                    0 => {
+                        lines.push(format!(".rept {}", rng.gen_range(0..4)));
+                        lines.extend(generate_code(rng, instructions, 4, depth - 1));
+                        lines.push(".endr".to_owned());
+                    }
+                    1 => {
+                        lines.push(".if SIZE".to_owned());
+                        lines.extend(generate_code(rng, instructions, 4, depth - 1));
+                        lines.push(".else".to_owned());
+                        lines.extend(generate_code(rng, instructions, 4, depth - 1));
+                        lines.push(".endif".to_owned());
+                    }
+                    _ => {
+                        lines.push(".preload table 0x100000".to_owned());
+                        for _ in 0..rng.gen_range(0..4) {
+                            lines.push(DATA_DIRECTIVES.choose(rng).unwrap().to_string());
+                        }
+                        lines.push(".endpreload".to_owned());
+                    }
+                },
+            }
+        }
+
+        lines
+    }
+
+    /// Generates a program that is mostly made of valid instructions, labels and directives,
+    /// with the occasional mistake to also reach the error paths
+    fn generate_program(rng: &mut StdRng, instructions: &[Instruction]) -> String {
+        let mut lines = generate_code(rng, instructions, MAX_LINES, 2);
+        if rng.gen_bool(0.5) {
+            lines.insert(0, ".define SIZE 16".to_owned());
+        }
+
+        for line in &mut lines {
+            if rng.gen_ratio(1, 100) {
+                *line = MISPLACED_DIRECTIVES.choose(rng).unwrap().to_string();
+            }
+
+            if rng.gen_ratio(1, 100) {
+                let position = rng.gen_range(0..=line.len());
+                line.insert_str(position, JUNK.choose(rng).unwrap());
+            }
+        }
+
+        lines.join("\n") + "\n"
+    }
+
+    fn is_valid_range(range: FileRange, source: &str) -> bool {
+        let line_count = source.lines().count() as u64;
+        let line_length = |line: u64| match source.lines().nth(line as usize - 1) {
+            Some(text) => text.chars().count() as u64,
+            None => 0,
+        };
+
+        range.start.line >= 1
+            && range.start.column >= 1
+            && (range.start.line, range.start.column) <= (range.end.line, range.end.column)
+            // Errors at the end of the file point right after its last character
+            && range.end.line <= line_count + 1
+            && range.start.column <= line_length(range.start.line) + 1
+            && range.end.column <= line_length(range.end.line) + 1
+    }
+
+    /// Assembles `source`, describing what went wrong if it panicked, produced an executable that can't be read,
+    /// or failed with an error that doesn't point into the source code
+    fn check(source: &str) -> std::result::Result<(), String> {
+        let result = panic::catch_unwind(|| {
+            let mut program = Vec::new();
+            assemble(
+                &mut source.as_bytes(),
+                &mut program,
+                &AssemblerOptions::default(),
+            )
+            .map(|_| program)
+        });
+
+        match result {
+            Err(_) => Err("Assembling panicked".to_owned()),
+            Ok(Ok(program)) => Executable::read(&mut program.as_slice())
+                .map(|_| ())
+                .map_err(|e| format!("Assembled into an executable that can't be read: {}", e)),
+            Ok(Err(error)) => match error.range {
+                Some(range) if is_valid_range(range, source) => Ok(()),
+                range => Err(format!(
+                    "Error \"{}\" has an invalid range {:?}",
+                    error.message, range
+                )),
+            },
+        }
+    }
+
+    /// Removes lines from a failing `source` for as long as it keeps failing, to find a minimal reproducer
+    fn shrink(source: &str) -> String {
+        let mut lines: Vec<&str> = source.lines().collect();
+
+        let mut removed_any = true;
+        while removed_any {
+            removed_any = false;
+            let mut i = 0;
+            while i < lines.len() {
+                let mut candidate = lines.clone();
+                candidate.remove(i);
+                if check(&(candidate.join("\n") + "\n")).is_err() {
+                    lines = candidate;
+                    removed_any = true;
+                } else {
+                    i += 1;
+                }
+            }
+        }
+
+        lines.join("\n") + "\n"
+    }
+
+    #[test]
+    fn random_programs_assemble_or_fail_with_a_located_error() {
+        let instructions: Vec<Instruction> = (0..=Instruction::MASK)
+            .filter_map(Instruction::from_value)
+            .collect();
+        let mut rng = StdRng::seed_from_u64(SEED);
+
+        for _ in 0..PROGRAMS {
+            let source = generate_program(&mut rng, &instructions);
+            if check(&source).is_err() {
+                let minimal = shrink(&source);
+                panic!(
+                    "{}\nMinimal reproducer:\n{}\nFull program:\n{}",
+                    check(&minimal).unwrap_err(),
+                    minimal,
+                    source
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn warnings_as_errors_fails_on_the_first_warning() {
+        let source = "unused:\nhalt\n";
+
+        let mut program = Vec::new();
+        let warnings = assemble(
+            &mut source.as_bytes(),
+            &mut program,
+            &AssemblerOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(warnings.len(), 1);
+        assert!(!program.is_empty());
+
+        let options = AssemblerOptions {
+            warnings_as_errors: true,
+        };
+        let mut program = Vec::new();
+        let error = assemble(&mut source.as_bytes(), &mut program, &options).unwrap_err();
+        assert_eq!(
+            error.message,
+            format!("{} (warnings are treated as errors)", warnings[0].message)
+        );
+        assert_eq!(error.range, Some(warnings[0].range));
+        assert!(program.is_empty());
+    }
 }
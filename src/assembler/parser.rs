@@ -1,28 +1,60 @@
 use super::lexer::{
     Directive as LexerDirective, Token as LexerToken, TokenValue as LexerTokenValue,
 };
-use super::{Error, FilePosition, FileRange, Result, VoidResult};
+use super::{Error, FilePosition, FileRange, Phase, Result, VoidResult};
 use crate::core::{IWord, RegisterIndex, UWord};
+use crate::executable::SectionKind;
 use crate::opcodes::{Instruction, OperandMode};
+use std::cmp::Ordering;
 use std::fmt::{Display, Formatter, Result as FmtResult};
 
-#[derive(PartialEq, Eq, Clone)]
+/// Most tokens a single `.rept` block can produce, so a typo in the count can't exhaust memory
+const MAX_REPEATED_TOKENS: usize = 1 << 20;
+
+#[derive(PartialEq, Eq, Debug, Clone)]
 pub struct Token {
     pub value: TokenValue,
     pub range: FileRange,
 }
 
-#[derive(PartialEq, Eq, Clone)]
+#[derive(PartialEq, Eq, Debug, Clone)]
 pub enum TokenValue {
     Label(String),
     String {
         length_label: Option<String>,
-        value: String,
+        value: Vec<u8>,
+        /// Set by `.asciiz`, which appends a 0 byte after the string
+        null_terminated: bool,
+    },
+    Align {
+        alignment: UWord,
+        /// Byte written as padding, 0 unless specified
+        fill: u8,
     },
-    Align(UWord),
     Define {
         label: String,
-        value: IWord,
+        value: DefineValue,
+    },
+    Section(SectionKind),
+    Org(UWord),
+    If(String),
+    Else,
+    EndIf,
+    Bytes(Vec<u8>),
+    Words {
+        length_label: Option<String>,
+        values: Vec<IWord>,
+    },
+    Zero(UWord),
+    /// Start of a block of data that is loaded at `address` instead of being part of the program
+    Preload {
+        name: String,
+        address: UWord,
+    },
+    EndPreload,
+    SizeOf {
+        label: String,
+        start: String,
     },
     Opcode {
         instruction: Instruction,
@@ -30,9 +62,15 @@ pub enum TokenValue {
     },
 }
 
-#[derive(PartialEq, Eq, Clone)]
+#[derive(PartialEq, Eq, Debug, Clone)]
 pub enum Operand {
-    Label(String),
+    /// Address of a label, plus a constant offset
+    Label {
+        name: String,
+        offset: IWord,
+        /// Where the label and its offset are in the source code, as they can only be checked once all labels are known
+        range: FileRange,
+    },
     Immediate(IWord),
     Register(RegisterIndex),
     Stack(UWord),
@@ -40,6 +78,19 @@ pub enum Operand {
         register: RegisterIndex,
         offset: IWord,
     },
+    /// A register reference offset by the value of another register, like `[R0+R1]`
+    Indexed {
+        base: RegisterIndex,
+        index: RegisterIndex,
+    },
+}
+
+/// Right-hand side of a `.define`
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum DefineValue {
+    Number(IWord),
+    /// Value of another label or define, which must be known by the time the `.define` is encoded
+    Symbol(String),
 }
 
 pub struct Parser<'a> {
@@ -63,28 +114,79 @@ impl Display for TokenValue {
             Self::String {
                 length_label,
                 value,
+                null_terminated,
             } => {
-                write!(f, ".string ")?;
+                if *null_terminated {
+                    write!(f, ".asciiz ")?;
+                } else {
+                    write!(f, ".string ")?;
+                }
 
                 if let Some(label) = length_label {
                     write!(f, "{} ", label)?;
                 }
 
-                write!(f, "\"{}\"", value.escape_default())
+                write!(f, "\"")?;
+                for byte in value {
+                    write!(f, "{}", std::ascii::escape_default(*byte))?;
+                }
+                write!(f, "\"")
             }
 
-            Self::Align(alignment) => write!(f, ".align {}", alignment),
+            Self::Align { alignment, fill: 0 } => write!(f, ".align {}", alignment),
+            Self::Align { alignment, fill } => write!(f, ".align {}, {:#04X}", alignment, fill),
+            Self::Section(kind) => write!(f, ".section {}", kind),
+            Self::Org(offset) => write!(f, ".org {}", offset),
+            Self::If(symbol) => write!(f, ".if {}", symbol),
+            Self::Else => write!(f, ".else"),
+            Self::EndIf => write!(f, ".endif"),
+            Self::Zero(count) => write!(f, ".zero {}", count),
+            Self::Preload { name, address } => write!(f, ".preload {} {:#X}", name, address),
+            Self::EndPreload => write!(f, ".endpreload"),
+            Self::SizeOf { label, start } => write!(f, ".sizeof {} {}", label, start),
+            Self::Words {
+                length_label,
+                values,
+            } => {
+                write!(f, ".word")?;
+
+                if let Some(label) = length_label {
+                    write!(f, " {}", label)?;
+                }
+
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+
+                    write!(f, " {}", value)?;
+                }
+
+                Ok(())
+            }
+            Self::Bytes(bytes) => {
+                write!(f, ".byte")?;
+                for (i, byte) in bytes.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+
+                    write!(f, " {}", byte)?;
+                }
+
+                Ok(())
+            }
             Self::Opcode {
                 instruction,
                 operands,
             } => {
                 write!(f, "    {}", instruction)?;
-                for i in 0..operands.len() {
+                for (i, operand) in operands.iter().enumerate() {
                     if i > 0 {
                         write!(f, ",")?;
                     }
 
-                    write!(f, " {}", &operands[i])?;
+                    write!(f, " {}", operand)?;
                 }
 
                 Ok(())
@@ -96,7 +198,7 @@ impl Display for TokenValue {
 impl Operand {
     fn mode(&self) -> OperandMode {
         match self {
-            Self::Immediate(_) | Self::Label(_) => OperandMode::ReadOnly,
+            Self::Immediate(_) | Self::Label { .. } => OperandMode::ReadOnly,
             _ => OperandMode::ReadWrite,
         }
     }
@@ -105,7 +207,11 @@ impl Operand {
 impl Display for Operand {
     fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
         match self {
-            Operand::Label(x) => write!(f, "{}", x),
+            Operand::Label { name, offset, .. } => match offset.cmp(&0) {
+                Ordering::Equal => write!(f, "{}", name),
+                Ordering::Greater => write!(f, "{}+{}", name, offset),
+                Ordering::Less => write!(f, "{}-{}", name, offset.unsigned_abs()),
+            },
             Operand::Immediate(value) => write!(f, "{}", value),
             Operand::Register(i) => write!(f, "R{}", i),
             Operand::Reference {
@@ -117,12 +223,22 @@ impl Display for Operand {
             }
             Operand::Stack(0) => write!(f, "[SP]"),
             Operand::Stack(offset) => write!(f, "[SP{:+}]", offset),
+            Operand::Indexed { base, index } => write!(f, "[R{}+R{}]", base, index),
+        }
+    }
+}
+
+impl Display for DefineValue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            DefineValue::Number(value) => write!(f, "{}", value),
+            DefineValue::Symbol(symbol) => write!(f, "{}", symbol),
         }
     }
 }
 
 impl Parser<'_> {
-    fn new(inputs: &[LexerToken]) -> Parser {
+    fn new(inputs: &[LexerToken]) -> Parser<'_> {
         Parser {
             inputs,
             input_index: 0,
@@ -142,6 +258,11 @@ impl Parser<'_> {
         &self.inputs[self.input_index]
     }
 
+    /// Token right after the current one, if there is one
+    fn peek_next(&self) -> Option<&LexerTokenValue> {
+        self.inputs.get(self.input_index + 1).map(|x| &x.value)
+    }
+
     fn consume(&mut self) -> bool {
         if self.is_eof() {
             return false;
@@ -171,23 +292,43 @@ impl Parser<'_> {
             end,
         }
     }
+
+    /// Range from the start of the current token to the end of the last consumed input
+    fn consumed_range(&self) -> FileRange {
+        let end = match self.input_index.checked_sub(1) {
+            Some(i) => self.inputs[i].range.end,
+            None => self.token_start,
+        };
+
+        FileRange {
+            start: self.token_start,
+            end,
+        }
+    }
+
     fn make_token(&mut self, value: TokenValue) {
         self.outputs.push(Token {
             value,
-            range: self.range(),
+            range: self.consumed_range(),
         })
     }
 
     fn make_error(&self, msg: &str) -> Error {
+        self.make_error_at(self.range(), msg)
+    }
+
+    fn make_error_at(&self, range: FileRange, msg: &str) -> Error {
         Error {
             message: msg.to_owned(),
-            range: self.range(),
+            range: Some(range),
+            phase: Phase::Parser,
         }
     }
 
     fn parse(mut self) -> Result<Vec<Token>> {
         if !self.outputs.is_empty() {
             return Err(Error::from_message(
+                Phase::Parser,
                 "Parser cannot be reused after parse() is called",
             ));
         }
@@ -203,8 +344,8 @@ impl Parser<'_> {
     fn parse_single(&mut self) -> VoidResult {
         if let LexerTokenValue::LabelDefinition(label) = self.peek() {
             let label = label.to_owned();
-            self.make_token(TokenValue::Label(label));
             self.consume();
+            self.make_token(TokenValue::Label(label));
             return Ok(());
         }
 
@@ -212,6 +353,13 @@ impl Parser<'_> {
             return self.parse_directive();
         }
 
+        // Local label references are lexed from anything that looks like a directive but isn't one
+        if let LexerTokenValue::LabelReference(name) = self.peek() {
+            if let Some(directive) = name.strip_prefix('.') {
+                return Err(self.make_error(&format!("Unknown directive '{}'", directive)));
+            }
+        }
+
         if let LexerTokenValue::Instruction(_) = self.peek() {
             return self.parse_opcode();
         }
@@ -225,16 +373,53 @@ impl Parser<'_> {
             _ => return Err(self.make_error("Expected directive")),
         };
 
-        self.consume_or_error()?;
+        // Directives without arguments can be the last thing in the file
+        let has_arguments = !matches!(
+            directive,
+            LexerDirective::Else
+                | LexerDirective::EndIf
+                | LexerDirective::EndRept
+                | LexerDirective::EndPreload
+        );
+        if has_arguments {
+            self.consume_or_error()?;
+        } else {
+            self.consume();
+        }
 
         match directive {
-            LexerDirective::String => self.parse_directive_string(),
+            LexerDirective::String => self.parse_directive_string(false),
             LexerDirective::Align => self.parse_directive_align(),
             LexerDirective::Define => self.parse_directive_define(),
+            LexerDirective::Section => self.parse_directive_section(),
+            LexerDirective::Org => self.parse_directive_org(),
+            LexerDirective::If => self.parse_directive_if(),
+            LexerDirective::Else => {
+                self.make_token(TokenValue::Else);
+                Ok(())
+            }
+            LexerDirective::EndIf => {
+                self.make_token(TokenValue::EndIf);
+                Ok(())
+            }
+            LexerDirective::Byte => self.parse_directive_byte(),
+            LexerDirective::Word => self.parse_directive_word(),
+            LexerDirective::Zero => self.parse_directive_zero(),
+            LexerDirective::SizeOf => self.parse_directive_sizeof(),
+            LexerDirective::Asciiz => self.parse_directive_string(true),
+            LexerDirective::Rept => self.parse_directive_rept(),
+            LexerDirective::Preload => self.parse_directive_preload(),
+            LexerDirective::EndPreload => {
+                self.make_token(TokenValue::EndPreload);
+                Ok(())
+            }
+            LexerDirective::EndRept => {
+                Err(self.make_error_at(self.consumed_range(), ".endr without a matching .rept"))
+            }
         }
     }
 
-    fn parse_directive_string(&mut self) -> VoidResult {
+    fn parse_directive_string(&mut self, null_terminated: bool) -> VoidResult {
         let length_label = match self.peek() {
             LexerTokenValue::LabelReference(s) => {
                 let owned = s.to_owned();
@@ -245,7 +430,7 @@ impl Parser<'_> {
         };
 
         let value = match self.peek() {
-            LexerTokenValue::StringLiteral(str) => str.to_owned(),
+            LexerTokenValue::StringLiteral(bytes) => bytes.to_owned(),
             _ => return Err(self.make_error("Expected string literal")),
         };
 
@@ -253,6 +438,7 @@ impl Parser<'_> {
         self.make_token(TokenValue::String {
             length_label,
             value,
+            null_terminated,
         });
 
         Ok(())
@@ -268,12 +454,138 @@ impl Parser<'_> {
             return Err(self.make_error("Alignment must be bigger than 1"));
         }
 
+        let mut fill = 0;
+        if self.consume() && *self.peek() == LexerTokenValue::ArgumentSeparator {
+            self.consume_or_error()?;
+
+            let value = match self.peek() {
+                LexerTokenValue::Number(n) => *n,
+                LexerTokenValue::CharacterLiteral(c) => *c as IWord,
+                _ => return Err(self.make_error("Expected a fill byte")),
+            };
+
+            if !(0..=0xFF).contains(&value) {
+                return Err(self.make_error_at(
+                    self.peek_full().range,
+                    &format!("Fill byte {} is outside of the range 0 to 255", value),
+                ));
+            }
+
+            fill = value as u8;
+            self.consume();
+        }
+
+        self.make_token(TokenValue::Align {
+            alignment: alignment as UWord,
+            fill,
+        });
+
+        Ok(())
+    }
+
+    fn parse_directive_byte(&mut self) -> VoidResult {
+        let mut bytes = Vec::new();
+
+        for (value, range) in self.parse_number_list()? {
+            if !(0..=0xFF).contains(&value) {
+                return Err(Error {
+                    message: format!("Byte value {} is outside of the range 0 to 255", value),
+                    range: Some(range),
+                    phase: Phase::Parser,
+                });
+            }
+
+            bytes.push(value as u8);
+        }
+
+        self.make_token(TokenValue::Bytes(bytes));
+        Ok(())
+    }
+
+    fn parse_directive_word(&mut self) -> VoidResult {
+        let length_label = match self.peek() {
+            LexerTokenValue::LabelReference(s) => {
+                let owned = s.to_owned();
+                self.consume_or_error()?;
+                Some(owned)
+            }
+            _ => None,
+        };
+
+        let values = self
+            .parse_number_list()?
+            .into_iter()
+            .map(|(value, _)| value)
+            .collect();
+
+        self.make_token(TokenValue::Words {
+            length_label,
+            values,
+        });
+
+        Ok(())
+    }
+
+    fn parse_directive_zero(&mut self) -> VoidResult {
+        let count = match self.peek() {
+            LexerTokenValue::Number(n) => *n,
+            _ => return Err(self.make_error("Expected a number")),
+        };
+
+        if count < 0 {
+            return Err(self.make_error("Number of zero bytes cannot be negative"));
+        }
+
+        self.consume();
+        self.make_token(TokenValue::Zero(count as UWord));
+
+        Ok(())
+    }
+
+    fn parse_directive_sizeof(&mut self) -> VoidResult {
+        let label = match self.peek() {
+            LexerTokenValue::LabelReference(l) => l.to_owned(),
+            _ => return Err(self.make_error("Expected a label")),
+        };
+
+        self.consume_or_error()?;
+
+        let start = match self.peek() {
+            LexerTokenValue::LabelReference(l) => l.to_owned(),
+            _ => return Err(self.make_error("Expected the label to measure from")),
+        };
+
         self.consume();
-        self.make_token(TokenValue::Align(alignment as UWord));
+        self.make_token(TokenValue::SizeOf { label, start });
 
         Ok(())
     }
 
+    /// Parses a comma-separated list of numbers or character literals, along with the range of each one
+    fn parse_number_list(&mut self) -> Result<Vec<(IWord, FileRange)>> {
+        let mut values = Vec::new();
+
+        loop {
+            let value = match self.peek() {
+                LexerTokenValue::Number(n) => *n,
+                LexerTokenValue::CharacterLiteral(c) => *c as IWord,
+                _ => return Err(self.make_error("Expected a number")),
+            };
+
+            values.push((value, self.peek_full().range));
+            if !self.consume() {
+                break;
+            }
+
+            match self.peek() {
+                LexerTokenValue::ArgumentSeparator => self.consume_or_error()?,
+                _ => break,
+            }
+        }
+
+        Ok(values)
+    }
+
     fn parse_directive_define(&mut self) -> VoidResult {
         let label = match self.peek() {
             LexerTokenValue::LabelReference(l) => l.to_owned(),
@@ -283,12 +595,132 @@ impl Parser<'_> {
         self.consume_or_error()?;
 
         let value = match self.peek() {
+            LexerTokenValue::Number(n) => DefineValue::Number(*n),
+            LexerTokenValue::LabelReference(l) => DefineValue::Symbol(l.to_owned()),
+            _ => return Err(self.make_error("Expected a number or a symbol")),
+        };
+
+        self.consume();
+        self.make_token(TokenValue::Define { label, value });
+
+        Ok(())
+    }
+
+    fn parse_directive_org(&mut self) -> VoidResult {
+        let offset = match self.peek() {
             LexerTokenValue::Number(n) => *n,
             _ => return Err(self.make_error("Expected a number")),
         };
 
+        if offset < 0 {
+            return Err(self.make_error("Origin cannot be negative"));
+        }
+
         self.consume();
-        self.make_token(TokenValue::Define { label, value });
+        self.make_token(TokenValue::Org(offset as UWord));
+
+        Ok(())
+    }
+
+    fn parse_directive_if(&mut self) -> VoidResult {
+        let symbol = match self.peek() {
+            LexerTokenValue::LabelReference(l) => l.to_owned(),
+            _ => return Err(self.make_error("Expected a symbol")),
+        };
+
+        self.consume();
+        self.make_token(TokenValue::If(symbol));
+
+        Ok(())
+    }
+
+    /// Parses everything up to the matching `.endr` and outputs it the specified number of times.
+    /// The copies are identical, so any label defined inside the block is defined again by every copy
+    fn parse_directive_rept(&mut self) -> VoidResult {
+        let count = match self.peek() {
+            LexerTokenValue::Number(n) => *n,
+            _ => return Err(self.make_error("Expected a repeat count")),
+        };
+
+        if count < 0 {
+            return Err(self.make_error("Repeat count cannot be negative"));
+        }
+
+        let rept_range = self.range();
+        self.consume();
+
+        let body_start = self.outputs.len();
+        loop {
+            if self.is_eof() {
+                return Err(self.make_error_at(rept_range, "Missing .endr for .rept"));
+            }
+
+            if *self.peek() == LexerTokenValue::Directive(LexerDirective::EndRept) {
+                self.consume();
+                break;
+            }
+
+            self.token_start = self.peek_full().range.start;
+            self.parse_single()?;
+        }
+
+        let body = self.outputs.split_off(body_start);
+        let total = match body.len().checked_mul(count as usize) {
+            Some(total) if total <= MAX_REPEATED_TOKENS => total,
+            _ => {
+                return Err(self.make_error_at(
+                    rept_range,
+                    &format!(
+                    "Repeating {} tokens {} times would produce more than the maximum of {} tokens",
+                    body.len(),
+                    count,
+                    MAX_REPEATED_TOKENS
+                ),
+                ))
+            }
+        };
+
+        self.outputs
+            .extend(body.iter().cycle().take(total).cloned());
+
+        Ok(())
+    }
+
+    fn parse_directive_preload(&mut self) -> VoidResult {
+        let name = match self.peek() {
+            LexerTokenValue::LabelReference(l) => l.to_owned(),
+            _ => return Err(self.make_error("Expected a name for the preloaded data")),
+        };
+
+        self.consume_or_error()?;
+
+        let address = match self.peek() {
+            LexerTokenValue::Number(n) => *n,
+            _ => return Err(self.make_error("Expected the address to load the data at")),
+        };
+
+        if address < 0 {
+            return Err(self.make_error("Preload address cannot be negative"));
+        }
+
+        self.consume();
+        self.make_token(TokenValue::Preload {
+            name,
+            address: address as UWord,
+        });
+
+        Ok(())
+    }
+
+    fn parse_directive_section(&mut self) -> VoidResult {
+        let kind = match self.peek() {
+            LexerTokenValue::LabelReference(name) if name == "code" => SectionKind::Code,
+            LexerTokenValue::LabelReference(name) if name == "data" => SectionKind::Data,
+            _ => return Err(self.make_error("Expected a section kind (code or data)")),
+        };
+
+        self.consume();
+        self.make_token(TokenValue::Section(kind));
 
         Ok(())
     }
@@ -303,10 +735,17 @@ impl Parser<'_> {
         let mut operands = Vec::new();
 
         loop {
+            if !self.is_eof() && *self.peek() == LexerTokenValue::ArgumentSeparator {
+                return Err(self.make_error_at(self.peek_full().range, "Unexpected comma"));
+            }
+
             match self.parse_operand()? {
                 Some(x) => operands.push(x),
                 None if operands.is_empty() => break,
-                None => return Err(self.make_error("Expected operand")),
+                None => {
+                    let comma = self.inputs[self.input_index - 1].range;
+                    return Err(self.make_error_at(comma, "Trailing comma after the last operand"));
+                }
             }
 
             if self.is_eof() {
@@ -314,34 +753,51 @@ impl Parser<'_> {
             }
 
             match self.peek() {
-                LexerTokenValue::ArgumentSeparator => self.consume_or_error()?,
+                LexerTokenValue::ArgumentSeparator => self.consume(),
                 _ => break,
-            }
+            };
         }
 
         let descriptor = instruction.descriptor();
-        if descriptor.operands.len() != operands.len() {
-            return Err(self.make_error(&format!(
-                "{} expects {} operand(s), but {} were provided",
-                descriptor.mnemonic,
-                descriptor.operands.len(),
-                operands.len()
-            )));
+        if !descriptor.accepts_operand_count(operands.len()) {
+            return Err(self.make_error_at(
+                self.consumed_range(),
+                &format!(
+                    "{} expects {} operand(s), but {} were provided",
+                    descriptor.mnemonic,
+                    descriptor.operand_count_description(),
+                    operands.len()
+                ),
+            ));
         }
 
-        for i in 0..descriptor.operands.len() {
-            let expected = descriptor.operands[i];
-            let actual = operands[i].mode();
+        for (i, (expected, operand)) in descriptor.operands.iter().zip(&operands).enumerate() {
+            if operand.mode().can_be_used_as(expected) {
+                continue;
+            }
 
-            if !actual.can_be_used_as(&expected) {
-                return Err(self.make_error(&format!(
+            let message = if *expected == OperandMode::ReadWrite {
+                format!(
+                    "{}'s operand {} is written to, so it can't be the constant value {}. Use a register or memory reference instead",
+                    descriptor.mnemonic,
+                    i + 1,
+                    operand
+                )
+            } else {
+                format!(
                     "{}'s operand {} is {}, but {} was provided",
                     descriptor.mnemonic,
                     i + 1,
                     expected,
-                    operands[i]
-                )));
-            }
+                    operand
+                )
+            };
+
+            return Err(Error {
+                message,
+                range: Some(self.consumed_range()),
+                phase: Phase::Parser,
+            });
         }
 
         self.make_token(TokenValue::Opcode {
@@ -360,7 +816,10 @@ impl Parser<'_> {
         let mut consume = true;
 
         let found_operand = match self.peek() {
-            LexerTokenValue::LabelReference(label) => Some(Operand::Label(label.clone())),
+            LexerTokenValue::LabelReference(_) => {
+                consume = false;
+                Some(self.parse_label_operand()?)
+            }
             LexerTokenValue::Number(n) => Some(Operand::Immediate(*n)),
             LexerTokenValue::Register(i) => Some(Operand::Register(*i)),
             LexerTokenValue::CharacterLiteral(c) => Some(Operand::Immediate(*c as IWord)),
@@ -381,6 +840,31 @@ impl Parser<'_> {
         Ok(found_operand)
     }
 
+    /// Parses a label, optionally followed by a constant offset with no spaces in between, like `table+8`
+    fn parse_label_operand(&mut self) -> Result<Operand> {
+        let (name, mut range) = match &self.peek_full().value {
+            LexerTokenValue::LabelReference(name) => (name.to_owned(), self.peek_full().range),
+            _ => return Err(self.make_error("Expected label")),
+        };
+
+        self.consume();
+
+        let mut offset = 0;
+        if !self.is_eof() && self.peek_full().range.start == range.end {
+            if let LexerTokenValue::Number(n) = self.peek() {
+                offset = *n;
+                range.end = self.peek_full().range.end;
+                self.consume();
+            }
+        }
+
+        Ok(Operand::Label {
+            name,
+            offset,
+            range,
+        })
+    }
+
     fn parse_reference_or_stack(&mut self) -> Result<Operand> {
         match self.peek() {
             LexerTokenValue::StartReference => {}
@@ -397,10 +881,36 @@ impl Parser<'_> {
 
         self.consume_or_error()?;
 
-        let offset = self.parse_reference_or_stack_offset()?;
-        if offset < 0 && register.is_none() {
-            return Err(self.make_error("Stack pointer offsets cannot be negative"));
-        }
+        let is_indexed = register.is_some()
+            && *self.peek() == LexerTokenValue::OffsetPositive
+            && matches!(self.peek_next(), Some(LexerTokenValue::Register(_)));
+
+        let operand = match register {
+            Some(base) if is_indexed => {
+                self.consume_or_error()?;
+                let index = match self.peek() {
+                    LexerTokenValue::Register(r) => *r,
+                    _ => unreachable!("is_indexed checked that a register comes next"),
+                };
+                self.consume_or_error()?;
+
+                Operand::Indexed { base, index }
+            }
+            _ => {
+                let offset = self.parse_reference_or_stack_offset()?;
+                if offset < 0 && register.is_none() {
+                    return Err(self.make_error("Stack pointer offsets cannot be negative"));
+                }
+
+                match register {
+                    None => Operand::Stack(offset as UWord),
+                    Some(r) => Operand::Reference {
+                        register: r,
+                        offset,
+                    },
+                }
+            }
+        };
 
         match self.peek() {
             LexerTokenValue::EndReference => {}
@@ -408,14 +918,7 @@ impl Parser<'_> {
         }
 
         self.consume();
-
-        match register {
-            None => Ok(Operand::Stack(offset as UWord)),
-            Some(r) => Ok(Operand::Reference {
-                register: r,
-                offset,
-            }),
-        }
+        Ok(operand)
     }
 
     fn parse_reference_or_stack_offset(&mut self) -> Result<IWord> {
@@ -443,5 +946,54 @@ impl Parser<'_> {
 }
 
 pub fn parse(tokens: &[LexerToken]) -> Result<Vec<Token>> {
-    Parser::new(tokens).parse()
+    let tokens: Vec<LexerToken> = tokens
+        .iter()
+        .filter(|x| !matches!(x.value, LexerTokenValue::Comment(_)))
+        .cloned()
+        .collect();
+
+    Parser::new(&tokens).parse()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::lexer;
+
+    fn parse_source(source: &str) -> Result<Vec<Token>> {
+        parse(&lexer::lex(&mut source.as_bytes())?)
+    }
+
+    fn range(start: (u64, u64), end: (u64, u64)) -> FileRange {
+        FileRange {
+            start: FilePosition {
+                line: start.0,
+                column: start.1,
+            },
+            end: FilePosition {
+                line: end.0,
+                column: end.1,
+            },
+        }
+    }
+
+    #[test]
+    fn doubled_comma_is_reported_at_the_second_comma() {
+        let error = parse_source("add R0,,R1").unwrap_err();
+
+        assert_eq!(error.message, "Unexpected comma");
+        assert_eq!(error.range, Some(range((1, 8), (1, 9))));
+    }
+
+    #[test]
+    fn trailing_comma_is_reported_at_the_comma() {
+        let error = parse_source("add R0, R1,\nhalt").unwrap_err();
+
+        assert_eq!(error.message, "Trailing comma after the last operand");
+        assert_eq!(error.range, Some(range((1, 11), (1, 12))));
+
+        let at_end = parse_source("add R0, R1,").unwrap_err();
+        assert_eq!(at_end.message, "Trailing comma after the last operand");
+        assert_eq!(at_end.range, Some(range((1, 11), (1, 12))));
+    }
 }
@@ -0,0 +1,225 @@
+use crate::core::{Error, Result, UWord, VoidResult};
+use std::convert::TryInto;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::io::{Read, Write};
+
+/// Bytes that identify an executable with a header.
+/// No instruction starts with these bytes, but a program that starts with data can, so `Executable::write`
+/// always adds a header to such programs. Headerless executables made by other tools must not start with them.
+pub const MAGIC: [u8; 4] = [0xFF, b'L', b'K', b'S'];
+pub const VERSION: u8 = 2;
+
+/// What the bytes of a section are meant to be used for
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum SectionKind {
+    Code,
+    Data,
+}
+
+/// A range of the program that contains a single kind of content
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct Section {
+    pub kind: SectionKind,
+    /// Address of the first byte of the section
+    pub start: UWord,
+    /// Address right after the last byte of the section
+    pub end: UWord,
+}
+
+/// Data that is loaded into memory at a fixed address before the program starts running
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct PreloadSection {
+    /// Name of the data, only used for debugging
+    pub name: String,
+    /// Address where the data will be loaded. Must be page-aligned and after the program.
+    pub address: UWord,
+    pub data: Vec<u8>,
+}
+
+/// An assembled program along with its metadata
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct Executable {
+    /// Sections of the program, in ascending address order. Empty if the program doesn't use sections.
+    pub sections: Vec<Section>,
+    /// Data loaded into memory along with the program, in ascending address order
+    pub preloads: Vec<PreloadSection>,
+    /// The bytes that get loaded into memory at address 0
+    pub program: Vec<u8>,
+}
+
+impl SectionKind {
+    fn from_value(value: u8) -> Option<SectionKind> {
+        match value {
+            0 => Some(SectionKind::Code),
+            1 => Some(SectionKind::Data),
+            _ => None,
+        }
+    }
+
+    fn value(&self) -> u8 {
+        match self {
+            SectionKind::Code => 0,
+            SectionKind::Data => 1,
+        }
+    }
+}
+
+impl Display for SectionKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            SectionKind::Code => write!(f, "code"),
+            SectionKind::Data => write!(f, "data"),
+        }
+    }
+}
+
+impl Executable {
+    /// Reads an executable, with or without a header
+    pub fn read(reader: &mut impl Read) -> Result<Executable> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+
+        if !data.starts_with(&MAGIC) {
+            return Ok(Executable {
+                sections: Vec::new(),
+                preloads: Vec::new(),
+                program: data,
+            });
+        }
+
+        let mut header = HeaderReader {
+            data: &data,
+            index: MAGIC.len(),
+        };
+
+        let version = header.read_byte()?;
+        if version == 0 || version > VERSION {
+            return Err(Error::new(&format!(
+                "Unsupported executable version {}",
+                version
+            )));
+        }
+
+        let section_count = header.read_word()?;
+        let mut sections = Vec::new();
+        for _ in 0..section_count {
+            let kind_value = header.read_byte()?;
+            let kind = SectionKind::from_value(kind_value)
+                .ok_or_else(|| Error::new(&format!("Invalid section kind {:02X}", kind_value)))?;
+
+            let start = header.read_word()?;
+            let end = header.read_word()?;
+            sections.push(Section { kind, start, end });
+        }
+
+        // Version 1 didn't support preloaded data
+        let mut preloads = Vec::new();
+        if version >= 2 {
+            let preload_count = header.read_word()?;
+            for _ in 0..preload_count {
+                let name_len = header.read_word()?;
+                let name = String::from_utf8_lossy(header.read(name_len as usize)?).into_owned();
+                let address = header.read_word()?;
+                let data_len = header.read_word()?;
+                let data = header.read(data_len as usize)?.to_vec();
+                preloads.push(PreloadSection {
+                    name,
+                    address,
+                    data,
+                });
+            }
+        }
+
+        let program = data[header.index..].to_vec();
+        for section in &sections {
+            if section.start > section.end || section.end > program.len() as UWord {
+                return Err(Error::new(&format!(
+                    "Section {:016X}-{:016X} is outside of the program",
+                    section.start, section.end
+                )));
+            }
+        }
+
+        Ok(Executable {
+            sections,
+            preloads,
+            program,
+        })
+    }
+
+    /// Writes the executable, only including a header if there is any metadata to store,
+    /// or if the program starts with `MAGIC` and would be mistaken for a header without one
+    pub fn write(&self, writer: &mut impl Write) -> VoidResult {
+        if !self.sections.is_empty()
+            || !self.preloads.is_empty()
+            || self.program.starts_with(&MAGIC)
+        {
+            writer.write_all(&MAGIC)?;
+            writer.write_all(&[VERSION])?;
+            writer.write_all(&(self.sections.len() as UWord).to_le_bytes())?;
+
+            for section in &self.sections {
+                writer.write_all(&[section.kind.value()])?;
+                writer.write_all(&section.start.to_le_bytes())?;
+                writer.write_all(&section.end.to_le_bytes())?;
+            }
+
+            writer.write_all(&(self.preloads.len() as UWord).to_le_bytes())?;
+            for preload in &self.preloads {
+                writer.write_all(&(preload.name.len() as UWord).to_le_bytes())?;
+                writer.write_all(preload.name.as_bytes())?;
+                writer.write_all(&preload.address.to_le_bytes())?;
+                writer.write_all(&(preload.data.len() as UWord).to_le_bytes())?;
+                writer.write_all(&preload.data)?;
+            }
+        }
+
+        writer.write_all(&self.program)?;
+        Ok(())
+    }
+}
+
+struct HeaderReader<'a> {
+    data: &'a [u8],
+    index: usize,
+}
+
+impl HeaderReader<'_> {
+    fn read(&mut self, len: usize) -> Result<&[u8]> {
+        let bytes = self
+            .data
+            .get(self.index..self.index.saturating_add(len))
+            .ok_or_else(|| Error::new("Unexpected end of executable header"))?;
+
+        self.index += len;
+        Ok(bytes)
+    }
+
+    fn read_byte(&mut self) -> Result<u8> {
+        Ok(self.read(1)?[0])
+    }
+
+    fn read_word(&mut self) -> Result<UWord> {
+        let bytes = self.read(std::mem::size_of::<UWord>())?;
+        Ok(UWord::from_le_bytes(bytes.try_into().unwrap()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn program_starting_with_magic_round_trips() {
+        let mut program = MAGIC.to_vec();
+        program.extend_from_slice(&[VERSION, 0, 0]);
+        let executable = Executable {
+            program,
+            ..Default::default()
+        };
+
+        let mut bytes = Vec::new();
+        executable.write(&mut bytes).unwrap();
+        assert_eq!(Executable::read(&mut bytes.as_slice()).unwrap(), executable);
+    }
+}
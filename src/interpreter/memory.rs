@@ -1,21 +1,17 @@
-use super::DataWord;
-use crate::core::{
-    Error, Result, UWord, VoidResult, INITIAL_MEMORY_SIZE, MAX_MEMORY_SIZE, WORD_BYTE_SIZE,
-};
+use super::{DataWord, GcPolicy};
+use crate::core::{Error, Result, UWord, VoidResult, MAX_MEMORY_SIZE, WORD_BYTE_SIZE};
 use bitvec::prelude::*;
 use bitvec::ptr::{Const, Mut};
 use bytesize::ByteSize;
-use std::alloc;
-use std::alloc::Layout;
+use std::cell::RefCell;
 use std::cmp::min;
-use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::convert::TryInto;
-use std::fmt::{self, Display, Formatter};
-use std::hash::Hash;
-use std::io::{self, Read};
-use std::ops::{Add, Deref, DerefMut, Div, Index, IndexMut, Mul, Range, Sub};
-use std::ptr;
-use std::slice;
+use std::fmt::{self, Debug, Display, Formatter};
+use std::hash::{Hash, Hasher};
+use std::io::{self, Write};
+use std::ops::{Add, Deref, DerefMut, Div, Index, IndexMut, Mul, Range, Rem};
 
 const VIRTUAL_PAGE_SIZE: UWord = 1024;
 
@@ -25,33 +21,273 @@ pub struct Memory {
     regions: HeapRegions,
     allocations: IdHashMap<Allocation>,
     heap: Heap,
+    garbage_collection_count: u64,
+    gc_policy: GcPolicy,
+    /// Allocations made since the garbage collector last ran, used by the periodic policy
+    allocations_since_collection: u64,
+    /// If words can be read and written at addresses that aren't a multiple of the word size
+    allow_unaligned: bool,
+    /// Where diagnostics such as the out of memory report are written to
+    diagnostics: Diagnostics,
+    trace: Option<RefCell<Vec<MemoryAccess>>>,
+    watchpoints: BTreeSet<UWord>,
+    watchpoint_hits: Vec<WatchpointHit>,
+    /// What every garbage collection did, only recorded if enabled with `set_recording_collections`
+    collections: Option<Vec<CollectionReport>>,
+}
+
+/// Destination of diagnostics, wrapped so `Memory` can still derive `Debug`
+struct Diagnostics(Box<dyn Write>);
+
+/// A single read or write recorded while memory tracing is enabled
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct MemoryAccess {
+    pub is_write: bool,
+    pub address: UWord,
+    /// Bytes that were read or written. Word accesses are stored as little-endian
+    pub data: Vec<u8>,
+}
+
+/// What a single run of the garbage collector did
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct CollectionReport {
+    /// IDs of the allocations that were freed, in ascending order. These are the same IDs given by `objid`
+    pub freed: Vec<UWord>,
+    /// Number of allocations still alive after the collection, including the ones that are never collected
+    /// such as the program and the stack
+    pub survivors: usize,
+}
+
+/// A write to a watched word
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct WatchpointHit {
+    pub address: UWord,
+    pub old_value: UWord,
+    pub new_value: UWord,
 }
 
 impl Memory {
-    pub fn new() -> Memory {
+    /// Creates an empty memory whose heap starts out with `heap_size` bytes
+    pub fn new(heap_size: usize) -> Memory {
         Memory {
             virtual_mapper: VirtualAddressMapper::new(),
             allocations: IdHashMap::new(),
-            regions: HeapRegions::new(INITIAL_MEMORY_SIZE),
-            heap: Heap::new(INITIAL_MEMORY_SIZE),
+            regions: HeapRegions::new(heap_size),
+            heap: Heap::new(heap_size),
+            garbage_collection_count: 0,
+            gc_policy: GcPolicy::OnPressure,
+            allocations_since_collection: 0,
+            allow_unaligned: false,
+            diagnostics: Diagnostics(Box::new(io::stdout())),
+            trace: None,
+            watchpoints: BTreeSet::new(),
+            watchpoint_hits: Vec::new(),
+            collections: None,
         }
     }
 
-    pub fn reader_for(&self, addr: UWord) -> MemoryReader {
-        MemoryReader::new(self, addr)
+    /// Starts or stops recording every read and write, apart from instruction fetches
+    pub fn set_tracing(&mut self, enabled: bool) {
+        self.trace = if enabled {
+            Some(RefCell::new(Vec::new()))
+        } else {
+            None
+        };
+    }
+
+    /// Changes when allocations automatically run the garbage collector
+    pub fn set_gc_policy(&mut self, policy: GcPolicy) {
+        self.gc_policy = policy;
+        self.allocations_since_collection = 0;
+    }
+
+    /// Allows or forbids word accesses at addresses that aren't a multiple of the word size.
+    /// Unaligned words straddle two aligned words, so they can only hold regular data, never references
+    pub fn set_allow_unaligned(&mut self, allowed: bool) {
+        self.allow_unaligned = allowed;
+    }
+
+    /// Redirects where diagnostics, such as the report of what was requested when running out of memory, are written to.
+    /// Defaults to stdout. Errors are still returned no matter where diagnostics go
+    pub fn set_diagnostics(&mut self, diagnostics: Box<dyn Write>) {
+        self.diagnostics = Diagnostics(diagnostics);
+    }
+
+    /// Where diagnostics are written to, as set by `set_diagnostics`
+    pub fn diagnostics(&mut self) -> &mut dyn Write {
+        &mut *self.diagnostics.0
+    }
+
+    /// Starts or stops recording a report of every garbage collection
+    pub fn set_recording_collections(&mut self, enabled: bool) {
+        self.collections = if enabled { Some(Vec::new()) } else { None };
+    }
+
+    /// Returns the reports of all collections since the last call, if recording is enabled
+    pub fn take_collection_reports(&mut self) -> Vec<CollectionReport> {
+        match &mut self.collections {
+            Some(collections) => collections.split_off(0),
+            None => Vec::new(),
+        }
+    }
+
+    /// Returns all accesses recorded since the last call, if tracing is enabled
+    pub fn take_trace(&mut self) -> Vec<MemoryAccess> {
+        match &mut self.trace {
+            Some(trace) => trace.get_mut().split_off(0),
+            None => Vec::new(),
+        }
+    }
+
+    fn record(&self, is_write: bool, address: UWord, data: &[u8]) {
+        if let Some(trace) = &self.trace {
+            trace.borrow_mut().push(MemoryAccess {
+                is_write,
+                address,
+                data: data.to_vec(),
+            });
+        }
+    }
+
+    /// Starts recording every write to the word at `addr`
+    pub fn add_watchpoint(&mut self, addr: UWord) -> VoidResult {
+        Self::ensure_aligned(addr)?;
+        self.watchpoints.insert(addr);
+        Ok(())
+    }
+
+    /// Stops watching the word at `addr`. Returns false if it wasn't being watched
+    pub fn remove_watchpoint(&mut self, addr: UWord) -> bool {
+        self.watchpoints.remove(&addr)
+    }
+
+    pub fn watchpoints(&self) -> Vec<UWord> {
+        self.watchpoints.iter().copied().collect()
+    }
+
+    /// Number of writes to watched words that haven't been taken yet
+    pub fn pending_watchpoint_hits(&self) -> usize {
+        self.watchpoint_hits.len()
+    }
+
+    /// Returns all writes to watched words since the last call
+    pub fn take_watchpoint_hits(&mut self) -> Vec<WatchpointHit> {
+        self.watchpoint_hits.split_off(0)
+    }
+
+    /// Reads every watched word that overlaps the given range, to be compared after the range is written to
+    fn read_watched_words(&self, addr: UWord, size: UWord) -> Vec<(UWord, UWord)> {
+        if size == 0 {
+            return Vec::new();
+        }
+
+        let first_word = addr.saturating_sub(WORD_BYTE_SIZE - 1);
+        self.watchpoints
+            .range(first_word..addr.saturating_add(size))
+            .filter_map(|&x| self.peek_word(x).map(|value| (x, value)))
+            .collect()
+    }
+
+    fn record_watchpoint_hits(&mut self, old_values: Vec<(UWord, UWord)>) {
+        for (address, old_value) in old_values {
+            if let Some(new_value) = self.peek_word(address) {
+                self.watchpoint_hits.push(WatchpointHit {
+                    address,
+                    old_value,
+                    new_value,
+                });
+            }
+        }
+    }
+
+    /// Reads a word without tracing, or None if it isn't mapped
+    fn peek_word(&self, addr: UWord) -> Option<UWord> {
+        let bytes = self.addr_to_slice(addr, WORD_BYTE_SIZE).ok()?;
+        Some(UWord::from_le_bytes(
+            bytes.try_into().expect("Invalid array size"),
+        ))
+    }
+
+    /// Number of times the garbage collector has run
+    pub fn garbage_collection_count(&self) -> u64 {
+        self.garbage_collection_count
     }
 
     pub fn get(&self, addr: UWord, size: UWord) -> Result<&[u8]> {
-        Ok(self.addr_to_slice(addr, size)?)
+        let data = self.addr_to_slice(addr, size)?;
+        self.record(false, addr, data);
+        Ok(data)
+    }
+
+    /// Same as `get`, but copies the bytes so the memory isn't borrowed while they're in use
+    pub fn read_bytes(&self, addr: UWord, size: UWord) -> Result<Vec<u8>> {
+        Ok(self.get(addr, size)?.to_vec())
+    }
+
+    /// Reads instruction bytes. Same as `get`, but never traced
+    pub fn fetch(&self, addr: UWord, size: UWord) -> Result<&[u8]> {
+        self.addr_to_slice(addr, size)
     }
 
     pub fn set(&mut self, addr: UWord, data: &[u8]) -> VoidResult {
+        let watched = self.read_watched_words(addr, data.len() as UWord);
+
         let slice = self.addr_to_mut_slice(addr, data.len() as UWord)?;
         slice.copy_from_slice(data);
 
+        self.record(true, addr, data);
+        self.record_watchpoint_hits(watched);
+        Ok(())
+    }
+
+    /// Same as `set`, but every word touched by the write, even partially, stops being a reference
+    pub fn set_data(&mut self, addr: UWord, data: &[u8]) -> VoidResult {
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        self.set(addr, data)?;
+        self.clear_references(addr, data.len() as UWord)
+    }
+
+    /// Sets `size` bytes starting at `addr` to `value`.
+    /// Every word touched by the fill, even partially, stops being a reference
+    pub fn fill(&mut self, addr: UWord, size: UWord, value: u8) -> VoidResult {
+        if size == 0 {
+            return Ok(());
+        }
+
+        let (start, end) = self.addr_to_indices(addr, size)?;
+        let watched = self.read_watched_words(addr, size);
+
+        self.heap[start..end].fill(value);
+        self.clear_references(addr, size)?;
+
+        self.record(true, addr, &self.heap[start..end]);
+        self.record_watchpoint_hits(watched);
         Ok(())
     }
 
+    /// Clears the reference bit of every word touched by the `size` bytes starting at `addr`, which can't be 0
+    fn clear_references(&mut self, addr: UWord, size: UWord) -> VoidResult {
+        let (allocation, offset) = self.addr_to_allocation(addr)?;
+        let bitfield_start = allocation.bitfield_start();
+        let bitfield_end = allocation.bitfield_end();
+
+        let first_word = offset / WORD_BYTE_SIZE as usize;
+        let last_word = (offset + size as usize - 1) / WORD_BYTE_SIZE as usize;
+        self.heap[bitfield_start..bitfield_end].view_bits_mut::<Lsb0>()[first_word..=last_word]
+            .set_all(false);
+
+        Ok(())
+    }
+
+    /// Unique identifier of the allocation that contains `addr`, which never changes or gets reused
+    pub fn allocation_id(&self, addr: UWord) -> Result<UWord> {
+        let (allocation, _) = self.addr_to_allocation(addr)?;
+        Ok(*allocation.id)
+    }
+
     pub fn is_reference(&self, addr: UWord) -> Result<bool> {
         Ok(*self.addr_to_reference_ptr(addr)?)
     }
@@ -62,7 +298,10 @@ impl Memory {
     }
 
     pub fn get_word(&self, addr: UWord) -> Result<UWord> {
-        Self::ensure_aligned(addr)?;
+        if !self.allow_unaligned {
+            Self::ensure_aligned(addr)?;
+        }
+
         Ok(UWord::from_le_bytes(
             self.get(addr, WORD_BYTE_SIZE)?
                 .try_into()
@@ -71,12 +310,19 @@ impl Memory {
     }
 
     pub fn set_word(&mut self, addr: UWord, value: UWord) -> VoidResult {
-        Self::ensure_aligned(addr)?;
+        if !self.allow_unaligned {
+            Self::ensure_aligned(addr)?;
+        }
+
         self.set(addr, &value.to_le_bytes())?;
         Ok(())
     }
 
     pub fn get_data_word(&self, addr: UWord) -> Result<DataWord> {
+        if !addr.is_multiple_of(WORD_BYTE_SIZE) && self.allow_unaligned {
+            return Ok(DataWord::new(self.get_word(addr)?, false));
+        }
+
         Ok(DataWord {
             value: self.get_word(addr)?,
             is_reference: self.is_reference(addr)?,
@@ -84,11 +330,25 @@ impl Memory {
     }
 
     pub fn set_data_word(&mut self, addr: UWord, value: DataWord) -> VoidResult {
+        if !addr.is_multiple_of(WORD_BYTE_SIZE) && self.allow_unaligned {
+            // The garbage collector only looks for references in aligned words, so it would never find this one
+            if value.is_reference {
+                return Err(Error::new(&format!(
+                    "Cannot store a reference at address {:016X}, which isn't word-aligned",
+                    addr
+                )));
+            }
+
+            return self.set_data(addr, &value.value.to_le_bytes());
+        }
+
         self.set_word(addr, value.value)?;
         self.set_reference(addr, value.is_reference)?;
         Ok(())
     }
 
+    /// Allocates `data_size` bytes, running the garbage collector and growing the heap if needed.
+    /// Invalidates every heap offset computed before the call
     pub fn allocate(
         &mut self,
         data_size: UWord,
@@ -117,14 +377,42 @@ impl Memory {
 
         let allocation = self.allocations.get(allocation_id).unwrap();
 
-        for x in &mut self.heap[allocation.bitfield_start()..allocation.bitfield_end()] {
-            *x = 0;
-        }
+        self.heap[allocation.start..allocation.bitfield_end()].fill(0);
 
         Ok(addr)
     }
 
+    /// Same as `allocate`, but the returned address is a multiple of `alignment`, which must be a power of two.
+    /// The allocation is always collectible
+    pub fn allocate_aligned(
+        &mut self,
+        data_size: UWord,
+        alignment: UWord,
+        gc_roots: &[DataWord],
+    ) -> Result<UWord> {
+        if !alignment.is_power_of_two() {
+            return Err(Error::new(&format!(
+                "Alignment {} isn't a power of two",
+                alignment
+            )));
+        }
+
+        // Allocations always start at a new page, so only bigger alignments need to skip ahead
+        let preferred_base = if alignment > self.virtual_mapper.page_size {
+            Some(round_up_to(self.virtual_mapper.next_address, alignment)?)
+        } else {
+            None
+        };
+
+        self.allocate(data_size, true, gc_roots, preferred_base, None)
+    }
+
+    /// Frees every collectible allocation not reachable from `gc_roots` and compacts the heap.
+    /// Invalidates every heap offset computed before the call
     pub fn force_garbage_collection(&mut self, gc_roots: &[DataWord]) -> VoidResult {
+        self.garbage_collection_count += 1;
+        self.allocations_since_collection = 0;
+
         let mut collectible = HashSet::with_capacity(self.allocations.len());
         let mut visited = HashSet::with_capacity(self.allocations.len());
         let mut next: Vec<UWord> = gc_roots
@@ -171,11 +459,21 @@ impl Memory {
                 .for_each(|x| next.push(x))
         }
 
+        let mut freed: Vec<UWord> = collectible.iter().map(|id| **id).collect();
+        freed.sort_unstable();
+
         for id in collectible {
             //println!("LAKESIS | GC: Deallocating {}", id);
             self.deallocate(id)?;
         }
 
+        if let Some(collections) = &mut self.collections {
+            collections.push(CollectionReport {
+                freed,
+                survivors: self.allocations.len(),
+            });
+        }
+
         //println!("LAKESIS | GC: Compacting memory");
         self.regions.compact(&mut self.heap);
 
@@ -187,32 +485,174 @@ impl Memory {
         Ok(())
     }
 
+    /// Checks that the allocations, heap regions and virtual address blocks all agree with each other.
+    /// Meant for catching bookkeeping bugs in the allocator and garbage collector
+    pub fn verify(&self) -> VoidResult {
+        let mut expected_base = 0;
+        for id in &self.regions.in_order {
+            let region = self
+                .regions
+                .get(*id)
+                .ok_or_else(|| Error::new(&format!("Ordered region {} doesn't exist", id)))?;
+
+            if region.base != expected_base {
+                return Err(Error::new(&format!(
+                    "Region {} starts at {:08X}, but the previous region ends at {:08X}",
+                    id, region.base, expected_base
+                )));
+            }
+            expected_base = region.end();
+
+            if let HeapRegionState::Used(allocation_id) = region.state {
+                let allocation = self.allocations.get(allocation_id).ok_or_else(|| {
+                    Error::new(&format!(
+                        "Region {} is used by non-existent allocation {}",
+                        id, allocation_id
+                    ))
+                })?;
+
+                if allocation.region != *id {
+                    return Err(Error::new(&format!(
+                        "Region {} is used by allocation {}, which points to region {}",
+                        id, allocation_id, allocation.region
+                    )));
+                }
+            }
+        }
+
+        if expected_base != self.heap.len() {
+            return Err(Error::new(&format!(
+                "Regions end at {:08X}, but the heap ends at {:08X}",
+                expected_base,
+                self.heap.len()
+            )));
+        }
+
+        for allocation in self.allocations.iter() {
+            let region = self.regions.get(allocation.region).ok_or_else(|| {
+                Error::new(&format!(
+                    "Allocation {} points to non-existent region {}",
+                    allocation.id, allocation.region
+                ))
+            })?;
+
+            if region.state != HeapRegionState::Used(allocation.id)
+                || region.base != allocation.start
+                || region.length != total_region_len(allocation.data_length)
+            {
+                return Err(Error::new(&format!(
+                    "Allocation {} doesn't match its region {}",
+                    allocation.id, region
+                )));
+            }
+
+            let block = self
+                .virtual_mapper
+                .get(allocation.virtual_block)
+                .ok_or_else(|| {
+                    Error::new(&format!(
+                        "Allocation {} points to non-existent virtual block {}",
+                        allocation.id, allocation.virtual_block
+                    ))
+                })?;
+
+            if block.allocation != allocation.id || block.size != allocation.data_length as UWord {
+                return Err(Error::new(&format!(
+                    "Allocation {} doesn't match its virtual block {}",
+                    allocation.id, block.id
+                )));
+            }
+
+            if let Ok((translated, _)) = self.virtual_mapper.translate(block.base) {
+                if translated != allocation.id {
+                    return Err(Error::new(&format!(
+                        "Base address of allocation {} translates to allocation {}",
+                        allocation.id, translated
+                    )));
+                }
+            } else {
+                return Err(Error::new(&format!(
+                    "Base address of allocation {} isn't mapped",
+                    allocation.id
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn checksum(&self) -> u64 {
+        let mut sorted_allocations: Vec<&Allocation> = self.allocations.iter().collect();
+        sorted_allocations.sort_unstable_by_key(|x| {
+            self.virtual_mapper
+                .get(x.virtual_block)
+                .expect("Allocation pointed to non-existent virtual memory block")
+                .base
+        });
+
+        let mut hasher = DefaultHasher::new();
+        for allocation in sorted_allocations {
+            let block = self
+                .virtual_mapper
+                .get(allocation.virtual_block)
+                .expect("Allocation pointed to non-existent virtual memory block");
+
+            block.base.hash(&mut hasher);
+            self.heap[allocation.start..allocation.bitfield_end()].hash(&mut hasher);
+        }
+
+        hasher.finish()
+    }
+
     fn try_allocate_region(
         &mut self,
         data_size: UWord,
         allocation_id: AllocationId,
         gc_roots: &[DataWord],
     ) -> Result<(usize, HeapRegionId)> {
+        // No amount of collecting or growing the heap would make room for this, so don't report it as running out
+        let total_size = total_region_len(data_size as usize);
+        if total_size > MAX_MEMORY_SIZE {
+            return Err(Error::new(&format!(
+                "Allocation too large: requested {} ({} bytes, {} bytes with its reference bitfield), but the heap can be at most {} ({} bytes)",
+                human_readable_byte_size(data_size),
+                data_size,
+                total_size,
+                human_readable_byte_size(MAX_MEMORY_SIZE as u64),
+                MAX_MEMORY_SIZE
+            )));
+        }
+
+        self.allocations_since_collection += 1;
+        if let GcPolicy::Periodic { allocations } = self.gc_policy {
+            if self.allocations_since_collection >= allocations {
+                self.force_garbage_collection(gc_roots)?;
+            }
+        }
+
         match self.regions.allocate(data_size as usize, allocation_id) {
             HeapRegionAllocationResult::Success { base, id } => return Ok((base, id)),
-            HeapRegionAllocationResult::Error(e) => return Err(e),
             HeapRegionAllocationResult::OutOfMemory => {}
         };
 
         // Not enough space left, try to free up memory by running the GC
-        self.force_garbage_collection(gc_roots)?;
+        if self.gc_policy != GcPolicy::Never {
+            self.force_garbage_collection(gc_roots)?;
 
-        match self.regions.allocate(data_size as usize, allocation_id) {
-            HeapRegionAllocationResult::Success { base, id } => return Ok((base, id)),
-            HeapRegionAllocationResult::Error(e) => return Err(e),
-            HeapRegionAllocationResult::OutOfMemory => {}
-        };
+            match self.regions.allocate(data_size as usize, allocation_id) {
+                HeapRegionAllocationResult::Success { base, id } => return Ok((base, id)),
+                HeapRegionAllocationResult::OutOfMemory => {}
+            };
+        }
 
         // Still not enough space left, try to expand the heap
 
-        let minimum_required = self.regions.used_bytes() + total_region_len(data_size as usize);
+        let minimum_required = self
+            .regions
+            .used_bytes()
+            .saturating_add(total_region_len(data_size as usize));
         if minimum_required > MAX_MEMORY_SIZE {
-            self.report_out_of_memory(data_size);
+            self.report_out_of_memory(data_size)?;
             return Err(Error::new("Out of memory"));
         }
 
@@ -234,24 +674,26 @@ impl Memory {
 
         match self.regions.allocate(data_size as usize, allocation_id) {
             HeapRegionAllocationResult::Success { base, id } => Ok((base, id)),
-            HeapRegionAllocationResult::Error(e) => Err(e),
             HeapRegionAllocationResult::OutOfMemory => {
-                self.report_out_of_memory(data_size);
+                self.report_out_of_memory(data_size)?;
                 Err(Error::new("Out of memory"))
             }
         }
     }
 
-    fn report_out_of_memory(&self, data_size: UWord) {
+    fn report_out_of_memory(&mut self, data_size: UWord) -> VoidResult {
         let total_size = total_region_len(data_size as usize) as UWord;
-        println!(
-            "LAKESIS | Out of memory - Requested: Data {} ({} bytes) / Total {} ({} bytes)",
+        let report = format!(
+            "LAKESIS | Out of memory - Requested: Data {} ({} bytes) / Total {} ({} bytes)\n{}",
             human_readable_byte_size(data_size),
             data_size,
             human_readable_byte_size(total_size),
-            total_size
+            total_size,
+            self
         );
-        println!("{}", self);
+        writeln!(self.diagnostics.0, "{}", report)?;
+
+        Ok(())
     }
 
     fn deallocate(&mut self, id: AllocationId) -> VoidResult {
@@ -267,7 +709,7 @@ impl Memory {
     }
 
     fn ensure_aligned(addr: UWord) -> VoidResult {
-        if addr % WORD_BYTE_SIZE != 0 {
+        if !addr.is_multiple_of(WORD_BYTE_SIZE) {
             Err(Error::new(&format!(
                 "Address {:016X} isn't word-aligned",
                 addr
@@ -292,6 +734,7 @@ impl Memory {
         Ok((allocation, offset))
     }
 
+    /// Heap offsets of the `size` bytes starting at `addr`, only valid until the next allocation or collection
     fn addr_to_indices(&self, addr: UWord, size: UWord) -> Result<(usize, usize)> {
         let (allocation, offset) = self.addr_to_allocation(addr)?;
 
@@ -322,7 +765,7 @@ impl Memory {
     }
 
     fn addr_to_reference_indices(&self, addr: UWord) -> Result<(usize, usize, usize)> {
-        if addr % WORD_BYTE_SIZE != 0 {
+        if !addr.is_multiple_of(WORD_BYTE_SIZE) {
             return Err(Error::new("Address isn't byte-aligned"));
         }
 
@@ -336,7 +779,7 @@ impl Memory {
         ))
     }
 
-    fn addr_to_reference_ptr_mut(&mut self, addr: UWord) -> Result<BitRef<Mut, Lsb0, u8>> {
+    fn addr_to_reference_ptr_mut(&mut self, addr: UWord) -> Result<BitRef<'_, Mut, Lsb0, u8>> {
         let (start, end, offset) = self.addr_to_reference_indices(addr)?;
 
         let slice = &mut self.heap[start..end];
@@ -346,7 +789,7 @@ impl Memory {
             .expect("Unable to read reference bitfield"))
     }
 
-    fn addr_to_reference_ptr(&self, addr: UWord) -> Result<BitRef<Const, Lsb0, u8>> {
+    fn addr_to_reference_ptr(&self, addr: UWord) -> Result<BitRef<'_, Const, Lsb0, u8>> {
         let (start, end, offset) = self.addr_to_reference_indices(addr)?;
 
         let slice = &self.heap[start..end];
@@ -365,6 +808,25 @@ impl Memory {
     }
 }
 
+impl Debug for Diagnostics {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Diagnostics").finish_non_exhaustive()
+    }
+}
+
+impl Display for MemoryAccess {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let kind = if self.is_write { "W" } else { "R" };
+        write!(f, "{} {:08X} {:2} B |", kind, self.address, self.data.len())?;
+
+        for byte in &self.data {
+            write!(f, " {:02X}", byte)?;
+        }
+
+        Ok(())
+    }
+}
+
 impl Display for Memory {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         write!(f, "Allocations:")?;
@@ -409,14 +871,6 @@ impl Allocation {
     fn bitfield_end(&self) -> usize {
         self.bitfield_start() + self.bitfield_len()
     }
-
-    fn end(&self) -> usize {
-        self.bitfield_end()
-    }
-
-    fn length(&self) -> usize {
-        self.end() - self.start
-    }
 }
 
 impl Display for Allocation {
@@ -438,36 +892,6 @@ impl Display for Allocation {
     }
 }
 
-pub struct MemoryReader<'a> {
-    memory: &'a Memory,
-    base_addr: UWord,
-    addr: UWord,
-}
-
-impl MemoryReader<'_> {
-    fn new(memory: &Memory, addr: UWord) -> MemoryReader {
-        MemoryReader {
-            memory,
-            base_addr: addr,
-            addr,
-        }
-    }
-
-    pub fn offset(&self) -> UWord {
-        self.addr - self.base_addr
-    }
-}
-
-impl Read for MemoryReader<'_> {
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let read = self.memory.get(self.addr, buf.len() as UWord)?;
-        buf.copy_from_slice(read);
-
-        self.addr += buf.len() as UWord;
-        Ok(buf.len())
-    }
-}
-
 #[derive(PartialEq, Eq, Copy, Clone, Debug)]
 struct VirtualAddressMapping {
     block: VirtualAddressBlockId,
@@ -487,14 +911,26 @@ struct VirtualAddressMapper {
     blocks: IdHashMap<VirtualAddressBlock>,
     mappings: HashMap<UWord, VirtualAddressMapping>,
     next_address: UWord,
+    page_size: UWord,
+    /// Addresses from this one onwards are never mapped
+    address_limit: UWord,
 }
 
 impl VirtualAddressMapper {
     fn new() -> VirtualAddressMapper {
+        Self::with_limits(VIRTUAL_PAGE_SIZE, UWord::MAX)
+    }
+
+    /// Creates a mapper with a custom page size, which must not be 0, that never maps addresses at or after `address_limit`
+    fn with_limits(page_size: UWord, address_limit: UWord) -> VirtualAddressMapper {
+        assert!(page_size > 0, "Virtual pages can't be empty");
+
         VirtualAddressMapper {
             blocks: IdHashMap::new(),
             mappings: HashMap::new(),
             next_address: 0,
+            page_size,
+            address_limit,
         }
     }
 
@@ -505,7 +941,7 @@ impl VirtualAddressMapper {
         preferred_base: Option<UWord>,
     ) -> Result<(UWord, VirtualAddressBlockId)> {
         if let Some(base) = preferred_base {
-            if base % VIRTUAL_PAGE_SIZE != 0 {
+            if base % self.page_size != 0 {
                 return Err(Error::new(&format!(
                     "Requested base address {:08X} isn't page-aligned",
                     base
@@ -518,11 +954,27 @@ impl VirtualAddressMapper {
                     base
                 )));
             }
+        }
 
-            self.next_address = base;
+        let base_addr = preferred_base.unwrap_or(self.next_address);
+
+        // Make sure the address right after the last mapped page still fits, instead of wrapping around
+        // and handing out addresses that are already in use
+        let page_count = size / self.page_size + 1;
+        match page_count
+            .checked_mul(self.page_size)
+            .and_then(|x| x.checked_add(base_addr))
+        {
+            Some(end) if end <= self.address_limit => {}
+            _ => {
+                return Err(Error::new(&format!(
+                    "Virtual address space exhausted: unable to map {} bytes at {:08X}",
+                    size, base_addr
+                )))
+            }
         }
 
-        let base_addr = self.next_address;
+        self.next_address = base_addr;
 
         let block_id = self.blocks.insert(VirtualAddressBlock {
             id: Default::default(),
@@ -531,15 +983,15 @@ impl VirtualAddressMapper {
             allocation,
         });
 
-        for (page, addr) in Self::pages_of(base_addr, size) {
+        for (page, addr) in self.pages_of(base_addr, size) {
             self.mappings.insert(
                 addr,
                 VirtualAddressMapping {
                     block: block_id,
-                    offset: page as usize * VIRTUAL_PAGE_SIZE as usize,
+                    offset: page * self.page_size as usize,
                 },
             );
-            self.next_address += VIRTUAL_PAGE_SIZE;
+            self.next_address += self.page_size;
         }
 
         Ok((base_addr, block_id))
@@ -551,7 +1003,7 @@ impl VirtualAddressMapper {
             .remove(id)
             .ok_or_else(|| Error::new("Invalid virtual block ID"))?;
 
-        for (_, addr) in Self::pages_of(block.base, block.size) {
+        for (_, addr) in self.pages_of(block.base, block.size) {
             self.mappings.remove(&addr);
         }
 
@@ -563,7 +1015,7 @@ impl VirtualAddressMapper {
     }
 
     fn translate(&self, addr: UWord) -> Result<(AllocationId, usize)> {
-        let aligned_addr = round_down_to(addr, VIRTUAL_PAGE_SIZE);
+        let aligned_addr = round_down_to(addr, self.page_size);
         let alignment_offset = addr as usize - aligned_addr as usize;
 
         let mapping = self.mappings.get(&aligned_addr).ok_or_else(|| {
@@ -581,11 +1033,11 @@ impl VirtualAddressMapper {
         Ok((block.allocation, mapping.offset + alignment_offset))
     }
 
-    fn pages_of(base_addr: UWord, size: UWord) -> Vec<(usize, UWord)> {
-        let mut pages = Vec::with_capacity(size as usize / VIRTUAL_PAGE_SIZE as usize);
+    fn pages_of(&self, base_addr: UWord, size: UWord) -> Vec<(usize, UWord)> {
+        let mut pages = Vec::with_capacity(size as usize / self.page_size as usize);
 
-        for page in 0..=(size / VIRTUAL_PAGE_SIZE) {
-            pages.push((page as usize, base_addr + page * VIRTUAL_PAGE_SIZE));
+        for page in 0..=(size / self.page_size) {
+            pages.push((page as usize, base_addr + page * self.page_size));
         }
 
         pages
@@ -635,7 +1087,6 @@ struct HeapRegions {
 enum HeapRegionAllocationResult {
     Success { base: usize, id: HeapRegionId },
     OutOfMemory,
-    Error(Error),
 }
 
 impl HeapRegions {
@@ -674,7 +1125,7 @@ impl HeapRegions {
         data_size: usize,
         allocation: AllocationId,
     ) -> HeapRegionAllocationResult {
-        let total_size = total_region_len(data_size as usize);
+        let total_size = total_region_len(data_size);
 
         let (index, region) = match self
             .in_order
@@ -956,42 +1407,39 @@ impl Display for HeapRegionState {
     }
 }
 
-#[derive(Debug)]
+/// Backing storage for every allocation.
+///
+/// Borrow discipline: [Memory::allocate] can grow the heap, which may move the buffer,
+/// and [Memory::force_garbage_collection] compacts it, which moves allocations around inside it.
+/// Slices into the heap borrow [Memory], so the compiler already refuses to keep one across either call,
+/// but plain offsets (such as [Allocation::start] or the ones returned by `addr_to_indices`) aren't tracked.
+/// Always translate virtual addresses to offsets again after allocating or collecting, never cache them.
+///
+/// Performance: growing goes through the same allocator as the raw `realloc` this used to call,
+/// the only extra cost is `Vec::resize` zero-filling the new bytes. The heap grows by doubling,
+/// so that cost is amortized over the allocations that filled it.
 struct Heap {
-    ptr: *mut u8,
-    len: usize,
+    data: Vec<u8>,
 }
 
 impl Heap {
     fn new(size: usize) -> Heap {
-        let mut heap = Heap {
-            ptr: unsafe { ptr::null_mut() },
-            len: size,
-        };
-
-        heap.ptr = unsafe { alloc::alloc(heap.layout()) };
-
-        heap
-    }
-
-    fn layout(&self) -> Layout {
-        Layout::array::<u8>(self.len).unwrap()
+        Heap {
+            data: vec![0; size],
+        }
     }
 
     fn resize(&mut self, new_size: usize) {
-        self.ptr = unsafe { alloc::realloc(self.ptr, self.layout(), new_size) };
-        self.len = new_size;
+        self.data.resize(new_size, 0);
+        self.data.shrink_to_fit();
     }
 }
 
-impl Drop for Heap {
-    fn drop(&mut self) {
-        unsafe {
-            alloc::dealloc(self.ptr, self.layout());
-        };
-
-        self.ptr = ptr::null_mut();
-        self.len = 0;
+impl fmt::Debug for Heap {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Heap")
+            .field("len", &self.data.len())
+            .finish()
     }
 }
 
@@ -999,13 +1447,13 @@ impl Index<Range<usize>> for Heap {
     type Output = [u8];
 
     fn index(&self, index: Range<usize>) -> &Self::Output {
-        unsafe { slice::from_raw_parts(self.ptr.add(index.start), index.len()) }
+        &self.data[index]
     }
 }
 
 impl IndexMut<Range<usize>> for Heap {
     fn index_mut(&mut self, index: Range<usize>) -> &mut Self::Output {
-        unsafe { slice::from_raw_parts_mut(self.ptr.add(index.start), index.len()) }
+        &mut self.data[index]
     }
 }
 
@@ -1013,13 +1461,13 @@ impl Deref for Heap {
     type Target = [u8];
 
     fn deref(&self) -> &Self::Target {
-        unsafe { slice::from_raw_parts(self.ptr, self.len) }
+        &self.data
     }
 }
 
 impl DerefMut for Heap {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        unsafe { slice::from_raw_parts_mut(self.ptr, self.len) }
+        &mut self.data
     }
 }
 
@@ -1037,7 +1485,6 @@ trait IdWrapper: Hash + Eq + Copy + Default + Deref<Target = u64> + DerefMut {
 
 trait StructWithId {
     type Id: IdWrapper;
-    fn get_id(&self) -> Self::Id;
     fn set_id(&mut self, id: Self::Id);
 }
 
@@ -1055,9 +1502,6 @@ macro_rules! entity_id {
         impl StructWithId for $entity {
             type Id = $id_wrapper;
 
-            fn get_id(&self) -> Self::Id {
-                self.id
-            }
             fn set_id(&mut self, id: Self::Id) {
                 self.id = id
             }
@@ -1135,19 +1579,15 @@ where
         self.map.remove(&id)
     }
 
-    fn iter(&self) -> std::collections::hash_map::Values<T::Id, T> {
+    fn iter(&self) -> std::collections::hash_map::Values<'_, T::Id, T> {
         self.map.values()
     }
 
-    fn iter_mut(&mut self) -> std::collections::hash_map::ValuesMut<T::Id, T> {
+    fn iter_mut(&mut self) -> std::collections::hash_map::ValuesMut<'_, T::Id, T> {
         self.map.values_mut()
     }
 
-    fn id_iter(&self) -> std::collections::hash_map::Keys<T::Id, T> {
-        self.map.keys()
-    }
-
-    fn entry_iter(&self) -> std::collections::hash_map::Iter<T::Id, T> {
+    fn entry_iter(&self) -> std::collections::hash_map::Iter<'_, T::Id, T> {
         self.map.iter()
     }
 
@@ -1172,8 +1612,9 @@ fn bitfield_len(data_len: usize) -> usize {
     divide_round_up(data_len, WORD_BYTE_SIZE as usize * 8)
 }
 
+/// Saturates instead of overflowing, so huge sizes are still rejected as being bigger than the maximum memory size
 fn total_region_len(data_len: usize) -> usize {
-    data_len + bitfield_len(data_len)
+    data_len.saturating_add(bitfield_len(data_len))
 }
 
 fn round_down_to<T>(value: T, alignment: T) -> T
@@ -1183,20 +1624,376 @@ where
     (value / alignment) * alignment
 }
 
-fn round_up_to<T>(value: T, alignment: T) -> T
-where
-    T: Copy + Add<Output = T> + Sub<Output = T> + Div<Output = T> + Mul<Output = T> + From<u8>,
-{
-    divide_round_up(value, alignment) * alignment
+/// Rounds `value` up to a multiple of `alignment`, failing if the result doesn't fit in a word
+pub fn round_up_to(value: UWord, alignment: UWord) -> Result<UWord> {
+    divide_round_up(value, alignment)
+        .checked_mul(alignment)
+        .ok_or_else(|| {
+            Error::new(&format!(
+                "Unable to align {} bytes to {} bytes without overflowing",
+                value, alignment
+            ))
+        })
 }
 
+/// Divides and rounds up. Never overflows, unlike adding `divisor - 1` before dividing
 fn divide_round_up<T>(dividend: T, divisor: T) -> T
 where
-    T: Copy + Add<Output = T> + Sub<Output = T> + Div<Output = T> + From<u8>,
+    T: Copy + PartialEq + Add<Output = T> + Div<Output = T> + Rem<Output = T> + From<u8>,
 {
-    (dividend + divisor - 1.into()) / divisor
+    if dividend % divisor == 0.into() {
+        dividend / divisor
+    } else {
+        dividend / divisor + 1.into()
+    }
 }
 
 fn human_readable_byte_size(value: impl Into<u64>) -> String {
     ByteSize(value.into()).to_string_as(true)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mapping_past_the_address_limit_fails() {
+        let mut mapper = VirtualAddressMapper::with_limits(16, 64);
+        let allocation = AllocationId::default();
+
+        // Blocks always get one page more than their size needs, so these take 1 and 3 pages
+        assert_eq!(mapper.map(10, allocation, None).unwrap().0, 0);
+        assert_eq!(mapper.map(40, allocation, None).unwrap().0, 16);
+
+        let error = mapper.map(0, allocation, None).unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("Virtual address space exhausted"),
+            "{}",
+            error
+        );
+        assert!(mapper.translate(64).is_err());
+        assert_eq!(mapper.next_address, 64);
+    }
+
+    #[test]
+    fn mapping_near_the_end_of_the_address_space_does_not_wrap() {
+        let mut mapper = VirtualAddressMapper::new();
+        let base = round_down_to(UWord::MAX, VIRTUAL_PAGE_SIZE);
+
+        let error = mapper
+            .map(VIRTUAL_PAGE_SIZE, AllocationId::default(), Some(base))
+            .unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("Virtual address space exhausted"),
+            "{}",
+            error
+        );
+        assert!(mapper.translate(0).is_err());
+    }
+
+    /// Allocates a collectible node with `value` in its first word, followed by `child_slots` null references
+    fn allocate_node(memory: &mut Memory, value: UWord, child_slots: UWord) -> UWord {
+        let addr = memory
+            .allocate((1 + child_slots) * WORD_BYTE_SIZE, true, &[], None, None)
+            .unwrap();
+        memory
+            .set_data_word(addr, DataWord::new(value, false))
+            .unwrap();
+        addr
+    }
+
+    fn slot(addr: UWord, index: UWord) -> UWord {
+        addr + (1 + index) * WORD_BYTE_SIZE
+    }
+
+    fn link(memory: &mut Memory, slot_addr: UWord, target: UWord) {
+        memory
+            .set_data_word(slot_addr, DataWord::new(target, true))
+            .unwrap();
+    }
+
+    /// Follows a linked list of nodes with one child each, returning the value and address of every node
+    fn walk_list(memory: &Memory, head: DataWord) -> Vec<(UWord, UWord)> {
+        let mut nodes = Vec::new();
+        let mut next = head;
+        while next.is_reference() {
+            let addr = next.value();
+            nodes.push((memory.get_data_word(addr).unwrap().value(), addr));
+            next = memory.get_data_word(slot(addr, 0)).unwrap();
+        }
+
+        nodes
+    }
+
+    /// Checks that every node of a full binary tree holds its breadth-first index, returning how many nodes it has
+    fn check_tree(memory: &Memory, node: DataWord, index: UWord) -> UWord {
+        if !node.is_reference() {
+            return 0;
+        }
+
+        let value = memory.get_data_word(node.value()).unwrap();
+        assert_eq!(value, DataWord::new(index, false));
+
+        let left = memory.get_data_word(slot(node.value(), 0)).unwrap();
+        let right = memory.get_data_word(slot(node.value(), 1)).unwrap();
+        1 + check_tree(memory, left, index * 2 + 1) + check_tree(memory, right, index * 2 + 2)
+    }
+
+    #[test]
+    fn garbage_collection_keeps_reachable_graph_intact() {
+        const TREE_DEPTH: u32 = 7;
+
+        // A small heap makes allocations collect, compact and grow the heap while the graph is being built
+        let mut memory = Memory::new(4096);
+        memory.set_diagnostics(Box::new(io::sink()));
+
+        // Roots of the graph, which is never collected as it isn't collectible
+        let roots = memory
+            .allocate(3 * WORD_BYTE_SIZE, false, &[], None, Some("Roots"))
+            .unwrap();
+        let list_root = roots;
+        let tree_root = roots + WORD_BYTE_SIZE;
+        // Keeps a node alive while the node it will be linked to is being allocated
+        let scratch_root = roots + 2 * WORD_BYTE_SIZE;
+
+        let mut garbage = Vec::new();
+        let mut expected_list: Vec<UWord> = Vec::new();
+        let mut next_value = 0;
+
+        // Built top-down, so every node is reachable before its children are allocated
+        let root_node = allocate_node(&mut memory, 0, 2);
+        link(&mut memory, tree_root, root_node);
+        let mut pending = vec![(root_node, 0)];
+        while let Some((parent, index)) = pending.pop() {
+            if index >= (1 << TREE_DEPTH) - 1 {
+                continue;
+            }
+
+            for child in 0..2 {
+                let child_index = index * 2 + 1 + child;
+                let node = allocate_node(&mut memory, child_index, 2);
+                link(&mut memory, slot(parent, child), node);
+                pending.push((node, child_index));
+
+                garbage.push(allocate_node(&mut memory, UWord::MAX, 2));
+            }
+        }
+
+        for round in 0..5 {
+            for _ in 0..100 {
+                let head = memory.get_data_word(list_root).unwrap();
+                let node = allocate_node(&mut memory, next_value, 1);
+                memory.set_data_word(slot(node, 0), head).unwrap();
+                link(&mut memory, list_root, node);
+                expected_list.insert(0, next_value);
+                next_value += 1;
+
+                // Cycles that nothing points to must be freed as well
+                let first = allocate_node(&mut memory, UWord::MAX, 1);
+                link(&mut memory, scratch_root, first);
+                let second = allocate_node(&mut memory, UWord::MAX, 1);
+                link(&mut memory, slot(first, 0), second);
+                link(&mut memory, slot(second, 0), first);
+                memory
+                    .set_data_word(scratch_root, DataWord::new(0, false))
+                    .unwrap();
+                garbage.push(first);
+                garbage.push(second);
+            }
+
+            // Drop the end of the list, which turns those nodes into garbage too
+            let nodes = walk_list(&memory, memory.get_data_word(list_root).unwrap());
+            let keep = nodes.len() - 20 * (round + 1);
+            memory
+                .set_data_word(slot(nodes[keep - 1].1, 0), DataWord::new(0, false))
+                .unwrap();
+            garbage.extend(nodes[keep..].iter().map(|(_, addr)| *addr));
+            expected_list.truncate(keep);
+
+            for _ in 0..3 {
+                memory.force_garbage_collection(&[]).unwrap();
+                memory.verify().unwrap();
+
+                let list = walk_list(&memory, memory.get_data_word(list_root).unwrap());
+                let values: Vec<UWord> = list.iter().map(|(value, _)| *value).collect();
+                assert_eq!(values, expected_list);
+
+                let tree = memory.get_data_word(tree_root).unwrap();
+                assert_eq!(check_tree(&memory, tree, 0), (1 << (TREE_DEPTH + 1)) - 1);
+
+                for addr in &garbage {
+                    assert!(
+                        memory.get_data_word(*addr).is_err(),
+                        "{:08X} wasn't freed",
+                        addr
+                    );
+                }
+            }
+        }
+
+        assert!(memory.garbage_collection_count() > 15);
+    }
+
+    #[test]
+    fn large_fill_clears_every_reference_bit_it_touches() {
+        const WORDS: UWord = 128 * 1024;
+
+        let mut memory = Memory::new(4096);
+        memory.set_diagnostics(Box::new(io::sink()));
+        let base = memory
+            .allocate(WORDS * WORD_BYTE_SIZE, false, &[], None, None)
+            .unwrap();
+        for word in 0..WORDS {
+            link(&mut memory, base + word * WORD_BYTE_SIZE, base);
+        }
+
+        // Starts and ends in the middle of a word, so the first and last words are only partially overwritten
+        let start = base + WORD_BYTE_SIZE + 3;
+        let end = base + (WORDS - 1) * WORD_BYTE_SIZE - 3;
+        memory.fill(start, end - start, 0xAB).unwrap();
+
+        assert!(memory
+            .fetch(start, end - start)
+            .unwrap()
+            .iter()
+            .all(|x| *x == 0xAB));
+        assert!(memory.is_reference(base).unwrap());
+        assert!(memory
+            .is_reference(base + (WORDS - 1) * WORD_BYTE_SIZE)
+            .unwrap());
+        for word in 1..WORDS - 1 {
+            assert!(
+                !memory.is_reference(base + word * WORD_BYTE_SIZE).unwrap(),
+                "Word {} is still a reference",
+                word
+            );
+        }
+    }
+
+    #[test]
+    fn resizing_the_heap_keeps_its_contents() {
+        let mut heap = Heap::new(16);
+        for (i, byte) in heap.iter_mut().enumerate() {
+            *byte = i as u8 + 1;
+        }
+        let original = heap.to_vec();
+
+        heap.resize(64);
+        assert_eq!(heap.len(), 64);
+        assert_eq!(&heap[0..16], original.as_slice());
+        assert!(heap[16..64].iter().all(|x| *x == 0));
+
+        heap.resize(8);
+        assert_eq!(&*heap, &original[..8]);
+    }
+
+    #[test]
+    fn freed_heap_regions_are_reused() {
+        fn allocate(regions: &mut HeapRegions, allocation: AllocationId) -> (usize, HeapRegionId) {
+            match regions.allocate(64, allocation) {
+                HeapRegionAllocationResult::Success { base, id } => (base, id),
+                HeapRegionAllocationResult::OutOfMemory => panic!("Out of memory"),
+            }
+        }
+
+        let first = AllocationId::first();
+        let second = first.next();
+        let third = second.next();
+
+        let mut regions = HeapRegions::new(1024);
+        let (first_base, first_region) = allocate(&mut regions, first);
+        let (second_base, _) = allocate(&mut regions, second);
+        assert!(second_base > first_base);
+
+        regions.deallocate(first_region).unwrap();
+        let (third_base, _) = allocate(&mut regions, third);
+        assert_eq!(third_base, first_base);
+        assert_eq!(regions.used_bytes(), 2 * total_region_len(64));
+    }
+
+    /// Small enough to also run under miri, which would catch any access through a stale heap pointer
+    #[test]
+    fn data_survives_the_heap_moving_during_allocation_and_collection() {
+        let mut memory = Memory::new(64);
+        memory.set_diagnostics(Box::new(io::sink()));
+
+        // Collecting the garbage in front of the kept allocation makes compaction move it
+        memory.allocate(8, true, &[], None, None).unwrap();
+        let kept = memory.allocate(16, true, &[], None, None).unwrap();
+        memory.set(kept, b"lakesis heap 123").unwrap();
+        // An owned copy, the slice returned by `get` can't be held across the calls below
+        let expected = memory.get(kept, 16).unwrap().to_vec();
+        let roots = [DataWord::new(kept, true)];
+
+        let start_before = memory.addr_to_allocation(kept).unwrap().0.start;
+        memory.force_garbage_collection(&roots).unwrap();
+        assert!(memory.addr_to_allocation(kept).unwrap().0.start < start_before);
+
+        // Too big for the current heap, so it has to grow
+        let len_before = memory.heap.len();
+        memory.allocate(256, true, &roots, None, None).unwrap();
+        assert!(memory.heap.len() > len_before);
+
+        assert_eq!(memory.get(kept, 16).unwrap(), expected.as_slice());
+        memory.verify().unwrap();
+    }
+
+    #[test]
+    fn read_bytes_copies_the_same_bytes_as_get() {
+        let mut memory = Memory::new(4096);
+        let base = memory.allocate(32, false, &[], None, None).unwrap();
+        let data: Vec<u8> = (1..=32).collect();
+        memory.set(base, &data).unwrap();
+
+        // Starts and ends in the middle of a word
+        let (addr, size) = (base + 3, 21);
+        let owned = memory.read_bytes(addr, size).unwrap();
+        assert_eq!(owned.as_slice(), memory.get(addr, size).unwrap());
+        assert_eq!(owned.as_slice(), &data[3..24]);
+    }
+
+    #[test]
+    fn collection_report_lists_exactly_the_freed_allocations() {
+        let mut memory = Memory::new(4096);
+        memory.set_recording_collections(true);
+
+        let root = allocate_node(&mut memory, 1, 1);
+        let child = allocate_node(&mut memory, 2, 0);
+        link(&mut memory, slot(root, 0), child);
+        let garbage = [
+            allocate_node(&mut memory, 3, 0),
+            allocate_node(&mut memory, 4, 0),
+        ];
+
+        let mut garbage_ids: Vec<UWord> = garbage
+            .iter()
+            .map(|&addr| memory.allocation_id(addr).unwrap())
+            .collect();
+        garbage_ids.sort_unstable();
+
+        memory
+            .force_garbage_collection(&[DataWord::new(root, true)])
+            .unwrap();
+
+        let reports = memory.take_collection_reports();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].freed, garbage_ids);
+        assert_eq!(reports[0].survivors, 2);
+        assert_eq!(memory.get_data_word(root).unwrap(), DataWord::new(1, false));
+        assert_eq!(
+            memory.get_data_word(slot(root, 0)).unwrap(),
+            DataWord::new(child, true)
+        );
+        assert_eq!(
+            memory.get_data_word(child).unwrap(),
+            DataWord::new(2, false)
+        );
+        for addr in garbage {
+            assert!(memory.get_data_word(addr).is_err());
+        }
+    }
+}
@@ -1,11 +1,20 @@
-use crate::core::{Error, IWord, Result, UWord, VoidResult, REGISTER_NUM, WORD_BYTE_SIZE};
+use crate::core::{
+    Error, IWord, RegisterIndex, Result, UWord, VoidResult, INITIAL_MEMORY_SIZE, MAX_MEMORY_SIZE,
+    REGISTER_NUM, WORD_BYTE_SIZE,
+};
+use crate::executable::{Executable, PreloadSection};
 use crate::opcodes::{Instruction, Opcode, Operand};
-use bytesize;
 use memory::Memory;
-use std::fmt::{Display, Formatter, UpperHex};
-use std::io::{self, Read};
+pub use memory::{CollectionReport, MemoryAccess, WatchpointHit};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::{self, Debug, Display, Formatter, UpperHex};
+use std::io::{self, Read, Write};
 use std::num::Wrapping;
 use std::ops::{Add, BitAnd, BitOr, BitXor, Div, Mul, Not, Shl, Shr, Sub};
+use std::rc::Rc;
 use std::thread;
 use std::time::Duration;
 
@@ -30,29 +39,257 @@ struct CpuState {
     zero_flag: bool,
 }
 
+/// Settings that change how a program is run
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct RunOptions {
+    /// Size in bytes of the heap when the machine starts.
+    /// Programs that allocate heavily can start with a bigger heap to avoid early garbage collections
+    /// and heap expansions. Must be between 1 and `MAX_MEMORY_SIZE`.
+    pub initial_heap: usize,
+    /// If set, the machine keeps a count of simulated cycles using this model
+    pub cycle_model: Option<CycleModel>,
+    /// Record every memory read and write done by the program, retrievable with `Machine::take_memory_trace`.
+    /// Instruction fetches are not recorded.
+    pub trace_memory: bool,
+    /// Record what every garbage collection freed, retrievable with `Machine::take_collection_reports`
+    pub record_collections: bool,
+    /// Seed for the numbers generated by the random native, to make runs reproducible.
+    /// If not set, the generator is seeded from system entropy
+    pub seed: Option<u64>,
+    /// Make shifts by 64 bits or more produce 0, instead of shifting by the amount modulo 64
+    pub zero_wide_shifts: bool,
+    /// Allow reading and writing words at addresses that aren't a multiple of the word size, instead of failing.
+    /// Unaligned words are always regular data, storing a reference in one still fails
+    pub allow_unaligned: bool,
+    /// Extra native functions provided by the host, added with `register_native`
+    pub host_natives: HostNatives,
+    /// When the garbage collector runs on its own, apart from explicit `gc` instructions
+    pub gc_policy: GcPolicy,
+    /// Discard diagnostics, such as the memory report when running out of memory and the CPU state at `brk` instructions.
+    /// The program's own output and any errors are unaffected. Diagnostics can also be redirected with `set_diagnostics`
+    pub quiet: bool,
+}
+
+/// When allocations automatically trigger a garbage collection
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum GcPolicy {
+    /// Collect only when there isn't enough free space on the heap for an allocation
+    OnPressure,
+    /// Collect before every `allocations`-th allocation, and also when out of space like `OnPressure`.
+    /// Keeps the heap small at the cost of more frequent collections. `allocations` must be at least 1
+    Periodic { allocations: u64 },
+    /// Never collect automatically, growing the heap whenever it runs out of space
+    Never,
+}
+
+/// A native function implemented by the program embedding the machine
+pub type HostNative = Box<dyn FnMut(&mut Machine) -> VoidResult>;
+
+/// First ID that can be used by natives added with `RunOptions::register_native`.
+/// Lower IDs are reserved for the built-in natives
+pub const FIRST_HOST_NATIVE_ID: UWord = 100;
+
+/// Native functions provided by the host, by ID.
+/// Cloning only copies the handles, so the same functions are shared by every machine loaded with the same options
+#[derive(Clone, Default)]
+pub struct HostNatives(HashMap<UWord, Rc<RefCell<HostNative>>>);
+
+/// Copy of the CPU registers and flags at a point in time, for debuggers and other tools that inspect a machine.
+/// Doesn't include memory. Serializable with the `serde` feature
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CpuSnapshot {
+    /// Value of every register and whether it's marked as a reference
+    pub registers: [(UWord, bool); REGISTER_NUM],
+    pub instruction_pointer: UWord,
+    pub stack_pointer: UWord,
+    pub carry_flag: bool,
+    pub zero_flag: bool,
+}
+
+/// Simulated cost in cycles of each instruction.
+/// Starts out with the default costs from the instruction descriptors, which can be overridden per instruction.
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct CycleModel {
+    overrides: HashMap<Instruction, u64>,
+}
+
+impl Default for RunOptions {
+    fn default() -> Self {
+        RunOptions {
+            initial_heap: INITIAL_MEMORY_SIZE,
+            cycle_model: None,
+            trace_memory: false,
+            record_collections: false,
+            seed: None,
+            zero_wide_shifts: false,
+            allow_unaligned: false,
+            host_natives: HostNatives::default(),
+            gc_policy: GcPolicy::OnPressure,
+            quiet: false,
+        }
+    }
+}
+
+impl RunOptions {
+    /// Makes `native id` call `native` instead of a built-in native function.
+    /// `id` must be at least `FIRST_HOST_NATIVE_ID`
+    pub fn register_native(&mut self, id: UWord, native: HostNative) -> VoidResult {
+        if id < FIRST_HOST_NATIVE_ID {
+            return Err(Error::new(&format!(
+                "Native ID {} is reserved for built-in natives, host natives must use IDs starting at {}",
+                id, FIRST_HOST_NATIVE_ID
+            )));
+        }
+
+        self.host_natives
+            .0
+            .insert(id, Rc::new(RefCell::new(native)));
+        Ok(())
+    }
+}
+
+impl PartialEq for HostNatives {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.len() == other.0.len()
+            && self
+                .0
+                .iter()
+                .all(|(id, native)| other.0.get(id).is_some_and(|x| Rc::ptr_eq(x, native)))
+    }
+}
+
+impl Eq for HostNatives {}
+
+impl Debug for HostNatives {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let mut ids: Vec<&UWord> = self.0.keys().collect();
+        ids.sort_unstable();
+        f.debug_tuple("HostNatives").field(&ids).finish()
+    }
+}
+
+impl CycleModel {
+    /// Number of cycles it takes to execute `instruction`
+    pub fn cost(&self, instruction: Instruction) -> u64 {
+        self.overrides
+            .get(&instruction)
+            .copied()
+            .unwrap_or_else(|| instruction.descriptor().cycles)
+    }
+
+    /// Changes the number of cycles it takes to execute `instruction`
+    pub fn set_cost(&mut self, instruction: Instruction, cycles: u64) {
+        self.overrides.insert(instruction, cycles);
+    }
+}
+
+/// A function implemented by the host that programs can call with the `native` instruction
+#[derive(Clone, Copy)]
+pub struct NativeFunction {
+    /// Number passed to the `native` instruction to call this function
+    pub id: UWord,
+    pub name: &'static str,
+    pub description: &'static str,
+    handler: fn(&mut Machine) -> VoidResult,
+}
+
+/// Every native function, in ascending ID order
+const NATIVE_FUNCTIONS: &[NativeFunction] = &[
+    NativeFunction {
+        id: 0,
+        name: "print",
+        description: "Prints a format string with length [SP+1w] and reference [SP+2w], filled in with the arguments after it",
+        handler: Machine::native_print,
+    },
+    NativeFunction {
+        id: 1,
+        name: "random",
+        description: "Stores a random number in R0",
+        handler: Machine::native_random,
+    },
+    NativeFunction {
+        id: 2,
+        name: "sleep",
+        description: "Sleeps for [SP+1w] milliseconds",
+        handler: Machine::native_sleep,
+    },
+    NativeFunction {
+        id: 3,
+        name: "fill",
+        description: "Sets [SP+2w] bytes starting at reference [SP+1w] to the lowest byte of [SP+3w]",
+        handler: Machine::native_fill,
+    },
+    NativeFunction {
+        id: 4,
+        name: "read",
+        description: "Reads up to [SP+2w] bytes of input into reference [SP+1w] and stores how many were read in R0",
+        handler: Machine::native_read,
+    },
+];
+
+/// A virtual machine with a program loaded into its memory
 #[derive(Debug)]
-struct Interpreter {
+pub struct Machine {
     cpu_state: CpuState,
     memory: Memory,
+    program_length: UWord,
+    breakpoints: BTreeSet<UWord>,
+    instructions_executed: u64,
+    cycle_model: Option<CycleModel>,
+    cycles: u64,
+    rng: StdRng,
+    zero_wide_shifts: bool,
+    host_natives: HostNatives,
+    io: HostIo,
+}
+
+/// Streams that natives read input from and that natives and debug instructions write output to,
+/// stdin and stdout unless redirected
+struct HostIo {
+    input: Box<dyn Read>,
+    output: Box<dyn Write>,
+}
+
+impl Default for HostIo {
+    fn default() -> Self {
+        HostIo {
+            input: Box::new(io::stdin()),
+            output: Box::new(io::stdout()),
+        }
+    }
 }
 
-struct InterpreterInstructionPointerReader<'a> {
+impl Debug for HostIo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HostIo").finish_non_exhaustive()
+    }
+}
+
+struct MachineInstructionPointerReader<'a> {
     memory: &'a Memory,
     cpu_state: &'a mut CpuState,
 }
 
 impl<T> DataValue<T> {
-    pub fn expect_reference(self) -> Result<T> {
-        if !self.is_reference {
-            Err(Error::new("Expected a reference, but found data"))
-        } else {
-            Ok(self.value)
+    pub fn new(value: T, is_reference: bool) -> DataValue<T> {
+        DataValue {
+            value,
+            is_reference,
         }
     }
 
-    pub fn expect_data(self) -> Result<T> {
-        if self.is_reference {
-            Err(Error::new("Expected data, but found a reference"))
+    pub fn value(self) -> T {
+        self.value
+    }
+
+    pub fn is_reference(&self) -> bool {
+        self.is_reference
+    }
+
+    pub fn expect_reference(self) -> Result<T> {
+        if !self.is_reference {
+            Err(Error::new("Expected a reference, but found data"))
         } else {
             Ok(self.value)
         }
@@ -218,12 +455,45 @@ impl DataWord {
         self.overflowing_operation(other, UWord::overflowing_div)
     }
 
+    /// Divides both values as two's complement signed numbers.
+    /// The carry is set if the result doesn't fit, which only happens when dividing the smallest number by -1
+    pub fn overflowing_signed_div(self, other: DataWord) -> (DataWord, bool) {
+        self.overflowing_operation(other, |a, b| {
+            let (result, overflow) = (a as IWord).overflowing_div(b as IWord);
+            (result as UWord, overflow)
+        })
+    }
+
+    /// Shifts left by `other` modulo 64, so shifting by 64 leaves the value unchanged.
+    /// The carry is set if `other` is 64 or more
     pub fn overflowing_shl(self, other: DataWord) -> (DataWord, bool) {
-        self.overflowing_operation(other, |a, b| a.overflowing_shl(b as u32))
+        self.overflowing_operation(other, |a, b| {
+            (a << (b % UWord::BITS as UWord), b >= UWord::BITS as UWord)
+        })
     }
 
+    /// Shifts right by `other` modulo 64, so shifting by 64 leaves the value unchanged.
+    /// The carry is set if `other` is 64 or more
     pub fn overflowing_shr(self, other: DataWord) -> (DataWord, bool) {
-        self.overflowing_operation(other, |a, b| a.overflowing_shr(b as u32))
+        self.overflowing_operation(other, |a, b| {
+            (a >> (b % UWord::BITS as UWord), b >= UWord::BITS as UWord)
+        })
+    }
+
+    /// Like `overflowing_shl`, but shifting by 64 or more produces 0
+    pub fn zeroing_shl(self, other: DataWord) -> (DataWord, bool) {
+        self.overflowing_operation(other, |a, b| match b {
+            b if b >= UWord::BITS as UWord => (0, true),
+            b => (a << b, false),
+        })
+    }
+
+    /// Like `overflowing_shr`, but shifting by 64 or more produces 0
+    pub fn zeroing_shr(self, other: DataWord) -> (DataWord, bool) {
+        self.overflowing_operation(other, |a, b| match b {
+            b if b >= UWord::BITS as UWord => (0, true),
+            b => (a >> b, false),
+        })
     }
 }
 
@@ -253,17 +523,381 @@ where
     }
 }
 
-impl Interpreter {
-    fn ip_reader(&mut self) -> InterpreterInstructionPointerReader {
-        InterpreterInstructionPointerReader {
+impl Machine {
+    /// Creates a new machine with the program read from `reader` loaded at address 0
+    pub fn load(reader: &mut impl Read) -> Result<Machine> {
+        Self::load_with_options(reader, &RunOptions::default())
+    }
+
+    /// Like `load`, but with custom run options
+    pub fn load_with_options(reader: &mut impl Read, options: &RunOptions) -> Result<Machine> {
+        if options.initial_heap == 0 || options.initial_heap > MAX_MEMORY_SIZE {
+            return Err(Error::new(&format!(
+                "Initial heap size must be between 1 and {} bytes, got {}",
+                MAX_MEMORY_SIZE, options.initial_heap
+            )));
+        }
+
+        if options.gc_policy == (GcPolicy::Periodic { allocations: 0 }) {
+            return Err(Error::new(
+                "Periodic garbage collection must happen every 1 or more allocations",
+            ));
+        }
+
+        let executable = Executable::read(reader)?;
+        let program_data = executable.program;
+
+        let mut machine = Machine {
+            cpu_state: CpuState::default(),
+            memory: Memory::new(options.initial_heap),
+            program_length: program_data.len() as UWord,
+            breakpoints: BTreeSet::new(),
+            instructions_executed: 0,
+            cycle_model: options.cycle_model.clone(),
+            cycles: 0,
+            rng: match options.seed {
+                Some(seed) => StdRng::seed_from_u64(seed),
+                None => StdRng::from_entropy(),
+            },
+            zero_wide_shifts: options.zero_wide_shifts,
+            host_natives: options.host_natives.clone(),
+            io: HostIo::default(),
+        };
+
+        // Allocations are made in whole words, so the program is padded with zeroes up to a word boundary.
+        // The padding is never executed, see `step`
+        let aligned_len = memory::round_up_to(program_data.len() as UWord, WORD_BYTE_SIZE)?;
+
+        if machine
+            .memory
+            .allocate(aligned_len, false, &[], Some(0), Some("Program"))?
+            != 0
+        {
+            return Err(Error::new("Unable to allocate program data at address 0"));
+        }
+
+        machine.memory.set(0, &program_data)?;
+
+        let mut preloads = executable.preloads;
+        preloads.sort_by_key(|x| x.address);
+        for preload in &preloads {
+            machine.load_preload(preload)?;
+        }
+
+        let stack_base = machine
+            .memory
+            .allocate(STACK_SIZE, false, &[], None, Some("Stack"))?;
+        machine.cpu_state.stack_pointer =
+            Wrapping(stack_base) + Wrapping(STACK_SIZE) - Wrapping(WORD_BYTE_SIZE);
+
+        // Only start tracing now so loading the program doesn't show up in the trace
+        machine.memory.set_tracing(options.trace_memory);
+        machine
+            .memory
+            .set_recording_collections(options.record_collections);
+        // Same for the GC policy, so periodic collections count allocations made by the program only
+        machine.memory.set_gc_policy(options.gc_policy);
+        machine.memory.set_allow_unaligned(options.allow_unaligned);
+        if options.quiet {
+            machine.set_diagnostics(Box::new(io::sink()));
+        }
+
+        Ok(machine)
+    }
+
+    /// Places data from the executable at its requested address.
+    /// Must be called before anything else is allocated, as addresses can only be requested in ascending order
+    fn load_preload(&mut self, preload: &PreloadSection) -> VoidResult {
+        let aligned_len = memory::round_up_to(preload.data.len() as UWord, WORD_BYTE_SIZE)?;
+
+        let addr = self
+            .memory
+            .allocate(
+                aligned_len,
+                false,
+                &[],
+                Some(preload.address),
+                Some(&preload.name),
+            )
+            .map_err(|e| {
+                Error::new(&format!(
+                    "Unable to load data section {} at {:016X}: {}",
+                    preload.name, preload.address, e
+                ))
+            })?;
+
+        self.memory.set(addr, &preload.data)
+    }
+
+    /// Executes instructions until the program halts.
+    /// There is no debugger to stop at `brk` instructions, so the CPU state is written to the diagnostics instead
+    pub fn run(&mut self) -> VoidResult {
+        //println!("LAKESIS | {}", self);
+
+        loop {
+            if self.is_at_guest_breakpoint() {
+                let report = format!("BREAKPOINT | {}", self);
+                writeln!(self.memory.diagnostics(), "{}", report)?;
+            }
+
+            if !self.step()? {
+                break;
+            }
+            //println!("LAKESIS | {}", self);
+        }
+
+        Ok(())
+    }
+
+    /// Executes instructions until the program halts, the instruction pointer reaches a breakpoint or a `brk`
+    /// instruction, or an instruction writes to a watchpoint.
+    /// At least one instruction is always executed, so this can be called again to resume from a breakpoint.
+    /// Returns false if the program has halted
+    pub fn run_to_breakpoint(&mut self) -> Result<bool> {
+        loop {
+            let watchpoint_hits = self.memory.pending_watchpoint_hits();
+            if !self.step()? {
+                return Ok(false);
+            }
+
+            if self.is_at_breakpoint() || self.memory.pending_watchpoint_hits() > watchpoint_hits {
+                return Ok(true);
+            }
+        }
+    }
+
+    /// Checks if the instruction pointer is at a breakpoint added by the host or at a `brk` instruction
+    fn is_at_breakpoint(&self) -> bool {
+        self.breakpoints
+            .contains(&self.cpu_state.instruction_pointer.0)
+            || self.is_at_guest_breakpoint()
+    }
+
+    /// Checks if the next instruction is a `brk`, without decoding the whole instruction
+    pub fn is_at_guest_breakpoint(&self) -> bool {
+        let ip = self.cpu_state.instruction_pointer.0;
+        if ip >= self.program_length {
+            return false;
+        }
+
+        match self.memory.fetch(ip, 1) {
+            Ok(bytes) => bytes[0] & Instruction::MASK == Instruction::Breakpoint as u8,
+            Err(_) => false,
+        }
+    }
+
+    /// Executes a single instruction like `step`, except that a `call` is executed along with its whole subroutine,
+    /// stopping at the instruction right after the `call` once it returns.
+    /// Stops early if the subroutine reaches a breakpoint. Returns false if the program has halted
+    pub fn step_over(&mut self) -> Result<bool> {
+        let call_ip = self.cpu_state.instruction_pointer;
+        let is_call = matches!(
+            Opcode::decode(&mut self.ip_reader()),
+            Ok(Opcode {
+                instruction: Instruction::Call,
+                ..
+            })
+        );
+        let return_ip = self.cpu_state.instruction_pointer.0;
+        self.cpu_state.instruction_pointer = call_ip;
+
+        if !is_call {
+            return self.step();
+        }
+
+        // Recursive calls return to the same address, so also wait for the stack to unwind back to where it was
+        let stack_pointer = self.cpu_state.stack_pointer.0;
+        while self.step()? {
+            let ip = self.cpu_state.instruction_pointer.0;
+            if ip == return_ip && self.cpu_state.stack_pointer.0 >= stack_pointer {
+                return Ok(true);
+            }
+
+            if self.is_at_breakpoint() {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Makes `run_to_breakpoint` stop before executing the instruction at `addr`
+    pub fn add_breakpoint(&mut self, addr: UWord) {
+        self.breakpoints.insert(addr);
+    }
+
+    /// Removes a breakpoint added with `add_breakpoint`. Returns false if there was no breakpoint at `addr`
+    pub fn remove_breakpoint(&mut self, addr: UWord) -> bool {
+        self.breakpoints.remove(&addr)
+    }
+
+    /// Addresses of all active breakpoints, in ascending order
+    pub fn breakpoints(&self) -> Vec<UWord> {
+        self.breakpoints.iter().copied().collect()
+    }
+
+    /// Redirects where natives such as print, and debug instructions such as debugcpu, write their output to.
+    /// Defaults to stdout
+    pub fn set_output(&mut self, output: Box<dyn Write>) {
+        self.io.output = output;
+    }
+
+    /// Redirects where natives such as read get their input from. Defaults to stdin
+    pub fn set_input(&mut self, input: Box<dyn Read>) {
+        self.io.input = input;
+    }
+
+    /// Redirects where diagnostics, such as the memory report when running out of memory and the CPU state at
+    /// `brk` instructions, are written to. Defaults to stdout
+    pub fn set_diagnostics(&mut self, diagnostics: Box<dyn Write>) {
+        self.memory.set_diagnostics(diagnostics);
+    }
+
+    /// Makes `run_to_breakpoint` stop right after any instruction writes to the word at `addr`.
+    /// The writes can be retrieved with `take_watchpoint_hits`
+    pub fn add_watchpoint(&mut self, addr: UWord) -> VoidResult {
+        self.memory.add_watchpoint(addr)
+    }
+
+    /// Removes a watchpoint added with `add_watchpoint`. Returns false if there was no watchpoint at `addr`
+    pub fn remove_watchpoint(&mut self, addr: UWord) -> bool {
+        self.memory.remove_watchpoint(addr)
+    }
+
+    /// Addresses of all active watchpoints, in ascending order
+    pub fn watchpoints(&self) -> Vec<UWord> {
+        self.memory.watchpoints()
+    }
+
+    /// Returns every write to a watched word since the last call, with the values before and after the write
+    pub fn take_watchpoint_hits(&mut self) -> Vec<WatchpointHit> {
+        self.memory.take_watchpoint_hits()
+    }
+
+    /// Reads a register directly, for host inspection only (e.g. debuggers).
+    /// Unlike instructions, this never touches the CPU flags
+    pub fn read_register_raw(&self, index: RegisterIndex) -> Result<DataWord> {
+        self.cpu_state
+            .registers
+            .get(index as usize)
+            .copied()
+            .ok_or_else(|| Error::new(&format!("Invalid register index {}", index)))
+    }
+
+    /// Writes a register directly, for host inspection only (e.g. debuggers).
+    /// Unlike instructions, this never touches the CPU flags
+    pub fn write_register_raw(&mut self, index: RegisterIndex, value: DataWord) -> VoidResult {
+        let register = self
+            .cpu_state
+            .registers
+            .get_mut(index as usize)
+            .ok_or_else(|| Error::new(&format!("Invalid register index {}", index)))?;
+
+        *register = value;
+        Ok(())
+    }
+
+    /// Copies the current registers and flags, so they can be inspected or stored without holding on to the machine
+    pub fn cpu_snapshot(&self) -> CpuSnapshot {
+        let state = &self.cpu_state;
+        CpuSnapshot {
+            registers: state.registers.map(|x| (x.value, x.is_reference)),
+            instruction_pointer: state.instruction_pointer.0,
+            stack_pointer: state.stack_pointer.0,
+            carry_flag: state.carry_flag,
+            zero_flag: state.zero_flag,
+        }
+    }
+
+    pub fn carry_flag(&self) -> bool {
+        self.cpu_state.carry_flag
+    }
+
+    pub fn zero_flag(&self) -> bool {
+        self.cpu_state.zero_flag
+    }
+
+    /// Number of instructions executed since the program was loaded
+    pub fn instructions_executed(&self) -> u64 {
+        self.instructions_executed
+    }
+
+    /// Number of simulated cycles spent so far, or None if no cycle model was enabled in the run options
+    pub fn cycles(&self) -> Option<u64> {
+        self.cycle_model.as_ref().map(|_| self.cycles)
+    }
+
+    /// Hashes the data and reference markers of every live allocation, allowing two machines
+    /// to be compared for equal memory contents without dumping their whole memory
+    pub fn memory_checksum(&self) -> u64 {
+        self.memory.checksum()
+    }
+
+    /// Number of times the garbage collector has run so far
+    pub fn garbage_collection_count(&self) -> u64 {
+        self.memory.garbage_collection_count()
+    }
+
+    /// Checks the internal consistency of the memory allocator and garbage collector bookkeeping
+    pub fn verify_memory(&self) -> VoidResult {
+        self.memory.verify()
+    }
+
+    /// Returns the memory accesses recorded since the last call.
+    /// Always empty unless `RunOptions::trace_memory` was set
+    pub fn take_memory_trace(&mut self) -> Vec<MemoryAccess> {
+        self.memory.take_trace()
+    }
+
+    /// Returns what every garbage collection did since the last call, including automatic ones.
+    /// Always empty unless `RunOptions::record_collections` was set
+    pub fn take_collection_reports(&mut self) -> Vec<CollectionReport> {
+        self.memory.take_collection_reports()
+    }
+
+    /// Returns a displayable listing of every allocation and its contents
+    pub fn memory_dump(&self) -> impl Display + '_ {
+        &self.memory
+    }
+
+    fn ip_reader(&mut self) -> MachineInstructionPointerReader<'_> {
+        MachineInstructionPointerReader {
             memory: &self.memory,
             cpu_state: &mut self.cpu_state,
         }
     }
 
-    fn step(&mut self) -> Result<bool> {
-        self.cpu_state.instruction_pointer;
-        let opcode = Opcode::decode(&mut self.ip_reader())?;
+    /// Executes a single instruction. Returns false if the program has halted
+    pub fn step(&mut self) -> Result<bool> {
+        let ip = self.cpu_state.instruction_pointer.0;
+        if ip >= self.program_length {
+            let padded_length = memory::round_up_to(self.program_length, WORD_BYTE_SIZE)?;
+            let location = if ip < padded_length {
+                "into the alignment padding after the end of the program"
+            } else {
+                "past end of program"
+            };
+
+            return Err(Error::new(&format!(
+                "Execution ran {} at {:016X} (missing halt?)",
+                location, ip
+            )));
+        }
+
+        // Check this before decoding errors, as reading the padding can make the instruction look invalid
+        let decoded = Opcode::decode(&mut self.ip_reader());
+        if self.cpu_state.instruction_pointer.0 > self.program_length {
+            return Err(Error::new(&format!(
+                "Instruction at {:016X} is cut off by the end of the program",
+                ip
+            )));
+        }
+
+        let opcode = decoded?;
+        self.instructions_executed += 1;
+        if let Some(model) = &self.cycle_model {
+            self.cycles += model.cost(opcode.instruction);
+        }
         //println!("LAKESIS | {:016X} {}", previous_ip, opcode);
 
         match opcode.instruction {
@@ -275,6 +909,17 @@ impl Interpreter {
                 self.write_with_flags(&opcode.operands[1], value)?;
             }
 
+            Instruction::MoveIfZero => self.conditional_move(&opcode, self.cpu_state.zero_flag)?,
+            Instruction::MoveIfNotZero => {
+                self.conditional_move(&opcode, !self.cpu_state.zero_flag)?
+            }
+            Instruction::MoveIfCarry => {
+                self.conditional_move(&opcode, self.cpu_state.carry_flag)?
+            }
+            Instruction::MoveIfNotCarry => {
+                self.conditional_move(&opcode, !self.cpu_state.carry_flag)?
+            }
+
             Instruction::Add => self.combine_with_carry(&opcode, DataWord::overflowing_add)?,
             Instruction::Subtract => {
                 self.reverse_combine_with_carry(&opcode, DataWord::overflowing_sub)?
@@ -283,11 +928,23 @@ impl Interpreter {
             Instruction::Divide => {
                 self.reverse_combine_with_carry(&opcode, DataWord::overflowing_div)?
             }
+            Instruction::SignedDivide => {
+                self.reverse_combine_with_carry(&opcode, DataWord::overflowing_signed_div)?
+            }
 
             Instruction::BitwiseAnd => self.combine(&opcode, DataWord::bitand)?,
             Instruction::BitwiseOr => self.combine(&opcode, DataWord::bitor)?,
             Instruction::BitwiseXor => self.combine(&opcode, DataWord::bitxor)?,
 
+            Instruction::Test => {
+                self.ensure_operands(&opcode, 2)?;
+                let value1 = self.read(&opcode.operands[0])?.value;
+                let value2 = self.read(&opcode.operands[1])?.value;
+
+                self.cpu_state.zero_flag = value1 & value2 == 0;
+                self.cpu_state.carry_flag = false;
+            }
+
             Instruction::BitwiseNot => {
                 self.ensure_operands(&opcode, 1)?;
                 let result = !self.read(&opcode.operands[0])?;
@@ -295,10 +952,20 @@ impl Interpreter {
             }
 
             Instruction::ShiftLeft => {
-                self.combine_with_carry(&opcode, DataWord::overflowing_shl)?
+                let shift = if self.zero_wide_shifts {
+                    DataWord::zeroing_shl
+                } else {
+                    DataWord::overflowing_shl
+                };
+                self.reverse_combine_with_carry(&opcode, shift)?
             }
             Instruction::ShiftRight => {
-                self.combine_with_carry(&opcode, DataWord::overflowing_shr)?
+                let shift = if self.zero_wide_shifts {
+                    DataWord::zeroing_shr
+                } else {
+                    DataWord::overflowing_shr
+                };
+                self.reverse_combine_with_carry(&opcode, shift)?
             }
 
             Instruction::Compare => {
@@ -309,6 +976,14 @@ impl Interpreter {
                 self.cpu_state.zero_flag = value1 == value2;
                 self.cpu_state.carry_flag = value1 >= value2;
             }
+            Instruction::SignedCompare => {
+                self.ensure_operands(&opcode, 2)?;
+                let value1 = self.read(&opcode.operands[0])?.value as IWord;
+                let value2 = self.read(&opcode.operands[1])?.value as IWord;
+
+                self.cpu_state.zero_flag = value1 == value2;
+                self.cpu_state.carry_flag = value1 >= value2;
+            }
 
             Instruction::Jump => self.jump(&opcode)?,
             Instruction::JumpEqual => self.conditional_jump(&opcode, Some(true), None)?,
@@ -374,6 +1049,22 @@ impl Interpreter {
                 self.write(&opcode.operands[1], addr)?;
             }
 
+            Instruction::NewAligned => {
+                self.ensure_operands(&opcode, 3)?;
+                let size = self.read(&opcode.operands[0])?.value;
+                let alignment = self.read(&opcode.operands[1])?.value;
+
+                let addr = DataWord {
+                    value: self.memory.allocate_aligned(
+                        size,
+                        alignment,
+                        &self.cpu_state.registers,
+                    )?,
+                    is_reference: true,
+                };
+                self.write(&opcode.operands[2], addr)?;
+            }
+
             Instruction::GarbageCollector => self
                 .memory
                 .force_garbage_collection(&self.cpu_state.registers)?,
@@ -392,47 +1083,141 @@ impl Interpreter {
                 self.write(&opcode.operands[0], value)?;
             }
 
+            Instruction::IsReference => {
+                self.ensure_operands(&opcode, 1)?;
+                let value = self.read(&opcode.operands[0])?;
+                self.cpu_state.zero_flag = value.is_reference;
+            }
+
+            Instruction::FetchAdd => {
+                self.ensure_operands(&opcode, 3)?;
+                let original = self.read(&opcode.operands[0])?;
+                let delta = self.read(&opcode.operands[1])?;
+
+                // The stored word keeps its own reference marker, regardless of the delta
+                let (sum, carry) = original.value.overflowing_add(delta.value);
+                let result = DataValue {
+                    value: sum,
+                    is_reference: original.is_reference,
+                };
+
+                self.write(&opcode.operands[0], result)?;
+                self.write(&opcode.operands[2], original)?;
+                self.cpu_state.zero_flag = sum == 0;
+                self.cpu_state.carry_flag = carry;
+            }
+
+            Instruction::ObjectId => {
+                self.ensure_operands(&opcode, 2)?;
+                let addr = self.read(&opcode.operands[0])?.expect_reference()?;
+                let id = DataValue {
+                    value: self.memory.allocation_id(addr)?,
+                    is_reference: false,
+                };
+                self.write(&opcode.operands[1], id)?;
+            }
+
+            // Debuggers stop before executing it, so there's nothing left to do
+            Instruction::Breakpoint => {}
+
+            Instruction::ClearFlags => {
+                self.ensure_operands(&opcode, 0)?;
+                self.cpu_state.zero_flag = false;
+                self.cpu_state.carry_flag = false;
+            }
+
+            Instruction::GetInstructionPointer => {
+                self.ensure_operands(&opcode, 1)?;
+
+                // The instruction has already been decoded, so the IP points to the next instruction
+                let ip = DataValue {
+                    value: self.cpu_state.instruction_pointer.0,
+                    is_reference: true,
+                };
+                self.write(&opcode.operands[0], ip)?;
+            }
+
             Instruction::CallNative => {
                 self.ensure_operands(&opcode, 1)?;
-                match self.read(&opcode.operands[0])?.value {
-                    0 => self.native_print()?,
-                    1 => self.native_random()?,
-                    2 => self.native_sleep()?,
-                    _ => unimplemented!(),
+                let id = self.read(&opcode.operands[0])?.value;
+
+                if let Some(native) = self.host_natives.0.get(&id).cloned() {
+                    (native.borrow_mut())(self)?;
+                } else {
+                    let native = NATIVE_FUNCTIONS
+                        .iter()
+                        .find(|x| x.id == id)
+                        .ok_or_else(|| {
+                            Error::new(&format!("Unknown native function {:02X}", id))
+                        })?;
+
+                    (native.handler)(self)?;
                 }
             }
 
             Instruction::DebugCpu => {
-                self.ensure_operands(&opcode, 1)?;
                 let num = self.read(&opcode.operands[0])?;
 
-                println!("DEBUGCPU | {} | {}", num, self);
+                let mut values = Vec::new();
+                for operand in &opcode.operands[1..] {
+                    values.push(self.read(operand)?.to_string());
+                }
+
+                // Formatted before writing, as the output is part of the machine being formatted
+                let line = if values.is_empty() {
+                    format!("DEBUGCPU | {} | {}", num, self)
+                } else {
+                    format!("DEBUGCPU | {} | {} | {}", num, values.join(", "), self)
+                };
+                writeln!(self.io.output, "{}", line)?;
             }
 
             Instruction::DebugDump => {
                 self.ensure_operands(&opcode, 2)?;
                 let addr = self.read(&opcode.operands[0])?.value;
                 let len = self.read(&opcode.operands[1])?.value;
-                let data = self.memory.get(addr, len)?;
+                let data = self.memory.read_bytes(addr, len)?;
 
-                print!("DEBUGDUMP | 0x{:X} | ", addr);
+                let mut line = format!("DEBUGDUMP | 0x{:X} | ", addr);
 
                 let mut i = 0;
-                for byte in data {
+                for byte in &data {
                     i += 1;
-                    print!("{:02X} ", byte);
+                    line.push_str(&format!("{:02X} ", byte));
 
                     if i % WORD_BYTE_SIZE == 0 {
-                        print!("  ");
+                        line.push_str("  ");
                     }
                 }
 
-                println!()
+                let text: String = data
+                    .iter()
+                    .map(|x| {
+                        if x.is_ascii_graphic() || *x == b' ' {
+                            *x as char
+                        } else {
+                            '.'
+                        }
+                    })
+                    .collect();
+                writeln!(self.io.output, "{}| {}", line, text)?;
             }
 
             Instruction::DebugMemory => {
                 self.ensure_operands(&opcode, 0)?;
-                println!("{}", self.memory);
+                writeln!(self.io.output, "{}", self.memory)?;
+            }
+
+            Instruction::Abort => {
+                self.ensure_operands(&opcode, 2)?;
+                let addr = self.read(&opcode.operands[0])?.value;
+                let len = self.read(&opcode.operands[1])?.value;
+                let message = self.memory.read_bytes(addr, len)?;
+
+                return Err(Error::new(&format!(
+                    "Program aborted: {}",
+                    String::from_utf8_lossy(&message)
+                )));
             }
 
             Instruction::Halt => return Ok(false),
@@ -458,7 +1243,7 @@ impl Interpreter {
         opcode: &Opcode,
         operation: impl FnOnce(DataWord, DataWord) -> DataWord,
     ) -> VoidResult {
-        self.ensure_operands(&opcode, 2)?;
+        self.ensure_operands(opcode, 2)?;
         let value1 = self.read(&opcode.operands[0])?;
         let value2 = self.read(&opcode.operands[1])?;
         let result = operation(value1, value2);
@@ -466,6 +1251,9 @@ impl Interpreter {
         self.write_with_flags(&opcode.operands[1], result)
     }
 
+    /// Like `combine_with_carry`, but with the operands swapped when calling `operation`,
+    /// so that non-commutative instructions compute `dst = dst <op> src`.
+    /// For example, `sub R0, R1` computes `R1 = R1 - R0`.
     fn reverse_combine_with_carry(
         &mut self,
         opcode: &Opcode,
@@ -545,12 +1333,31 @@ impl Interpreter {
                 Ok(addr.0)
             }
 
+            Operand::Indexed { base, index } => {
+                let base_addr = self.cpu_state.registers[*base as usize].expect_reference()?;
+                let offset = self.cpu_state.registers[*index as usize].value;
+                let (addr, _) = base_addr.overflowing_add(offset);
+                Ok(addr)
+            }
+
             _ => panic!(
                 "get_effective_address can only be called with a reference or stack reference"
             ),
         }
     }
 
+    /// Copies the first operand to the second one if `condition` is true, without changing the flags.
+    /// Otherwise, the second operand is left untouched, including its data type
+    fn conditional_move(&mut self, opcode: &Opcode, condition: bool) -> VoidResult {
+        self.ensure_operands(opcode, 2)?;
+        let value = self.read(&opcode.operands[0])?;
+        if condition {
+            self.write(&opcode.operands[1], value)?;
+        }
+
+        Ok(())
+    }
+
     fn conditional_jump(
         &mut self,
         opcode: &Opcode,
@@ -575,7 +1382,7 @@ impl Interpreter {
     }
 
     fn jump(&mut self, opcode: &Opcode) -> VoidResult {
-        self.ensure_operands(&opcode, 1)?;
+        self.ensure_operands(opcode, 1)?;
         let addr = self.read(&opcode.operands[0])?.value;
         self.cpu_state.instruction_pointer = Wrapping(addr);
         Ok(())
@@ -610,35 +1417,52 @@ impl Interpreter {
         let string_len = self.read_native_parameter(0)?;
         let string_base_addr = self.read_native_parameter(1)?;
 
+        // An empty string has nothing to read, so its base address doesn't matter
+        if string_len.value == 0 {
+            return Ok(());
+        }
+
         if !string_base_addr.is_reference {
             return Err(Error::new("Base address provided isn't a reference"));
         }
 
-        let string = self.memory.get(string_base_addr.value, string_len.value)?;
+        let string = self
+            .memory
+            .read_bytes(string_base_addr.value, string_len.value)?;
 
+        // Runs of literal text and %s arguments are decoded separately, so a multi-byte character cut off at the
+        // end of either becomes a replacement character instead of merging with whatever comes next
+        let mut output = String::new();
+        let mut literal_start = 0;
         let mut i = 0;
         let mut param_index = 2;
         while i < string.len() {
-            if string[i] == b'%' {
+            if string[i] != b'%' {
                 i += 1;
-                if i >= string.len() {
-                    return Err(Error::new("Unterminated format string placeholder"));
-                }
+                continue;
+            }
 
-                // TODO: This is ugly
-                if string[i] == b'%' {
-                    print!("%");
-                } else if string[i] == b'd' {
+            output.push_str(&String::from_utf8_lossy(&string[literal_start..i]));
+            i += 1;
+            if i >= string.len() {
+                return Err(Error::new("Unterminated format string placeholder"));
+            }
+
+            match string[i] {
+                b'%' => output.push('%'),
+                b'd' => {
                     let param = self.read_native_parameter(param_index)?.value as IWord;
                     param_index += 1;
 
-                    print!("{}", param);
-                } else if string[i] == b'u' {
+                    output.push_str(&param.to_string());
+                }
+                b'u' => {
                     let param = self.read_native_parameter(param_index)?.value;
                     param_index += 1;
 
-                    print!("{}", param);
-                } else if string[i] == b's' {
+                    output.push_str(&param.to_string());
+                }
+                b's' => {
                     let param_len = self.read_native_parameter(param_index)?.value;
                     param_index += 1;
                     let param = self.read_native_parameter(param_index)?;
@@ -648,36 +1472,75 @@ impl Interpreter {
                         return Err(Error::new("Tried to print a non-reference as a string"));
                     }
 
-                    let param_utf8 = self.memory.get(param.value, param_len)?;
-                    let param_str = String::from_utf8_lossy(param_utf8);
-                    print!("{}", param_str);
+                    let param_utf8 = self.memory.read_bytes(param.value, param_len)?;
+                    output.push_str(&String::from_utf8_lossy(&param_utf8));
                 }
-            } else {
-                print!("{}", string[i] as char);
+                _ => {}
             }
 
             i += 1;
+            literal_start = i;
         }
 
+        output.push_str(&String::from_utf8_lossy(&string[literal_start..]));
+        write!(self.io.output, "{}", output)?;
+
         Ok(())
     }
 
     fn native_random(&mut self) -> VoidResult {
         self.cpu_state.registers[0] = DataWord {
-            value: rand::random(),
+            value: self.rng.gen(),
             is_reference: false,
         };
         Ok(())
     }
 
-    fn native_sleep(&self) -> VoidResult {
+    fn native_fill(&mut self) -> VoidResult {
+        let base_addr = self.read_native_parameter(0)?;
+        let len = self.read_native_parameter(1)?.value;
+        let value = self.read_native_parameter(2)?.value;
+
+        if !base_addr.is_reference {
+            return Err(Error::new("Base address provided isn't a reference"));
+        }
+
+        self.memory.fill(base_addr.value, len, value as u8)
+    }
+
+    fn native_read(&mut self) -> VoidResult {
+        let base_addr = self.read_native_parameter(0)?;
+        let max_len = self.read_native_parameter(1)?.value;
+
+        if !base_addr.is_reference {
+            return Err(Error::new("Base address provided isn't a reference"));
+        }
+
+        // Make sure the destination is valid before consuming any input
+        self.memory.fetch(base_addr.value, max_len)?;
+
+        // Programs usually print a prompt before reading, which must show up before blocking on input
+        self.io.output.flush()?;
+
+        let mut buffer = vec![0; max_len as usize];
+        let len = self.io.input.read(&mut buffer)?;
+        self.memory.set_data(base_addr.value, &buffer[..len])?;
+
+        self.cpu_state.registers[0] = DataWord {
+            value: len as UWord,
+            is_reference: false,
+        };
+        Ok(())
+    }
+
+    fn native_sleep(&mut self) -> VoidResult {
         let millis = self.read_native_parameter(0)?.value;
         thread::sleep(Duration::from_millis(millis));
         Ok(())
     }
 }
 
-impl Display for Interpreter {
+impl Display for Machine {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         for i in 0..REGISTER_NUM {
             write!(f, "R{}={:02X} ", i, self.cpu_state.registers[i])?;
@@ -702,52 +1565,546 @@ impl Display for Interpreter {
     }
 }
 
-impl Read for InterpreterInstructionPointerReader<'_> {
+impl Read for MachineInstructionPointerReader<'_> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         let data = self
             .memory
-            .get(self.cpu_state.instruction_pointer.0, buf.len() as UWord)?;
+            .fetch(self.cpu_state.instruction_pointer.0, buf.len() as UWord)?;
         buf.copy_from_slice(data);
         self.cpu_state.instruction_pointer += Wrapping(buf.len() as UWord);
         Ok(buf.len())
     }
 }
 
+/// Lists every native function that programs can call
+pub fn native_functions() -> &'static [NativeFunction] {
+    NATIVE_FUNCTIONS
+}
+
 pub fn run(reader: &mut impl Read) -> VoidResult {
-    let mut interpreter = Interpreter {
-        cpu_state: CpuState::default(),
-        memory: Memory::new(),
+    Machine::load(reader)?.run()
+}
+
+/// Like `run`, but the random native generates the same sequence of numbers on every run with the same seed
+pub fn run_with_seed(reader: &mut impl Read, seed: u64) -> VoidResult {
+    let options = RunOptions {
+        seed: Some(seed),
+        ..RunOptions::default()
     };
 
-    let mut program_data = Vec::new();
-    reader.read_to_end(&mut program_data)?;
+    Machine::load_with_options(reader, &options)?.run()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::{self, AssemblerOptions};
+
+    /// Output that can still be read after the machine writing to it is done
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SharedBuffer {
+        fn text(&self) -> String {
+            String::from_utf8(self.0.borrow().clone()).unwrap()
+        }
+    }
+
+    fn load_source(source: &str) -> Machine {
+        let mut program = Vec::new();
+        assembler::assemble(
+            &mut source.as_bytes(),
+            &mut program,
+            &AssemblerOptions::default(),
+        )
+        .unwrap();
+
+        Machine::load(&mut program.as_slice()).unwrap()
+    }
+
+    /// Assembles `source` and runs it until it halts
+    fn run_source(source: &str) -> Machine {
+        let mut machine = load_source(source);
+        machine.run().unwrap();
+        machine
+    }
+
+    /// Same as `run_source`, but also returns everything the program wrote to its output
+    fn run_source_with_output(source: &str) -> (Machine, String) {
+        let mut machine = load_source(source);
+        let output = SharedBuffer::default();
+        machine.set_output(Box::new(output.clone()));
+        machine.run().unwrap();
+        (machine, output.text())
+    }
+
+    fn register(machine: &Machine, index: RegisterIndex) -> UWord {
+        machine.read_register_raw(index).unwrap().value()
+    }
 
-    let mut aligned_len = program_data.len() as UWord;
-    while aligned_len % WORD_BYTE_SIZE != 0 {
-        aligned_len += 1;
+    #[test]
+    fn memory_checksum_changes_with_data_and_reference_bits() {
+        // Stores R0 into a new allocation, so runs only differ in what the host puts in R0
+        let checksum_storing = |value: DataWord| {
+            let mut machine = load_source("new 16, r1\nmov r0, [r1]\nhalt\n");
+            machine.write_register_raw(0, value).unwrap();
+            machine.run().unwrap();
+            machine.memory_checksum()
+        };
+
+        let plain = checksum_storing(DataValue::new(5, false));
+        assert_eq!(plain, checksum_storing(DataValue::new(5, false)));
+        assert_ne!(plain, checksum_storing(DataValue::new(6, false)));
+        assert_ne!(plain, checksum_storing(DataValue::new(5, true)));
     }
 
-    if interpreter
-        .memory
-        .allocate(aligned_len, false, &[], Some(0), Some("Program"))?
-        != 0
-    {
-        return Err(Error::new("Unable to allocate program data at address 0"));
+    #[test]
+    fn subtract_takes_first_operand_from_second() {
+        let machine = run_source("mov 3, r0\nmov 10, r1\nsub r0, r1\nhalt\n");
+        assert_eq!(register(&machine, 0), 3);
+        assert_eq!(register(&machine, 1), 7);
     }
 
-    interpreter.memory.set(0, &program_data)?;
+    #[test]
+    fn divide_divides_second_operand_by_first() {
+        let machine = run_source("mov 4, r0\nmov 20, r1\ndiv r0, r1\nhalt\n");
+        assert_eq!(register(&machine, 0), 4);
+        assert_eq!(register(&machine, 1), 5);
+    }
 
-    let stack_base = interpreter
-        .memory
-        .allocate(STACK_SIZE, false, &[], None, Some("Stack"))?;
-    interpreter.cpu_state.stack_pointer =
-        Wrapping(stack_base) + Wrapping(STACK_SIZE) - Wrapping(WORD_BYTE_SIZE);
+    #[test]
+    fn subtract_and_divide_write_to_second_operand() {
+        for source in &["sub r0, 5\nhalt\n", "div r0, 5\nhalt\n"] {
+            let result = assembler::assemble(
+                &mut source.as_bytes(),
+                &mut Vec::new(),
+                &AssemblerOptions::default(),
+            );
+            assert!(result.is_err(), "{} was accepted", source);
+        }
+    }
+
+    #[test]
+    fn debugcpu_accepts_a_variable_number_of_operands() {
+        for (source, operand_count) in &[
+            ("debugcpu 1, r0\nhalt\n", 2),
+            ("debugcpu 1, r0, r1, 42\nhalt\n", 4),
+        ] {
+            let mut program = Vec::new();
+            assembler::assemble(
+                &mut source.as_bytes(),
+                &mut program,
+                &AssemblerOptions::default(),
+            )
+            .unwrap();
+
+            let opcode = Opcode::decode(&mut program.as_slice()).unwrap();
+            assert_eq!(opcode.instruction, Instruction::DebugCpu);
+            assert_eq!(opcode.operands.len(), *operand_count);
+
+            run_source(source);
+        }
+    }
 
-    //println!("LAKESIS | {}", interpreter);
+    #[test]
+    fn clear_flags_clears_flags_set_by_compare() {
+        let compared = run_source("cmp 1, 1\nhalt\n");
+        assert!(compared.carry_flag());
+        assert!(compared.zero_flag());
 
-    while interpreter.step()? {
-        //println!("LAKESIS | {}", interpreter);
+        let cleared = run_source("cmp 1, 1\nclf\nhalt\n");
+        assert!(!cleared.carry_flag());
+        assert!(!cleared.zero_flag());
     }
 
-    Ok(())
+    #[test]
+    fn debug_instructions_write_to_the_redirected_output() {
+        let (_, output) = run_source_with_output(
+            "
+            mov text, r0
+            ref r0
+            debugcpu 7, 42
+            debugdump r0, 3
+            debugmem
+            halt
+            text: .string \"abc\"
+            ",
+        );
+
+        let lines: Vec<&str> = output.lines().collect();
+        assert!(
+            lines[0].starts_with("DEBUGCPU | 7 | 42 | R0=[R]"),
+            "{}",
+            output
+        );
+        assert!(lines[1].starts_with("DEBUGDUMP | 0x"), "{}", output);
+        assert!(lines[1].ends_with(" | 61 62 63 | abc"), "{}", output);
+        assert_eq!(lines[2], "Allocations:");
+        assert!(output.contains(" Program\n"), "{}", output);
+    }
+
+    /// Loads a program that prints a line, stops at a breakpoint and then keeps two allocations alive that don't fit
+    /// in the largest heap. The heap starts at its largest size so it doesn't have to grow before running out
+    fn load_out_of_memory_program(quiet: bool) -> Machine {
+        let source = "debugcpu 1, 2\nbrk\nnew 0x1F000000, r0\nnew 0x26000000, r1\nhalt\n";
+        let mut program = Vec::new();
+        assembler::assemble(
+            &mut source.as_bytes(),
+            &mut program,
+            &AssemblerOptions::default(),
+        )
+        .unwrap();
+
+        let options = RunOptions {
+            initial_heap: MAX_MEMORY_SIZE,
+            quiet,
+            ..RunOptions::default()
+        };
+        Machine::load_with_options(&mut program.as_slice(), &options).unwrap()
+    }
+
+    #[test]
+    fn diagnostics_are_written_apart_from_the_output() {
+        let mut machine = load_out_of_memory_program(false);
+        let output = SharedBuffer::default();
+        let diagnostics = SharedBuffer::default();
+        machine.set_output(Box::new(output.clone()));
+        machine.set_diagnostics(Box::new(diagnostics.clone()));
+
+        let error = machine.run().unwrap_err();
+
+        assert_eq!(error.to_string(), "Out of memory");
+        assert_eq!(output.text().lines().count(), 1, "{}", output.text());
+        assert!(output.text().starts_with("DEBUGCPU | 1 | 2 |"));
+        let diagnostics = diagnostics.text();
+        assert!(diagnostics.starts_with("BREAKPOINT | "), "{}", diagnostics);
+        assert!(
+            diagnostics.contains("\nLAKESIS | Out of memory - Requested: Data 608.0 MiB"),
+            "{}",
+            diagnostics
+        );
+    }
+
+    #[test]
+    fn quiet_run_only_reports_the_out_of_memory_error() {
+        let mut machine = load_out_of_memory_program(true);
+        let output = SharedBuffer::default();
+        machine.set_output(Box::new(output.clone()));
+
+        let error = machine.run().unwrap_err();
+
+        assert_eq!(error.to_string(), "Out of memory");
+        assert!(!output.text().contains("LAKESIS |"), "{}", output.text());
+        assert!(!output.text().contains("BREAKPOINT |"), "{}", output.text());
+        assert_eq!(output.text().lines().count(), 1, "{}", output.text());
+    }
+
+    #[test]
+    fn execution_begins_at_start_label_in_the_middle_of_the_program() {
+        let machine = run_source(
+            "
+            mov 1, r0
+            halt
+            _start:
+            mov 2, r0
+            halt
+            ",
+        );
+
+        assert_eq!(register(&machine, 0), 2);
+    }
+
+    #[test]
+    fn debugmem_round_trips_and_dumps_all_of_memory() {
+        let mut program = Vec::new();
+        assembler::assemble(
+            &mut "debugmem\nhalt\n".as_bytes(),
+            &mut program,
+            &AssemblerOptions::default(),
+        )
+        .unwrap();
+        let opcode = Opcode::decode(&mut program.as_slice()).unwrap();
+        assert_eq!(opcode.instruction, Instruction::DebugMemory);
+        assert!(opcode.operands.is_empty());
+
+        let (_, output) = run_source_with_output("debugmem\nhalt\n");
+        assert!(output.starts_with("Allocations:\n"), "{}", output);
+        assert!(output.contains(" Program\n"), "{}", output);
+        assert!(output.contains(" Stack\n"), "{}", output);
+
+        // Dumping a range is what debugdump is for
+        let error = assembler::assemble(
+            &mut "debugmem r0, 8\nhalt\n".as_bytes(),
+            &mut Vec::new(),
+            &AssemblerOptions::default(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.message,
+            "debugmem expects 0 operand(s), but 2 were provided"
+        );
+        // Only the instruction is underlined, not the one after it
+        let range = error.range.unwrap();
+        assert_eq!((range.start.line, range.start.column), (1, 1));
+        assert_eq!((range.end.line, range.end.column), (1, 15));
+    }
+
+    #[test]
+    fn guest_reads_preloaded_data_at_its_address() {
+        let machine = run_source(
+            "
+            mov table, r0
+            ref r0
+            mov [r0], r1
+            mov [r0+1w], r2
+            mov text, r0
+            ref r0
+            mov [r0], r3
+            halt
+
+            .preload table 0x100000
+            table: .word 0x1234, -2
+            .endpreload
+
+            .preload text 0x200000
+            text: .string \"ABCDEFGH\"
+            .endpreload
+            ",
+        );
+
+        assert_eq!(register(&machine, 1), 0x1234);
+        assert_eq!(register(&machine, 2), -2i64 as UWord);
+        assert_eq!(register(&machine, 3), u64::from_le_bytes(*b"ABCDEFGH"));
+    }
+
+    #[test]
+    fn isref_sets_zero_flag_only_for_references() {
+        let allocated = run_source("new 16, r0\nisref r0\nhalt\n");
+        assert!(allocated.zero_flag());
+
+        let plain = run_source("mov 16, r0\nisref r0\nhalt\n");
+        assert!(!plain.zero_flag());
+    }
+
+    #[test]
+    fn running_off_the_end_of_the_program_is_an_error() {
+        let error = load_source("mov 3, r0\n").run().unwrap_err().to_string();
+        assert!(
+            error.contains("Execution ran into the alignment padding after the end of the program")
+                && error.contains("(missing halt?)"),
+            "{}",
+            error
+        );
+
+        let error = load_source("jmp 64\n").run().unwrap_err().to_string();
+        assert!(
+            error.contains("Execution ran past end of program")
+                && error.contains("(missing halt?)"),
+            "{}",
+            error
+        );
+
+        let mut program = Vec::new();
+        assembler::assemble(
+            &mut "mov 3, r0\n".as_bytes(),
+            &mut program,
+            &AssemblerOptions::default(),
+        )
+        .unwrap();
+        program.pop();
+
+        let mut machine = Machine::load(&mut program.as_slice()).unwrap();
+        let error = machine.run().unwrap_err().to_string();
+        assert_eq!(
+            error,
+            "Instruction at 0000000000000000 is cut off by the end of the program"
+        );
+    }
+
+    #[test]
+    fn bigger_initial_heap_avoids_garbage_collections() {
+        // Allocates 100 objects of 64 KiB each, only ever keeping the latest one alive.
+        // That's far more than the default heap has left after the 2 MiB stack
+        let source = "mov 100, r0\nloop:\nnew 0x10000, r1\nsub 1, r0\ncmp 0, r0\njne loop\nhalt\n";
+        let collections_with_heap = |initial_heap: usize| {
+            let mut program = Vec::new();
+            assembler::assemble(
+                &mut source.as_bytes(),
+                &mut program,
+                &AssemblerOptions::default(),
+            )
+            .unwrap();
+
+            let options = RunOptions {
+                initial_heap,
+                record_collections: true,
+                ..RunOptions::default()
+            };
+            let mut machine =
+                Machine::load_with_options(&mut program.as_slice(), &options).unwrap();
+            machine.run().unwrap();
+            machine.take_collection_reports().len()
+        };
+
+        assert_eq!(collections_with_heap(16 * 1024 * 1024), 0);
+        assert!(collections_with_heap(INITIAL_MEMORY_SIZE) > 1);
+    }
+
+    #[test]
+    fn run_to_breakpoint_stops_at_the_breakpoint_address() {
+        let source = "mov 1, r0\nadd 2, r0\nadd 3, r0\nadd 4, r0\nhalt\n";
+
+        let mut stepped = load_source(source);
+        assert!(stepped.step().unwrap());
+        assert!(stepped.step().unwrap());
+        assert_eq!(stepped.instructions_executed(), 2);
+        let third_instruction = stepped.cpu_snapshot().instruction_pointer;
+
+        let mut machine = load_source(source);
+        machine.add_breakpoint(third_instruction);
+        assert_eq!(machine.breakpoints(), vec![third_instruction]);
+
+        assert!(machine.run_to_breakpoint().unwrap());
+        assert_eq!(
+            machine.cpu_snapshot().instruction_pointer,
+            third_instruction
+        );
+        assert_eq!(machine.instructions_executed(), 2);
+        assert_eq!(register(&machine, 0), 3);
+
+        assert!(!machine.run_to_breakpoint().unwrap());
+        assert_eq!(machine.instructions_executed(), 5);
+        assert_eq!(register(&machine, 0), 10);
+    }
+
+    #[test]
+    fn fetchadd_returns_the_old_value_and_updates_memory() {
+        let machine =
+            run_source("new 16, r1\nmov 5, [r1]\nfetchadd [r1], 3, r2\nmov [r1], r3\nhalt\n");
+        assert_eq!(register(&machine, 2), 5);
+        assert_eq!(register(&machine, 3), 8);
+    }
+
+    #[test]
+    fn memory_trace_records_a_write_and_the_read_after_it() {
+        let mut program = Vec::new();
+        assembler::assemble(
+            &mut "new 16, r1\nmov 0x1234, [r1]\nmov [r1], r2\nhalt\n".as_bytes(),
+            &mut program,
+            &AssemblerOptions::default(),
+        )
+        .unwrap();
+
+        let options = RunOptions {
+            trace_memory: true,
+            ..RunOptions::default()
+        };
+        let mut machine = Machine::load_with_options(&mut program.as_slice(), &options).unwrap();
+        machine.run().unwrap();
+
+        let trace = machine.take_memory_trace();
+        let written = (0x1234 as UWord).to_le_bytes().to_vec();
+        assert_eq!(trace.len(), 2, "{:?}", trace);
+        assert!(trace[0].is_write);
+        assert!(!trace[1].is_write);
+        assert_eq!(trace[0].address, register(&machine, 1));
+        assert_eq!(trace[1].address, trace[0].address);
+        assert_eq!(trace[0].data, written);
+        assert_eq!(trace[1].data, written);
+
+        assert!(machine.take_memory_trace().is_empty());
+    }
+
+    #[test]
+    fn step_over_runs_a_call_and_stops_after_it() {
+        let source = "call function\nmov 1, r0\nhalt\nfunction:\nmov 2, r1\nret\n";
+        let mut program = Vec::new();
+        assembler::assemble(
+            &mut source.as_bytes(),
+            &mut program,
+            &AssemblerOptions::default(),
+        )
+        .unwrap();
+
+        let mut reader = program.as_slice();
+        Opcode::decode(&mut reader).unwrap();
+        let after_call = (program.len() - reader.len()) as UWord;
+
+        let mut machine = Machine::load(&mut program.as_slice()).unwrap();
+        let stack_pointer = machine.cpu_snapshot().stack_pointer;
+        assert!(machine.step_over().unwrap());
+
+        let snapshot = machine.cpu_snapshot();
+        assert_eq!(snapshot.instruction_pointer, after_call);
+        assert_eq!(snapshot.stack_pointer, stack_pointer);
+        assert_eq!(register(&machine, 1), 2);
+        assert_eq!(register(&machine, 0), 0);
+    }
+
+    #[test]
+    fn cpu_snapshot_reflects_registers_and_flags_after_stepping() {
+        let mut machine = load_source("new 16, r1\nmov 7, r2\ncmp 2, 2\nhalt\n");
+        let before = machine.cpu_snapshot();
+        assert_eq!(before.registers[1], (0, false));
+        assert!(!before.zero_flag);
+
+        assert!(machine.step().unwrap());
+        assert!(machine.step().unwrap());
+        assert!(machine.step().unwrap());
+
+        let after = machine.cpu_snapshot();
+        assert_eq!(after.registers[1], (register(&machine, 1), true));
+        assert_eq!(after.registers[2], (7, false));
+        assert!(after.zero_flag);
+        assert!(after.carry_flag);
+        assert!(after.instruction_pointer > before.instruction_pointer);
+        assert_eq!(after.stack_pointer, before.stack_pointer);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn cpu_snapshot_round_trips_through_serde() {
+        let mut machine = load_source("new 16, r1\ncmp 2, 2\nhalt\n");
+        machine.step().unwrap();
+        machine.step().unwrap();
+
+        let snapshot = machine.cpu_snapshot();
+        let json = serde_json::to_string(&snapshot).unwrap();
+        assert_eq!(
+            serde_json::from_str::<CpuSnapshot>(&json).unwrap(),
+            snapshot
+        );
+    }
+
+    #[test]
+    fn abort_fails_with_the_guest_message() {
+        let mut machine = load_source(
+            "jmp main\nmessage: .string \"bad input\"\nmain:\nabort message, 9\nhalt\n",
+        );
+        let error = machine.run().unwrap_err().to_string();
+        assert_eq!(error, "Program aborted: bad input");
+    }
+
+    #[test]
+    fn conditional_move_leaves_the_destination_untouched_when_false() {
+        let mut machine = load_source("new 16, r1\nclf\ncmovz 5, r1\nhalt\n");
+        assert!(machine.step().unwrap());
+        let allocated = machine.cpu_snapshot().registers[1];
+        assert!(allocated.1);
+
+        machine.run().unwrap();
+        assert!(!machine.zero_flag());
+        assert_eq!(machine.cpu_snapshot().registers[1], allocated);
+    }
 }
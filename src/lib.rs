@@ -0,0 +1,5 @@
+pub mod assembler;
+pub mod core;
+pub mod executable;
+pub mod interpreter;
+pub mod opcodes;
@@ -1,17 +1,25 @@
-use crate::core::{Error, VoidResult};
+use lakesis::assembler::{self, AssemblerOptions, Reports, Symbols, Warning};
+use lakesis::core::{Error, Result, UWord, VoidResult};
+use lakesis::executable::{Executable, SectionKind};
+use lakesis::interpreter::{self, CycleModel, Machine, RunOptions};
+use lakesis::opcodes::{self, Instruction, Operand};
 use std::env;
-use std::fs::File;
-use std::io::{Cursor, Read, Seek, SeekFrom};
+use std::fs::{self, File};
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
 use std::path::Path;
 
-mod assembler;
-mod core;
-mod interpreter;
-mod opcodes;
+/// Minimum number of consecutive printable ASCII bytes that `view --auto-sections` treats as text
+const MIN_TEXT_RUN: usize = 4;
+
+/// Path that means stdin when used as an input, or stdout when used as an output
+const STDIO_PATH: &str = "-";
+
+/// Flags accepted by the verbs that run a program
+const RUN_FLAGS: &[&str] = &["--dump-on-halt", "--cycles", "--trace-memory", "--quiet"];
 
 fn main() -> VoidResult {
     let raw_args: Vec<String> = env::args().collect();
-    let verb: &str = &raw_args.get(1).map(|x| x as &str).unwrap_or("");
+    let verb: &str = raw_args.get(1).map(|x| x as &str).unwrap_or("");
 
     let args = if raw_args.len() >= 3 {
         &raw_args[2..]
@@ -24,106 +32,500 @@ fn main() -> VoidResult {
         "asm" => assemble(args),
         "run" => run(args),
         "runasm" => assemble_and_run(args),
+        "natives" => list_natives(),
         _ => print_help(&raw_args),
     }
 }
 
 fn print_help(args: &[String]) -> VoidResult {
-    let program_name = args.get(0).map(|x| x as &str).unwrap_or("lakesis");
+    let program_name = args.first().map(|x| x as &str).unwrap_or("lakesis");
 
     println!("{} help", program_name);
     println!("\tPrints this message");
     println!();
 
-    println!("{} asm <source> [output]", program_name);
+    println!(
+        "{} asm [--dump-fixups] [--werror] [--listing] [--map] <source> [output]",
+        program_name
+    );
     println!("\tCompiles an assembly source code file to an executable");
-    println!("\tsource: Path of the file containing the assembly source code");
-    println!("\toutput: Path of the file where the executable will be written to.");
+    println!("\tsource: Path of the file containing the assembly source code, or - for stdin");
+    println!(
+        "\toutput: Path of the file where the executable will be written to, or - for stdout."
+    );
     println!("\t        If not specified, uses the same file as 'source' but with a");
-    println!("\t        .bin extension");
+    println!("\t        .bin extension, or stdout if 'source' is -");
+    println!("\t--dump-fixups: Prints the address each label reference resolved to");
+    println!("\t--werror: Fails to compile if there are any warnings, such as unused labels");
+    println!("\t--listing: Prints the address and bytes of every instruction and directive, along with its source location");
+    println!("\t--map: Writes the value of every label to a file with the same name as 'output' but with a .map extension");
+    println!();
+
+    println!("{} natives", program_name);
+    println!("\tLists the native functions programs can call with the native instruction");
     println!();
 
-    println!("{} view <file>", program_name);
+    println!(
+        "{} view [--encoding] [--auto-sections] <file>",
+        program_name
+    );
     println!("\tDisassembles an executable and displays its code");
-    println!("\tfile: Path of the file to disassemble");
+    println!("\tfile: Path of the file to disassemble, or - for stdin");
+    println!("\t--encoding: Shows the bytes of every instruction and the fields encoded in them");
+    println!("\t--auto-sections: In programs without sections, guesses which bytes are data and dumps them instead");
     println!();
 
-    println!("{} run <file>", program_name);
+    println!(
+        "{} run [--dump-on-halt] [--cycles] [--trace-memory] [--quiet] [--seed N] <file>",
+        program_name
+    );
     println!("\tRuns a compiled executable");
-    println!("\tfile: Path of the executable to run");
+    println!("\tfile: Path of the executable to run, or - for stdin");
+    println!("\t--dump-on-halt: Prints the CPU state and memory after the program halts");
+    println!("\t--cycles: Prints the number of simulated cycles the program took to run");
+    println!("\t--trace-memory: Prints every memory read and write done by the program");
+    println!("\t--quiet: Doesn't print diagnostics, such as the memory report when running out of memory or the CPU state at brk");
+    println!("\t--seed N: Seeds the random number generator to make runs reproducible");
     println!();
 
-    println!("{} runasm <file>", program_name);
+    println!(
+        "{} runasm [--dump-on-halt] [--cycles] [--trace-memory] [--quiet] [--seed N] <file>",
+        program_name
+    );
     println!("\tCompiles an assembly source file and immediately runs it");
-    println!("\tfile: Path of the assembly source code to compile and run");
+    println!("\tfile: Path of the assembly source code to compile and run, or - for stdin");
+    println!("\t--dump-on-halt: Prints the CPU state and memory after the program halts");
+    println!("\t--cycles: Prints the number of simulated cycles the program took to run");
+    println!("\t--trace-memory: Prints every memory read and write done by the program");
+    println!("\t--quiet: Doesn't print diagnostics, such as the memory report when running out of memory or the CPU state at brk");
+    println!("\t--seed N: Seeds the random number generator to make runs reproducible");
     println!();
 
     Ok(())
 }
 
+fn list_natives() -> VoidResult {
+    for native in interpreter::native_functions() {
+        println!("{:02X} {}: {}", native.id, native.name, native.description);
+    }
+
+    Ok(())
+}
+
 fn disassemble(args: &[String]) -> VoidResult {
+    let (flags, args) = split_flags(args, &["--encoding", "--auto-sections"])?;
     if args.len() != 1 {
         return Err(Error::new("Expected exactly 1 argument"));
     }
 
-    let mut file = File::open(&args[0])?;
-    let mut buffer = Vec::with_capacity(file.metadata()?.len() as usize);
-    file.read_to_end(&mut buffer)?;
-    let buffer_size = buffer.len();
+    let show_encoding = flags.contains(&"--encoding");
+    let executable = Executable::read(&mut Cursor::new(read_input(args[0])?))?;
+    let program = &executable.program;
+
+    if executable.sections.is_empty() {
+        if flags.contains(&"--auto-sections") {
+            disassemble_guessing_sections(program, show_encoding)?;
+        } else {
+            disassemble_code(program, 0, program.len() as UWord, show_encoding)?;
+        }
+    }
+
+    for section in &executable.sections {
+        println!("; .section {}", section.kind);
+        match section.kind {
+            SectionKind::Code => {
+                disassemble_code(program, section.start, section.end, show_encoding)?
+            }
+            SectionKind::Data => dump_data(
+                &program[section.start as usize..section.end as usize],
+                section.start,
+            ),
+        }
+    }
+
+    for preload in &executable.preloads {
+        println!("; preloaded data {}", preload.name);
+        dump_data(&preload.data, preload.address);
+    }
 
-    let mut cursor = Cursor::new(buffer);
+    Ok(())
+}
 
-    while (cursor.position() as usize) < buffer_size {
-        let opcode = opcodes::Opcode::decode(&mut cursor)?;
+fn disassemble_code(program: &[u8], start: UWord, end: UWord, show_encoding: bool) -> VoidResult {
+    let mut cursor = Cursor::new(program);
+    cursor.set_position(start);
+
+    while cursor.position() < end {
+        let opcode_start = cursor.position() as usize;
+        // Show malformed instructions as they are instead of stopping, since that's usually why they're being viewed
+        let opcode = opcodes::Opcode::decode_lenient(&mut cursor)?;
         println!("{:016X} {}", cursor.position(), opcode);
+        if let Err(e) = opcode.validate() {
+            println!("    ; invalid: {}", e);
+        }
+
+        if show_encoding {
+            print_encoding(&program[opcode_start..cursor.position() as usize]);
+        }
     }
 
     Ok(())
 }
 
+/// Disassembles a program without sections, guessing which parts of it are data.
+/// Bytes that don't decode to a valid instruction and runs of printable text are dumped as data
+/// until something that looks like code is found again
+fn disassemble_guessing_sections(program: &[u8], show_encoding: bool) -> VoidResult {
+    let mut data_start = None;
+    let mut position = 0;
+
+    while position < program.len() {
+        let text_len = printable_run_len(&program[position..]);
+        let instruction_len = if text_len >= MIN_TEXT_RUN {
+            None
+        } else {
+            let mut cursor = Cursor::new(program);
+            cursor.set_position(position as u64);
+            opcodes::Opcode::decode(&mut cursor)
+                .ok()
+                .map(|_| cursor.position() as usize - position)
+        };
+
+        match instruction_len {
+            Some(len) => {
+                if let Some(start) = data_start.take() {
+                    println!("; guessed data");
+                    dump_data(&program[start..position], start as UWord);
+                }
+
+                disassemble_code(
+                    program,
+                    position as UWord,
+                    (position + len) as UWord,
+                    show_encoding,
+                )?;
+                position += len;
+            }
+            None => {
+                data_start.get_or_insert(position);
+                position += text_len.max(1);
+            }
+        }
+    }
+
+    if let Some(start) = data_start {
+        println!("; guessed data");
+        dump_data(&program[start..], start as UWord);
+    }
+
+    Ok(())
+}
+
+/// Number of printable ASCII characters at the start of `bytes`
+fn printable_run_len(bytes: &[u8]) -> usize {
+    bytes.iter().take_while(|x| is_printable(**x)).count()
+}
+
+fn is_printable(byte: u8) -> bool {
+    byte.is_ascii_graphic() || byte == b' '
+}
+
+/// Prints the bytes of an encoded instruction along with the fields packed into each of them.
+/// `bytes` must contain exactly one decoded instruction
+fn print_encoding(bytes: &[u8]) {
+    let mut operand_count = (bytes[0] & !Instruction::MASK) >> Instruction::SHIFT;
+    let mut index = 1;
+    let mut header = format!("{:02X}", bytes[0]);
+    if operand_count == Instruction::EXTENDED_COUNT {
+        operand_count = bytes[1];
+        index += 1;
+        header.push_str(&format!(" {:02X}", bytes[1]));
+    }
+
+    println!(
+        "    {:<24}| instruction {:02X}, {} operand(s)",
+        header,
+        bytes[0] & Instruction::MASK,
+        operand_count
+    );
+
+    for _ in 0..operand_count {
+        let first_byte = bytes[index];
+        let mode = (first_byte & Operand::ADDRESSING_MODE_MASK) >> Operand::ADDRESSING_MODE_SHIFT;
+        let register = (first_byte & Operand::REGISTER_NUM_MASK) >> Operand::REGISTER_NUM_SHIFT;
+        let sign = (first_byte & Operand::SIGN_MASK) >> Operand::SIGN_SHIFT;
+        let value_size = Operand::value_size_from_field(
+            (first_byte & Operand::VALUE_SIZE_MASK) >> Operand::VALUE_SIZE_SHIFT,
+        );
+
+        let operand_bytes: Vec<String> = bytes[index..=index + value_size]
+            .iter()
+            .map(|x| format!("{:02X}", x))
+            .collect();
+
+        let mode_name = match mode {
+            0b00 => "immediate",
+            0b01 => "register",
+            0b10 => "reference",
+            _ if sign != 0 => "indexed",
+            _ => "stack",
+        };
+
+        println!(
+            "    {:<24}| mode {} ({}), register {}, sign {}, {} value byte(s)",
+            operand_bytes.join(" "),
+            mode,
+            mode_name,
+            register,
+            if sign == 0 { '+' } else { '-' },
+            value_size
+        );
+
+        index += 1 + value_size;
+    }
+}
+
+/// Hex-dumps `data`, displaying addresses as if it were loaded at `start`.
+/// Every line ends with its bytes as ASCII text, with `.` in place of unprintable characters
+fn dump_data(data: &[u8], start: UWord) {
+    for (i, line) in data.chunks(16).enumerate() {
+        print!("{:016X} |", start as usize + i * 16);
+        for byte in line {
+            print!(" {:02X}", byte);
+        }
+
+        let text: String = line
+            .iter()
+            .map(|x| if is_printable(*x) { *x as char } else { '.' })
+            .collect();
+        println!("{:width$} | {}", "", text, width = (16 - line.len()) * 3);
+    }
+}
+
 fn assemble(args: &[String]) -> VoidResult {
-    if args.len() < 1 || args.len() > 2 {
+    let (flags, args) = split_flags(args, &["--dump-fixups", "--werror", "--listing", "--map"])?;
+    if args.is_empty() || args.len() > 2 {
         return Err(Error::new("Expected 1 or 2 arguments"));
     }
 
-    let source_path = Path::new(&args[0]);
+    let options = AssemblerOptions {
+        warnings_as_errors: flags.contains(&"--werror"),
+    };
+
+    let source_path = Path::new(args[0]);
     let result_path = if args.len() >= 2 {
-        Path::new(&args[1]).to_owned()
+        Path::new(args[1]).to_owned()
+    } else if args[0] == STDIO_PATH {
+        Path::new(STDIO_PATH).to_owned()
     } else {
         source_path.with_extension("bin")
     };
 
-    let mut source = File::open(source_path)?;
-    let mut result = File::create(result_path)?;
+    let to_stdout = result_path == Path::new(STDIO_PATH);
+    if to_stdout && flags.contains(&"--map") {
+        return Err(Error::new(
+            "--map needs an output file to name the map after",
+        ));
+    }
+
+    let source = String::from_utf8_lossy(&read_input(args[0])?).into_owned();
+    let mut result: Box<dyn Write> = if to_stdout {
+        Box::new(io::stdout())
+    } else {
+        Box::new(File::create(&result_path)?)
+    };
+
+    // Reports go to stderr when stdout is taken by the program itself
+    let mut fixups_output = report_output(to_stdout);
+    let mut listing_output = report_output(to_stdout);
+    let reports = Reports {
+        fixups: if flags.contains(&"--dump-fixups") {
+            Some(&mut fixups_output)
+        } else {
+            None
+        },
+        listing: if flags.contains(&"--listing") {
+            Some(&mut listing_output)
+        } else {
+            None
+        },
+    };
+
+    let (warnings, symbols) =
+        assembler::assemble_with_symbols(&mut source.as_bytes(), &mut result, &options, reports)
+            .map_err(|e| print_source_excerpt(e, &source))?;
+    result.flush()?;
+    print_warnings(&warnings);
+
+    if flags.contains(&"--map") {
+        write_symbol_map(&result_path.with_extension("map"), &symbols)?;
+    }
+
+    Ok(())
+}
+
+/// Where assembler reports are written to, which is stderr if the program is being written to stdout
+fn report_output(to_stdout: bool) -> Box<dyn Write> {
+    if to_stdout {
+        Box::new(io::stderr())
+    } else {
+        Box::new(io::stdout())
+    }
+}
+
+/// Reads the whole file at `path`, or all of stdin if the path is `-`
+fn read_input(path: &str) -> Result<Vec<u8>> {
+    if path == STDIO_PATH {
+        let mut data = Vec::new();
+        io::stdin().read_to_end(&mut data)?;
+        Ok(data)
+    } else {
+        Ok(fs::read(path)?)
+    }
+}
+
+/// Writes every label as `label -> value`, sorted by value so labels appear in the order they were placed
+fn write_symbol_map(path: &Path, symbols: &Symbols) -> VoidResult {
+    let mut symbols: Vec<_> = symbols.iter().collect();
+    symbols.sort_unstable_by_key(|(label, value)| (*value, *label));
+
+    let mut file = File::create(path)?;
+    for (label, value) in symbols {
+        writeln!(file, "{} -> {:016X}", label, value)?;
+    }
 
-    assembler::assemble(&mut source, &mut result)?;
     Ok(())
 }
 
 fn run(args: &[String]) -> VoidResult {
+    let (seed, args) = take_seed(args)?;
+    let (flags, args) = split_flags(&args, RUN_FLAGS)?;
     if args.len() != 1 {
         return Err(Error::new("Expected 1 argument"));
     }
 
-    let mut program_data = File::open(&args[0])?;
-    interpreter::run(&mut program_data)?;
-
-    Ok(())
+    let mut program_data = Cursor::new(read_input(args[0])?);
+    run_program(&mut program_data, &flags, seed)
 }
 
 fn assemble_and_run(args: &[String]) -> VoidResult {
+    let (seed, args) = take_seed(args)?;
+    let (flags, args) = split_flags(&args, RUN_FLAGS)?;
     if args.len() != 1 {
         return Err(Error::new("Expected 1 argument"));
     }
 
-    let mut source_file = File::open(&args[0])?;
+    let source = String::from_utf8_lossy(&read_input(args[0])?).into_owned();
     let mut program_data = Cursor::new(Vec::new());
 
-    assembler::assemble(&mut source_file, &mut program_data)?;
+    let warnings = assembler::assemble(
+        &mut source.as_bytes(),
+        &mut program_data,
+        &AssemblerOptions::default(),
+    )
+    .map_err(|e| print_source_excerpt(e, &source))?;
+    print_warnings(&warnings);
 
     program_data.seek(SeekFrom::Start(0))?;
+    run_program(&mut program_data, &flags, seed)
+}
+
+/// Shows where an assembler error happened in the source code, passing the error through so it can still be returned
+fn print_source_excerpt(error: assembler::Error, source: &str) -> assembler::Error {
+    eprint!("{}", error.source_excerpt(source));
+    error
+}
+
+fn print_warnings(warnings: &[Warning]) {
+    for warning in warnings {
+        eprintln!("{}", warning);
+    }
+}
+
+/// Loads and runs a program, printing extra information about the run as requested by `flags`
+fn run_program(program_data: &mut impl Read, flags: &[&str], seed: Option<u64>) -> VoidResult {
+    let options = RunOptions {
+        cycle_model: if flags.contains(&"--cycles") {
+            Some(CycleModel::default())
+        } else {
+            None
+        },
+        trace_memory: flags.contains(&"--trace-memory"),
+        seed,
+        quiet: flags.contains(&"--quiet"),
+        ..RunOptions::default()
+    };
+
+    let mut machine = Machine::load_with_options(program_data, &options)?;
 
-    interpreter::run(&mut program_data)?;
+    if options.trace_memory {
+        // Print the trace after every instruction so it lines up with the program's own output
+        loop {
+            if !options.quiet && machine.is_at_guest_breakpoint() {
+                println!("BREAKPOINT | {}", machine);
+            }
+
+            let running = machine.step()?;
+            for access in machine.take_memory_trace() {
+                println!("MEMTRACE | {}", access);
+            }
+
+            if !running {
+                break;
+            }
+        }
+    } else {
+        machine.run()?;
+    }
+
+    if flags.contains(&"--dump-on-halt") {
+        println!("LAKESIS | {}", machine);
+        println!("{}", machine.memory_dump());
+    }
+
+    if let Some(cycles) = machine.cycles() {
+        println!("LAKESIS | Simulated cycles: {}", cycles);
+    }
 
     Ok(())
 }
+
+/// Removes `--seed N` from the arguments, returning the parsed seed if it was present
+fn take_seed(args: &[String]) -> Result<(Option<u64>, Vec<String>)> {
+    let index = match args.iter().position(|x| x == "--seed") {
+        Some(x) => x,
+        None => return Ok((None, args.to_vec())),
+    };
+
+    let seed = args
+        .get(index + 1)
+        .ok_or_else(|| Error::new("Expected a value after --seed"))?;
+    let seed = seed
+        .parse()
+        .map_err(|_| Error::new(&format!("Invalid seed {}", seed)))?;
+
+    let mut rest = args.to_vec();
+    rest.drain(index..=index + 1);
+    Ok((Some(seed), rest))
+}
+
+/// Separates `--flag` arguments from positional arguments, rejecting unknown flags
+fn split_flags<'a>(
+    args: &'a [String],
+    known_flags: &[&str],
+) -> Result<(Vec<&'a str>, Vec<&'a str>)> {
+    let mut flags = Vec::new();
+    let mut positional = Vec::new();
+
+    for arg in args {
+        if !arg.starts_with("--") {
+            positional.push(arg as &str);
+        } else if known_flags.contains(&(arg as &str)) {
+            flags.push(arg as &str);
+        } else {
+            return Err(Error::new(&format!("Unknown flag {}", arg)));
+        }
+    }
+
+    Ok((flags, positional))
+}
@@ -1,13 +1,14 @@
-use crate::core::{Error, IWord, RegisterIndex, Result, UWord};
+use crate::core::{Error, IWord, RegisterIndex, Result, UWord, VoidResult, REGISTER_NUM, WORD_BYTE_SIZE};
 use std::collections::HashMap;
 use std::fmt::{LowerHex, UpperHex, Display, Formatter, Result as FmtResult};
-use std::io::Read;
+use std::io::{Read, Write};
 use std::slice;
 
 /**
  * The smallest unit of computation that can be fully executed with no
  * extra data required.
  */
+#[derive(PartialEq, Eq, Clone, Debug)]
 pub struct Opcode {
     pub instruction: Instruction,
     pub operands: Vec<Operand>,
@@ -49,6 +50,21 @@ pub enum Instruction {
     Reference = 0x1A,
     Unreference = 0x1B,
     CallNative = 0x1C,
+    IsReference = 0x1D,
+    FetchAdd = 0x1E,
+    GetInstructionPointer = 0x1F,
+    ObjectId = 0x20,
+    ClearFlags = 0x21,
+    Breakpoint = 0x22,
+    NewAligned = 0x23,
+    SignedDivide = 0x24,
+    SignedCompare = 0x25,
+    Abort = 0x26,
+    Test = 0x27,
+    MoveIfZero = 0x28,
+    MoveIfNotZero = 0x29,
+    MoveIfCarry = 0x2A,
+    MoveIfNotCarry = 0x2B,
     DebugMemory = 0x3C,
     DebugDump = 0x3D,
     DebugCpu = 0x3E,
@@ -58,12 +74,33 @@ pub enum Instruction {
 /// Useful metadata about an instruction
 #[derive(PartialEq, Eq, Copy, Clone)]
 pub struct InstructionDescriptor {
-    /// Operands this instruction expects
+    /// Operands this instruction accepts
     pub operands: &'static [OperandMode],
+    /// Number of operands that must always be provided. Instructions that accept a variable number of operands
+    /// have fewer required operands than the ones listed in `operands`
+    pub min_operands: usize,
     /// Mnemonic used to represent this instruction for the user
     pub mnemonic: &'static str,
     /// If this instruction causes a jump
     pub is_jump: bool,
+    /// Default cost of this instruction when simulating cycle counts
+    pub cycles: u64,
+}
+
+impl InstructionDescriptor {
+    /// Checks if this instruction can be used with `count` operands
+    pub fn accepts_operand_count(&self, count: usize) -> bool {
+        count >= self.min_operands && count <= self.operands.len()
+    }
+
+    /// Describes how many operands this instruction accepts, such as `2` or `1 to 3`
+    pub fn operand_count_description(&self) -> String {
+        if self.min_operands == self.operands.len() {
+            self.min_operands.to_string()
+        } else {
+            format!("{} to {}", self.min_operands, self.operands.len())
+        }
+    }
 }
 
 /// Mode of use of an operand
@@ -79,7 +116,7 @@ pub enum OperandMode {
  * An argument used by instructions to identify the location where data will be read
  * or written to.
  */
-#[derive(PartialEq, Eq, Copy, Clone)]
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
 pub enum Operand {
     /// A hardcoded value that is always the same
     Immediate(IWord),
@@ -94,6 +131,13 @@ pub enum Operand {
     },
     /// A stack value
     Stack(UWord),
+    /// A location in memory referenced by a register, offset by the value of another register
+    Indexed {
+        /// Register that contains the memory reference
+        base: RegisterIndex,
+        /// Register whose value is added to the reference before dereferencing it
+        index: RegisterIndex,
+    },
 }
 
 /// Repository of instruction data and metadata
@@ -112,32 +156,68 @@ fn read_byte(read: &mut impl Read) -> Result<u8> {
 
 impl Opcode {
     pub fn decode(read: &mut impl Read) -> Result<Opcode> {
+        let opcode = Self::decode_lenient(read)?;
+        opcode.validate()?;
+        Ok(opcode)
+    }
+
+    /// Same as `decode`, but doesn't check the operands against the instruction's descriptor.
+    /// Meant for tools that need to show malformed programs, such as the disassembler
+    pub fn decode_lenient(read: &mut impl Read) -> Result<Opcode> {
         let first_byte = read_byte(read)?;
 
-        let operand_count = ((first_byte & !Instruction::MASK) >> Instruction::SHIFT) as usize;
+        let mut operand_count = (first_byte & !Instruction::MASK) >> Instruction::SHIFT;
         let instruction_id = first_byte & Instruction::MASK;
 
         let instruction = Instruction::decode(instruction_id)?;
+        if operand_count == Instruction::EXTENDED_COUNT {
+            operand_count = read_byte(read)?;
+            if operand_count < Instruction::EXTENDED_COUNT {
+                return Err(Error::new(&format!(
+                    "Operand count {} must be stored in the opcode byte",
+                    operand_count
+                )));
+            }
+        }
+
+        let operand_count = operand_count as usize;
         let mut operands = Vec::with_capacity(operand_count);
         for _ in 0..operand_count {
             operands.push(Operand::decode(read)?);
         }
 
-        let descriptor = instruction.descriptor();
-        if descriptor.operands.len() != operands.len() {
+        Ok(Opcode {
+            instruction,
+            operands,
+        })
+    }
+
+    /// Writes the bytes that `decode_lenient` reads back into this same opcode.
+    /// The operands aren't checked against the instruction, use `validate` for that
+    pub fn encode(&self, write: &mut impl Write) -> VoidResult {
+        self.instruction.encode_header(self.operands.len(), write)?;
+
+        for operand in &self.operands {
+            operand.encode(write)?;
+        }
+
+        Ok(())
+    }
+
+    /// Checks that the number of operands and their modes are accepted by the instruction
+    pub fn validate(&self) -> VoidResult {
+        let descriptor = self.instruction.descriptor();
+        if !descriptor.accepts_operand_count(self.operands.len()) {
             return Err(Error::new(&format!(
                 "Instruction {} expects {} operands, but {} were provided",
-                instruction,
-                descriptor.operands.len(),
-                operands.len()
+                self.instruction,
+                descriptor.operand_count_description(),
+                self.operands.len()
             )));
         }
 
-        for i in 0..descriptor.operands.len() {
-            let expected = descriptor.operands[i];
-            let actual = operands[i];
-
-            if !actual.mode().can_be_used_as(&expected) {
+        for (expected, actual) in descriptor.operands.iter().zip(&self.operands) {
+            if !actual.mode().can_be_used_as(expected) {
                 return Err(Error::new(&format!(
                     "Operand {} cannot be used as {}",
                     actual, expected
@@ -145,10 +225,7 @@ impl Opcode {
             }
         }
 
-        Ok(Opcode {
-            instruction,
-            operands,
-        })
+        Ok(())
     }
 }
 
@@ -157,12 +234,12 @@ impl Display for Opcode {
         write!(fmt, "{}", self.instruction)?;
 
         let is_jump = self.instruction.descriptor().is_jump;
-        for i in 0..self.operands.len() {
+        for (i, operand) in self.operands.iter().enumerate() {
 
             if is_jump {
-                write!(fmt, " {:X}", self.operands[i])?;
+                write!(fmt, " {:X}", operand)?;
             } else {
-                write!(fmt, " {}", self.operands[i])?;
+                write!(fmt, " {}", operand)?;
             }
 
             if i < self.operands.len() - 1 {
@@ -177,6 +254,35 @@ impl Display for Opcode {
 impl Instruction {
     pub const MASK: u8 = 0b0011_1111;
     pub const SHIFT: usize = 6;
+    /// Operand count stored in the opcode byte when the real count is too big for it,
+    /// meaning that the real count is in the byte right after the opcode byte
+    pub const EXTENDED_COUNT: u8 = 0b11;
+
+    /// First byte of an opcode with this instruction and `operand_count` operands.
+    /// Only the lowest two bits of `operand_count` fit, see `encode_header` for bigger counts
+    pub fn opcode_byte(self, operand_count: u8) -> u8 {
+        (self as u8 & Self::MASK) | ((operand_count << Self::SHIFT) & !Self::MASK)
+    }
+
+    /// Writes the opcode byte for this instruction with `operand_count` operands,
+    /// followed by a byte with the count if it's too big to fit in the opcode byte
+    pub fn encode_header(self, operand_count: usize, write: &mut impl Write) -> VoidResult {
+        if operand_count < Self::EXTENDED_COUNT as usize {
+            write.write_all(&[self.opcode_byte(operand_count as u8)])?;
+            return Ok(());
+        }
+
+        if operand_count > u8::MAX as usize {
+            return Err(Error::new(&format!(
+                "Instructions can't have more than {} operands, but {} were provided",
+                u8::MAX,
+                operand_count
+            )));
+        }
+
+        write.write_all(&[self.opcode_byte(Self::EXTENDED_COUNT), operand_count as u8])?;
+        Ok(())
+    }
 
     pub fn decode(value: u8) -> Result<Instruction> {
         Self::from_value(value).ok_or(Error::new(&format!(
@@ -211,20 +317,20 @@ impl InstructionRepository {
     fn get_descriptor(instr: &Instruction) -> InstructionDescriptor {
         INSTRUCTION_REPOSITORY.with(|r| 
             // All instructions must have a descriptor
-            r.descriptors.get(instr).map(Clone::clone).unwrap()
+            r.descriptors.get(instr).copied().unwrap()
         )
     }
 
     fn find_by_mnemonic(mnemonic: &str) -> Option<Instruction> {
         INSTRUCTION_REPOSITORY.with(|r| {
             let mnemonic = mnemonic.to_lowercase();
-            r.by_mnemonic.get(&mnemonic[..]).map(Clone::clone)
+            r.by_mnemonic.get(&mnemonic[..]).copied()
         })
     }
 
     fn find_by_value(value: u8) -> Option<Instruction> {
         INSTRUCTION_REPOSITORY.with(|r|
-            r.by_value.get(&value).map(Clone::clone)
+            r.by_value.get(&value).copied()
         )
     }
 
@@ -235,7 +341,9 @@ impl InstructionRepository {
             InstructionDescriptor {
                 mnemonic: "nop",
                 operands: &[],
+                min_operands: 0,
                 is_jump: false,
+                cycles: 1,
             },
         );
         descriptors.insert(
@@ -243,7 +351,9 @@ impl InstructionRepository {
             InstructionDescriptor {
                 mnemonic: "halt",
                 operands: &[],
+                min_operands: 0,
                 is_jump: false,
+                cycles: 1,
             },
         );
         descriptors.insert(
@@ -251,7 +361,9 @@ impl InstructionRepository {
             InstructionDescriptor {
                 mnemonic: "add",
                 operands: &[OperandMode::ReadOnly, OperandMode::ReadWrite],
+                min_operands: 2,
                 is_jump: false,
+                cycles: 1,
             },
         );
         descriptors.insert(
@@ -259,7 +371,9 @@ impl InstructionRepository {
             InstructionDescriptor {
                 mnemonic: "sub",
                 operands: &[OperandMode::ReadOnly, OperandMode::ReadWrite],
+                min_operands: 2,
                 is_jump: false,
+                cycles: 1,
             },
         );
         descriptors.insert(
@@ -267,7 +381,9 @@ impl InstructionRepository {
             InstructionDescriptor {
                 mnemonic: "mul",
                 operands: &[OperandMode::ReadOnly, OperandMode::ReadWrite],
+                min_operands: 2,
                 is_jump: false,
+                cycles: 3,
             },
         );
         descriptors.insert(
@@ -275,7 +391,9 @@ impl InstructionRepository {
             InstructionDescriptor {
                 mnemonic: "div",
                 operands: &[OperandMode::ReadOnly, OperandMode::ReadWrite],
+                min_operands: 2,
                 is_jump: false,
+                cycles: 10,
             },
         );
         descriptors.insert(
@@ -283,7 +401,9 @@ impl InstructionRepository {
             InstructionDescriptor {
                 mnemonic: "and",
                 operands: &[OperandMode::ReadOnly, OperandMode::ReadWrite],
+                min_operands: 2,
                 is_jump: false,
+                cycles: 1,
             },
         );
         descriptors.insert(
@@ -291,7 +411,9 @@ impl InstructionRepository {
             InstructionDescriptor {
                 mnemonic: "or",
                 operands: &[OperandMode::ReadOnly, OperandMode::ReadWrite],
+                min_operands: 2,
                 is_jump: false,
+                cycles: 1,
             },
         );
         descriptors.insert(
@@ -299,7 +421,9 @@ impl InstructionRepository {
             InstructionDescriptor {
                 mnemonic: "xor",
                 operands: &[OperandMode::ReadOnly, OperandMode::ReadWrite],
+                min_operands: 2,
                 is_jump: false,
+                cycles: 1,
             },
         );
         descriptors.insert(
@@ -307,7 +431,9 @@ impl InstructionRepository {
             InstructionDescriptor {
                 mnemonic: "not",
                 operands: &[OperandMode::ReadWrite],
+                min_operands: 1,
                 is_jump: false,
+                cycles: 1,
             },
         );
         descriptors.insert(
@@ -315,7 +441,9 @@ impl InstructionRepository {
             InstructionDescriptor {
                 mnemonic: "shl",
                 operands: &[OperandMode::ReadOnly, OperandMode::ReadWrite],
+                min_operands: 2,
                 is_jump: false,
+                cycles: 1,
             },
         );
         descriptors.insert(
@@ -323,7 +451,9 @@ impl InstructionRepository {
             InstructionDescriptor {
                 mnemonic: "shr",
                 operands: &[OperandMode::ReadOnly, OperandMode::ReadWrite],
+                min_operands: 2,
                 is_jump: false,
+                cycles: 1,
             },
         );
         descriptors.insert(
@@ -331,7 +461,9 @@ impl InstructionRepository {
             InstructionDescriptor {
                 mnemonic: "cmp",
                 operands: &[OperandMode::ReadOnly, OperandMode::ReadOnly],
+                min_operands: 2,
                 is_jump: false,
+                cycles: 1,
             },
         );
         descriptors.insert(
@@ -339,7 +471,9 @@ impl InstructionRepository {
             InstructionDescriptor {
                 mnemonic: "jmp",
                 operands: &[OperandMode::ReadOnly],
+                min_operands: 1,
                 is_jump: true,
+                cycles: 2,
             },
         );
         descriptors.insert(
@@ -347,7 +481,9 @@ impl InstructionRepository {
             InstructionDescriptor {
                 mnemonic: "jeq",
                 operands: &[OperandMode::ReadOnly],
+                min_operands: 1,
                 is_jump: true,
+                cycles: 2,
             },
         );
         descriptors.insert(
@@ -355,7 +491,9 @@ impl InstructionRepository {
             InstructionDescriptor {
                 mnemonic: "jne",
                 operands: &[OperandMode::ReadOnly],
+                min_operands: 1,
                 is_jump: true,
+                cycles: 2,
             },
         );
         descriptors.insert(
@@ -363,7 +501,9 @@ impl InstructionRepository {
             InstructionDescriptor {
                 mnemonic: "jgt",
                 operands: &[OperandMode::ReadOnly],
+                min_operands: 1,
                 is_jump: true,
+                cycles: 2,
             },
         );
         descriptors.insert(
@@ -371,7 +511,9 @@ impl InstructionRepository {
             InstructionDescriptor {
                 mnemonic: "jge",
                 operands: &[OperandMode::ReadOnly],
+                min_operands: 1,
                 is_jump: true,
+                cycles: 2,
             },
         );
         descriptors.insert(
@@ -379,7 +521,9 @@ impl InstructionRepository {
             InstructionDescriptor {
                 mnemonic: "jlt",
                 operands: &[OperandMode::ReadOnly],
+                min_operands: 1,
                 is_jump: true,
+                cycles: 2,
             },
         );
         descriptors.insert(
@@ -387,7 +531,9 @@ impl InstructionRepository {
             InstructionDescriptor {
                 mnemonic: "jle",
                 operands: &[OperandMode::ReadOnly],
+                min_operands: 1,
                 is_jump: true,
+                cycles: 2,
             },
         );
         descriptors.insert(
@@ -395,7 +541,9 @@ impl InstructionRepository {
             InstructionDescriptor {
                 mnemonic: "call",
                 operands: &[OperandMode::ReadOnly],
+                min_operands: 1,
                 is_jump: true,
+                cycles: 3,
             },
         );
         descriptors.insert(
@@ -403,7 +551,9 @@ impl InstructionRepository {
             InstructionDescriptor {
                 mnemonic: "ret",
                 operands: &[],
+                min_operands: 0,
                 is_jump: true,
+                cycles: 3,
             },
         );
         descriptors.insert(
@@ -411,7 +561,9 @@ impl InstructionRepository {
             InstructionDescriptor {
                 mnemonic: "mov",
                 operands: &[OperandMode::ReadOnly, OperandMode::ReadWrite],
+                min_operands: 2,
                 is_jump: false,
+                cycles: 1,
             },
         );
         descriptors.insert(
@@ -419,7 +571,9 @@ impl InstructionRepository {
             InstructionDescriptor {
                 mnemonic: "push",
                 operands: &[OperandMode::ReadOnly],
+                min_operands: 1,
                 is_jump: false,
+                cycles: 2,
             },
         );
         descriptors.insert(
@@ -427,7 +581,9 @@ impl InstructionRepository {
             InstructionDescriptor {
                 mnemonic: "pop",
                 operands: &[OperandMode::ReadWrite],
+                min_operands: 1,
                 is_jump: false,
+                cycles: 2,
             },
         );
         descriptors.insert(
@@ -435,7 +591,9 @@ impl InstructionRepository {
             InstructionDescriptor {
                 mnemonic: "new",
                 operands: &[OperandMode::ReadOnly, OperandMode::ReadWrite],
+                min_operands: 2,
                 is_jump: false,
+                cycles: 10,
             },
         );
         descriptors.insert(
@@ -443,7 +601,9 @@ impl InstructionRepository {
             InstructionDescriptor {
                 mnemonic: "gc",
                 operands: &[],
+                min_operands: 0,
                 is_jump: false,
+                cycles: 50,
             },
         );
         descriptors.insert(
@@ -451,7 +611,9 @@ impl InstructionRepository {
             InstructionDescriptor {
                 mnemonic: "ref",
                 operands: &[OperandMode::ReadWrite],
+                min_operands: 1,
                 is_jump: false,
+                cycles: 1,
             },
         );
         descriptors.insert(
@@ -459,7 +621,167 @@ impl InstructionRepository {
             InstructionDescriptor {
                 mnemonic: "unref",
                 operands: &[OperandMode::ReadWrite],
+                min_operands: 1,
+                is_jump: false,
+                cycles: 1,
+            },
+        );
+        descriptors.insert(
+            Instruction::IsReference,
+            InstructionDescriptor {
+                mnemonic: "isref",
+                operands: &[OperandMode::ReadOnly],
+                min_operands: 1,
+                is_jump: false,
+                cycles: 1,
+            },
+        );
+        descriptors.insert(
+            Instruction::FetchAdd,
+            InstructionDescriptor {
+                mnemonic: "fetchadd",
+                operands: &[
+                    OperandMode::ReadWrite,
+                    OperandMode::ReadOnly,
+                    OperandMode::ReadWrite,
+                ],
+                min_operands: 3,
+                is_jump: false,
+                cycles: 3,
+            },
+        );
+        descriptors.insert(
+            Instruction::GetInstructionPointer,
+            InstructionDescriptor {
+                mnemonic: "movip",
+                operands: &[OperandMode::ReadWrite],
+                min_operands: 1,
+                is_jump: false,
+                cycles: 1,
+            },
+        );
+        descriptors.insert(
+            Instruction::ObjectId,
+            InstructionDescriptor {
+                mnemonic: "objid",
+                operands: &[OperandMode::ReadOnly, OperandMode::ReadWrite],
+                min_operands: 2,
+                is_jump: false,
+                cycles: 1,
+            },
+        );
+        descriptors.insert(
+            Instruction::ClearFlags,
+            InstructionDescriptor {
+                mnemonic: "clf",
+                operands: &[],
+                min_operands: 0,
+                is_jump: false,
+                cycles: 1,
+            },
+        );
+        descriptors.insert(
+            Instruction::Breakpoint,
+            InstructionDescriptor {
+                mnemonic: "brk",
+                operands: &[],
+                min_operands: 0,
+                is_jump: false,
+                cycles: 1,
+            },
+        );
+        descriptors.insert(
+            Instruction::NewAligned,
+            InstructionDescriptor {
+                mnemonic: "newaligned",
+                operands: &[
+                    OperandMode::ReadOnly,
+                    OperandMode::ReadOnly,
+                    OperandMode::ReadWrite,
+                ],
+                min_operands: 3,
+                is_jump: false,
+                cycles: 10,
+            },
+        );
+        descriptors.insert(
+            Instruction::SignedDivide,
+            InstructionDescriptor {
+                mnemonic: "idiv",
+                operands: &[OperandMode::ReadOnly, OperandMode::ReadWrite],
+                min_operands: 2,
                 is_jump: false,
+                cycles: 10,
+            },
+        );
+        descriptors.insert(
+            Instruction::SignedCompare,
+            InstructionDescriptor {
+                mnemonic: "icmp",
+                operands: &[OperandMode::ReadOnly, OperandMode::ReadOnly],
+                min_operands: 2,
+                is_jump: false,
+                cycles: 1,
+            },
+        );
+        descriptors.insert(
+            Instruction::Abort,
+            InstructionDescriptor {
+                mnemonic: "abort",
+                operands: &[OperandMode::ReadOnly, OperandMode::ReadOnly],
+                min_operands: 2,
+                is_jump: false,
+                cycles: 1,
+            },
+        );
+        descriptors.insert(
+            Instruction::Test,
+            InstructionDescriptor {
+                mnemonic: "tst",
+                operands: &[OperandMode::ReadOnly, OperandMode::ReadOnly],
+                min_operands: 2,
+                is_jump: false,
+                cycles: 1,
+            },
+        );
+        descriptors.insert(
+            Instruction::MoveIfZero,
+            InstructionDescriptor {
+                mnemonic: "cmovz",
+                operands: &[OperandMode::ReadOnly, OperandMode::ReadWrite],
+                min_operands: 2,
+                is_jump: false,
+                cycles: 1,
+            },
+        );
+        descriptors.insert(
+            Instruction::MoveIfNotZero,
+            InstructionDescriptor {
+                mnemonic: "cmovnz",
+                operands: &[OperandMode::ReadOnly, OperandMode::ReadWrite],
+                min_operands: 2,
+                is_jump: false,
+                cycles: 1,
+            },
+        );
+        descriptors.insert(
+            Instruction::MoveIfCarry,
+            InstructionDescriptor {
+                mnemonic: "cmovc",
+                operands: &[OperandMode::ReadOnly, OperandMode::ReadWrite],
+                min_operands: 2,
+                is_jump: false,
+                cycles: 1,
+            },
+        );
+        descriptors.insert(
+            Instruction::MoveIfNotCarry,
+            InstructionDescriptor {
+                mnemonic: "cmovnc",
+                operands: &[OperandMode::ReadOnly, OperandMode::ReadWrite],
+                min_operands: 2,
+                is_jump: false,
+                cycles: 1,
             },
         );
         descriptors.insert(
@@ -467,15 +789,19 @@ impl InstructionRepository {
             InstructionDescriptor {
                 mnemonic: "native",
                 operands: &[OperandMode::ReadOnly],
+                min_operands: 1,
                 is_jump: false,
+                cycles: 10,
             },
         );
         descriptors.insert(
             Instruction::DebugCpu,
             InstructionDescriptor {
                 mnemonic: "debugcpu",
-                operands: &[OperandMode::ReadOnly],
+                operands: &[OperandMode::ReadOnly; 8],
+                min_operands: 1,
                 is_jump: false,
+                cycles: 1,
             },
         );
         descriptors.insert(
@@ -483,7 +809,9 @@ impl InstructionRepository {
             InstructionDescriptor {
                 mnemonic: "debugdump",
                 operands: &[OperandMode::ReadOnly, OperandMode::ReadOnly],
+                min_operands: 2,
                 is_jump: false,
+                cycles: 1,
             },
         );
         descriptors.insert(
@@ -491,7 +819,9 @@ impl InstructionRepository {
             InstructionDescriptor {
                 mnemonic: "debugmem",
                 operands: &[],
+                min_operands: 0,
                 is_jump: false,
+                cycles: 1,
             },
         );
 
@@ -499,8 +829,8 @@ impl InstructionRepository {
         let mut by_value = HashMap::new();
 
         for (instr, descr) in descriptors.iter() {
-            by_mnemonic.insert(descr.mnemonic, instr.clone());
-            by_value.insert(*instr as u8, instr.clone());
+            by_mnemonic.insert(descr.mnemonic, *instr);
+            by_value.insert(*instr as u8, *instr);
         }
 
         InstructionRepository {
@@ -525,7 +855,7 @@ impl OperandMode {
         }
 
         // Otherwise, not allowed
-        return false;
+        false
     }
 }
 
@@ -551,27 +881,140 @@ impl Operand {
     pub const VALUE_SIZE_MASK: u8 = 0b0000_0111;
     pub const VALUE_SIZE_SHIFT: usize = 0;
 
+    /// Value size field that means a full word of value bytes follows, instead of 7 bytes.
+    /// Values that need exactly 7 bytes are stored with 8 instead
+    pub const FULL_WORD_VALUE_SIZE: u8 = 0b111;
+
+    /// Largest number of value bytes an operand can have
+    pub const MAX_VALUE_SIZE: usize = WORD_BYTE_SIZE as usize;
+
+    /// Number of value bytes that follow an operand whose value size field is `field`
+    pub fn value_size_from_field(field: u8) -> usize {
+        if field == Self::FULL_WORD_VALUE_SIZE {
+            Self::MAX_VALUE_SIZE
+        } else {
+            field as usize
+        }
+    }
+
+    /// Writes the bytes that `decode` reads back into this same operand,
+    /// using as few value bytes as possible
+    pub fn encode(&self, write: &mut impl Write) -> VoidResult {
+        let value_size = self.encoded_size() - 1;
+        self.encode_with_value_size(write, value_size)
+    }
+
+    /// Same as `encode`, but always writes `value_size` value bytes.
+    /// Meant for placeholders that are overwritten later with a value that isn't known yet
+    pub fn encode_with_value_size(&self, write: &mut impl Write, value_size: usize) -> VoidResult {
+        let (addressing_mode, register, is_negative, value) = match *self {
+            Operand::Immediate(x) => (0b00, 0, x < 0, x.unsigned_abs()),
+            Operand::Register(r) => (0b01, r, false, 0),
+            Operand::Reference { register, offset } => {
+                (0b10, register, offset < 0, offset.unsigned_abs())
+            }
+            Operand::Stack(x) => (0b11, 0, false, x),
+            // Stack offsets are never negative, so a stack operand with the sign bit set is an indexed one instead
+            Operand::Indexed { base, index } => (0b11, base, true, index as UWord),
+        };
+
+        let index = match *self {
+            Operand::Indexed { index, .. } => index,
+            _ => 0,
+        };
+        for register in [register, index] {
+            if register as usize >= REGISTER_NUM {
+                return Err(Error::new(&format!("Register R{} doesn't exist", register)));
+            }
+        }
+
+        // Trailing zeroes are implied by the value size, so a value of 0 can be encoded with no bytes at all
+        let needed_size = Self::value_size(value);
+        if value_size > Self::MAX_VALUE_SIZE
+            || value_size == Self::FULL_WORD_VALUE_SIZE as usize
+            || value_size < needed_size
+        {
+            return Err(Error::new(&format!(
+                "Operand value {} can't be encoded with {} value bytes",
+                value, value_size
+            )));
+        }
+
+        let size_field = if value_size == Self::MAX_VALUE_SIZE {
+            Self::FULL_WORD_VALUE_SIZE
+        } else {
+            value_size as u8
+        };
+        let mut first_byte = (addressing_mode << Self::ADDRESSING_MODE_SHIFT)
+            | (register << Self::REGISTER_NUM_SHIFT)
+            | (size_field << Self::VALUE_SIZE_SHIFT);
+        if is_negative {
+            first_byte |= Self::SIGN_MASK;
+        }
+
+        write.write_all(slice::from_ref(&first_byte))?;
+        write.write_all(&value.to_le_bytes()[..value_size])?;
+        Ok(())
+    }
+
+    /// Number of bytes this operand takes up once encoded with `encode`, including its first byte
+    pub fn encoded_size(&self) -> usize {
+        let value = match *self {
+            Operand::Immediate(x) => x.unsigned_abs(),
+            Operand::Register(_) => 0,
+            Operand::Reference { offset, .. } => offset.unsigned_abs(),
+            Operand::Stack(x) => x,
+            Operand::Indexed { index, .. } => index as UWord,
+        };
+
+        1 + Self::value_size(value)
+    }
+
+    /// Number of bytes needed to store `value`, leaving out the trailing zeroes implied by the value size.
+    /// 7 bytes can't be represented, so those values take a full word
+    fn value_size(value: UWord) -> usize {
+        match WORD_BYTE_SIZE as usize - value.leading_zeros() as usize / 8 {
+            x if x >= Self::FULL_WORD_VALUE_SIZE as usize => Self::MAX_VALUE_SIZE,
+            x => x,
+        }
+    }
+
     fn decode(read: &mut impl Read) -> Result<Operand> {
         let first_byte = read_byte(read)?;
 
         let addr_mode = (first_byte & Self::ADDRESSING_MODE_MASK) >> Self::ADDRESSING_MODE_SHIFT;
         let register_num = (first_byte & Self::REGISTER_NUM_MASK) >> Self::REGISTER_NUM_SHIFT;
         let sign = (first_byte & Self::SIGN_MASK) >> Self::SIGN_SHIFT;
-        let value_size = ((first_byte & Self::VALUE_SIZE_MASK) >> Self::VALUE_SIZE_SHIFT) as usize;
-
-        let mut value_bytes = Vec::with_capacity(value_size);
-        for _ in 0..value_size {
-            value_bytes.push(0u8);
-        }
-        read.read_exact(&mut value_bytes)?;
+        let value_size = Self::value_size_from_field(
+            (first_byte & Self::VALUE_SIZE_MASK) >> Self::VALUE_SIZE_SHIFT,
+        );
 
+        // A value size of 0 means no value bytes follow and the value is 0.
+        // The assembler relies on this to encode zero immediates such as `mov 0, R0` in a single byte
         let mut value_padded_bytes = [0u8; 8];
-        for i in 0..value_size {
-            value_padded_bytes[i] = value_bytes[i];
-        }
+        read.read_exact(&mut value_padded_bytes[..value_size])?;
 
         let uvalue = UWord::from_le_bytes(value_padded_bytes);
-        let ivalue = uvalue as IWord * if sign == 0 { 1 } else { -1 };
+        // Wrapping, as the magnitude of the smallest negative number doesn't fit in a positive one
+        let ivalue = if sign == 0 {
+            uvalue as IWord
+        } else {
+            (uvalue as IWord).wrapping_neg()
+        };
+
+        // Only 2 bits are read today so this can't fail yet, but it keeps a wider register field
+        // from turning into an out of bounds index in the interpreter
+        let is_indexed = addr_mode == 0b11 && sign != 0;
+        let uses_register = addr_mode == 0b01 || addr_mode == 0b10 || is_indexed;
+        if uses_register && register_num as usize >= REGISTER_NUM {
+            return Err(Error::new(&format!(
+                "Register R{} doesn't exist",
+                register_num
+            )));
+        }
+        if is_indexed && uvalue >= REGISTER_NUM as UWord {
+            return Err(Error::new(&format!("Register R{} doesn't exist", uvalue)));
+        }
 
         match addr_mode {
             0b00 => Ok(Operand::Immediate(ivalue)),
@@ -580,6 +1023,10 @@ impl Operand {
                 register: register_num,
                 offset: ivalue,
             }),
+            0b11 if is_indexed => Ok(Operand::Indexed {
+                base: register_num,
+                index: uvalue as RegisterIndex,
+            }),
             0b11 => Ok(Operand::Stack(uvalue)),
             x => Err(Error::new(&format!("Invalid addressing mode {:2b}", x))),
         }
@@ -605,6 +1052,7 @@ impl Display for Operand {
             Operand::Reference { register, offset } => write!(fmt, "[R{}{:+}]", register, offset),
             Operand::Stack(0) => write!(fmt, "[SP]"),
             Operand::Stack(offset) => write!(fmt, "[SP{:+}]", offset),
+            Operand::Indexed { base, index } => write!(fmt, "[R{}+R{}]", base, index),
         }
     }
 }
@@ -621,6 +1069,7 @@ impl LowerHex for Operand {
             Operand::Reference { register, offset } => write!(fmt, "[R{}{:+}]", register, offset),
             Operand::Stack(0) => write!(fmt, "[SP]"),
             Operand::Stack(offset) => write!(fmt, "[SP{:+}]", offset),
+            Operand::Indexed { base, index } => write!(fmt, "[R{}+R{}]", base, index),
         }
     }
 }
@@ -637,6 +1086,176 @@ impl UpperHex for Operand {
             Operand::Reference { register, offset } => write!(fmt, "[R{}{:+}]", register, offset),
             Operand::Stack(0) => write!(fmt, "[SP]"),
             Operand::Stack(offset) => write!(fmt, "[SP{:+}]", offset),
+            Operand::Indexed { base, index } => write!(fmt, "[R{}+R{}]", base, index),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::{self, AssemblerOptions};
+
+    /// Operands that can be used anywhere, as assembly source and the operand it should decode to.
+    /// Values that need all 8 bytes make sure the biggest value size survives the round trip
+    const READ_WRITE_OPERANDS: &[(&str, Operand)] = &[
+        ("r1", Operand::Register(1)),
+        (
+            "[r2]",
+            Operand::Reference {
+                register: 2,
+                offset: 0,
+            },
+        ),
+        (
+            "[r3-0x1122334455667788]",
+            Operand::Reference {
+                register: 3,
+                offset: -0x1122_3344_5566_7788,
+            },
+        ),
+        ("[r3+r0]", Operand::Indexed { base: 3, index: 0 }),
+        (
+            "[sp+0x7EDCBA9876543210]",
+            Operand::Stack(0x7EDC_BA98_7654_3210),
+        ),
+    ];
+
+    /// Operands that can only be read from, which also includes every read-write operand
+    const READ_ONLY_OPERANDS: &[(&str, Operand)] = &[
+        ("0", Operand::Immediate(0)),
+        ("-2", Operand::Immediate(-2)),
+        (
+            "0x7FEDCBA987654321",
+            Operand::Immediate(0x7FED_CBA9_8765_4321),
+        ),
+        ("[r0+r1]", Operand::Indexed { base: 0, index: 1 }),
+    ];
+
+    fn assemble_single(source: &str) -> Vec<u8> {
+        let mut program = Vec::new();
+        assembler::assemble(
+            &mut source.as_bytes(),
+            &mut program,
+            &AssemblerOptions::default(),
+        )
+        .unwrap_or_else(|e| panic!("Failed to assemble {}: {}", source, e));
+        program
+    }
+
+    #[test]
+    fn every_instruction_round_trips_through_the_assembler() {
+        let instructions: Vec<Instruction> = (0..=Instruction::MASK)
+            .filter_map(Instruction::from_value)
+            .collect();
+        let read_only: Vec<&(&str, Operand)> = READ_ONLY_OPERANDS
+            .iter()
+            .chain(READ_WRITE_OPERANDS)
+            .collect();
+        let rows = read_only.len().max(READ_WRITE_OPERANDS.len());
+
+        for instruction in instructions {
+            let descriptor = instruction.descriptor();
+            let mut counts = vec![descriptor.min_operands, descriptor.operands.len()];
+            counts.dedup();
+
+            for count in counts {
+                for row in 0..rows {
+                    // Each position takes a different row of the table, so every kind shows up in every position
+                    let operands: Vec<&(&str, Operand)> = descriptor.operands[..count]
+                        .iter()
+                        .enumerate()
+                        .map(|(i, mode)| match mode {
+                            OperandMode::ReadOnly => read_only[(row + i) % read_only.len()],
+                            OperandMode::ReadWrite => {
+                                &READ_WRITE_OPERANDS[(row + i) % READ_WRITE_OPERANDS.len()]
+                            }
+                        })
+                        .collect();
+
+                    let sources: Vec<&str> = operands.iter().map(|(source, _)| *source).collect();
+                    let source = format!("{} {}", descriptor.mnemonic, sources.join(", "));
+                    let program = assemble_single(&source);
+
+                    let mut reader = program.as_slice();
+                    let opcode = Opcode::decode(&mut reader)
+                        .unwrap_or_else(|e| panic!("Failed to decode {}: {}", source, e));
+                    let expected = Opcode {
+                        instruction,
+                        operands: operands.iter().map(|(_, operand)| *operand).collect(),
+                    };
+                    assert_eq!(opcode, expected, "{}", source);
+                    assert!(
+                        reader.is_empty(),
+                        "{} left {} bytes undecoded",
+                        source,
+                        reader.len()
+                    );
+
+                    let mut encoded = Vec::new();
+                    opcode.encode(&mut encoded).unwrap();
+                    assert_eq!(encoded, program, "{}", source);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn zero_immediate_is_encoded_in_a_single_byte() {
+        let operand = Operand::Immediate(0);
+        assert_eq!(operand.encoded_size(), 1);
+
+        let mut encoded = Vec::new();
+        operand.encode(&mut encoded).unwrap();
+        assert_eq!(encoded.len(), 1);
+        assert_eq!(Operand::decode(&mut encoded.as_slice()).unwrap(), operand);
+    }
+
+    #[test]
+    fn encoded_size_matches_the_bytes_written() {
+        let values: [IWord; 4] = [0, 0xFF, 0x100, IWord::MIN];
+        for &value in &values {
+            let operands = [
+                Operand::Immediate(value),
+                Operand::Register(3),
+                Operand::Reference {
+                    register: 2,
+                    offset: value,
+                },
+                Operand::Stack(value as UWord),
+                Operand::Indexed { base: 1, index: 3 },
+            ];
+
+            for operand in &operands {
+                let mut encoded = Vec::new();
+                operand.encode(&mut encoded).unwrap();
+                assert_eq!(operand.encoded_size(), encoded.len(), "{:?}", operand);
+            }
+        }
+    }
+
+    #[test]
+    fn decoding_a_register_that_does_not_exist_fails() {
+        // The register field only has room for existing registers, but the index of an indexed operand is a whole value byte
+        let first_byte = (0b11 << Operand::ADDRESSING_MODE_SHIFT)
+            | (1 << Operand::REGISTER_NUM_SHIFT)
+            | Operand::SIGN_MASK
+            | (1 << Operand::VALUE_SIZE_SHIFT);
+        let encoded = [first_byte, REGISTER_NUM as u8];
+
+        let error = Operand::decode(&mut encoded.as_ref()).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!("Register R{} doesn't exist", REGISTER_NUM)
+        );
+
+        let valid = [first_byte, REGISTER_NUM as u8 - 1];
+        assert_eq!(
+            Operand::decode(&mut valid.as_ref()).unwrap(),
+            Operand::Indexed {
+                base: 1,
+                index: REGISTER_NUM as RegisterIndex - 1
+            }
+        );
+    }
+}
@@ -0,0 +1,67 @@
+//! Runs the command line program itself, for behavior that only exists in its argument handling
+
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+const SOURCE: &str = "mov 1, r0\nloop:\nadd 1, r0\ncmp 5, r0\njne loop\nhalt\n";
+
+/// A directory only used by the test called `name`, so tests running in parallel don't overwrite each other's files
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("lakesis-cli-{}-{}", name, std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn lakesis() -> Command {
+    Command::new(env!("CARGO_BIN_EXE_lakesis"))
+}
+
+#[test]
+fn assembling_to_stdout_writes_the_same_bytes_as_assembling_to_a_file() {
+    let dir = scratch_dir("asm-stdout");
+    let source_path = dir.join("program.asm");
+    let binary_path = dir.join("program.bin");
+    fs::write(&source_path, SOURCE).unwrap();
+
+    let status = lakesis()
+        .arg("asm")
+        .arg(&source_path)
+        .arg(&binary_path)
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let output = lakesis()
+        .arg("asm")
+        .arg(&source_path)
+        .arg("-")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    let from_file = fs::read(&binary_path).unwrap();
+    assert!(!from_file.is_empty());
+    assert_eq!(output.stdout, from_file);
+
+    // Reading the source from stdin too
+    let mut child = lakesis()
+        .args(["asm", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(SOURCE.as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(output.stdout, from_file);
+
+    fs::remove_dir_all(&dir).unwrap();
+}